@@ -0,0 +1,79 @@
+//! Exercises `MountedFatfs::rename` and `MountedFatfs::rename_replace` on RAM-backed FAT filesystems:
+//! replacing an existing file, moving a file to another directory, and refusing to move a file
+//! to another volume.
+//!
+//! Runs on any chip, as it does not need an SD card or a flash partition.
+//! Build with `--features experimental` (for now).
+
+#[cfg(feature = "experimental")]
+fn main() -> anyhow::Result<()> {
+    use esp_idf_svc::fs::fatfs::Fatfs;
+    use esp_idf_svc::log::EspLogger;
+    use esp_idf_svc::sys::{ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE};
+
+    use log::info;
+
+    esp_idf_svc::sys::link_patches();
+
+    EspLogger::initialize_default();
+
+    let mut fatfs = Fatfs::new_ram(0, 128 * 1024)?;
+    let mut other_fatfs = Fatfs::new_ram(1, 128 * 1024)?;
+
+    let fs = fatfs.mount()?;
+    let _other_fs = other_fatfs.mount()?;
+
+    let read = |path: &str| -> anyhow::Result<String> {
+        let mut buf = [0; 32];
+        let len = fs.read_range(path, 0, &mut buf)?;
+
+        Ok(String::from_utf8(buf[..len].to_vec())?)
+    };
+
+    // Overwrite: `rename` refuses to replace an existing file, `rename_replace` replaces it
+    fs.write_atomic("/config.txt", b"old")?;
+    fs.write_atomic("/config.new", b"new")?;
+
+    let err = fs.rename("/config.new", "/config.txt").unwrap_err();
+    assert_eq!(err.code(), ESP_ERR_INVALID_STATE);
+
+    fs.rename_replace("/config.new", "/config.txt")?;
+
+    assert_eq!(read("/config.txt")?, "new");
+    assert!(fs.read_range("/config.new", 0, &mut [0; 1]).is_err());
+
+    info!("Overwriting rename passed");
+
+    // Cross-directory: the file moves to another directory of the same volume
+    fs.create_dir_all("/logs/archive")?;
+    fs.write_atomic("/logs/today.log", b"log")?;
+
+    fs.rename_replace("/logs/today.log", "/logs/archive/today.log")?;
+
+    assert_eq!(read("/logs/archive/today.log")?, "log");
+    assert!(fs.read_range("/logs/today.log", 0, &mut [0; 1]).is_err());
+
+    info!("Cross-directory rename passed");
+
+    // Cross-volume: FAT cannot move files across volumes, so drive prefixes are rejected
+    let err = fs.rename("/config.txt", "1:/config.txt").unwrap_err();
+    assert_eq!(err.code(), ESP_ERR_INVALID_ARG);
+
+    let err = fs
+        .rename_replace("/config.txt", "1:/config.txt")
+        .unwrap_err();
+    assert_eq!(err.code(), ESP_ERR_INVALID_ARG);
+
+    assert_eq!(read("/config.txt")?, "new");
+
+    info!("Cross-volume rename passed");
+
+    Ok(())
+}
+
+#[cfg(not(feature = "experimental"))]
+fn main() {
+    use esp_idf_svc::{self as _};
+
+    panic!("Use `--features experimental` when building this example");
+}
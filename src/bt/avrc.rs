@@ -3,7 +3,7 @@
 
 use core::fmt::Debug;
 
-use enumset::EnumSetType;
+use enumset::{EnumSet, EnumSetType};
 
 use crate::sys::*;
 
@@ -68,9 +68,59 @@ pub enum KeyCode {
     F3 = esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_F3 as _,
     F4 = esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_F4 as _,
     F5 = esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_F5 as _,
+    /// Vendor unique passthrough operation.
+    ///
+    /// Note that this is only the passthrough operation itself: the ESP IDF AVRC API
+    /// does not support sending vendor-dependent commands with a custom PDU payload.
     Vendor = esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_VENDOR as _,
 }
 
+/// The common actions of a media remote, see `KeyCode::from_media_action`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MediaAction {
+    /// Play if the playback is paused or stopped, pause otherwise.
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    FastForward,
+    Rewind,
+}
+
+impl KeyCode {
+    /// Returns the key code of a media remote action.
+    ///
+    /// `MediaAction::PlayPause` is mapped to `Pause` if the last known `playback` status is playing
+    /// (or seeking), and to `Play` otherwise, including when the playback status is unknown.
+    pub fn from_media_action(action: MediaAction, playback: Option<PlaybackStatus>) -> Self {
+        match action {
+            MediaAction::PlayPause => match playback {
+                Some(
+                    PlaybackStatus::Playing
+                    | PlaybackStatus::SeekForward
+                    | PlaybackStatus::SeekBackward,
+                ) => Self::Pause,
+                _ => Self::Play,
+            },
+            MediaAction::Play => Self::Play,
+            MediaAction::Pause => Self::Pause,
+            MediaAction::Stop => Self::Stop,
+            MediaAction::Next => Self::Forward,
+            MediaAction::Previous => Self::Backward,
+            MediaAction::VolumeUp => Self::VolumeUp,
+            MediaAction::VolumeDown => Self::VolumeDown,
+            MediaAction::Mute => Self::Mute,
+            MediaAction::FastForward => Self::FastForward,
+            MediaAction::Rewind => Self::Rewind,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u32)]
 pub enum ResponseCode {
@@ -125,24 +175,167 @@ pub enum NotificationType {
     Unknown2 = 15,
 }
 
+/// The playback position (and track length) reported by the target when no track is selected.
+const NO_TRACK_PLAYBACK_POSITION: u32 = u32::MAX;
+
+/// Maps the `NO_TRACK_PLAYBACK_POSITION` sentinel of a position or length to `None`.
+fn known_position(position: u32) -> Option<u32> {
+    (position != NO_TRACK_PLAYBACK_POSITION).then_some(position)
+}
+
+/// A playback position (or duration) in milliseconds, with helpers for displaying it
+/// as `mm:ss` in a now-playing UI.
+///
+/// The raw value reported by the target is kept, including the `0xFFFFFFFF` sentinel
+/// sent when no track is selected, which is displayed as `—`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PlaybackPosition(pub u32);
+
+impl PlaybackPosition {
+    /// Returns `true` if the position is the sentinel sent when no track is selected.
+    pub const fn is_unknown(&self) -> bool {
+        self.0 == NO_TRACK_PLAYBACK_POSITION
+    }
+
+    /// Returns the position in milliseconds, or `None` if no track is selected.
+    pub const fn as_millis(&self) -> Option<u32> {
+        if self.is_unknown() {
+            None
+        } else {
+            Some(self.0)
+        }
+    }
+
+    /// Returns the position in whole seconds, or `None` if no track is selected.
+    pub const fn as_secs(&self) -> Option<u32> {
+        if self.is_unknown() {
+            None
+        } else {
+            Some(self.0 / 1000)
+        }
+    }
+
+    /// Returns the position as whole minutes and the remaining seconds,
+    /// or `None` if no track is selected.
+    pub const fn as_mmss(&self) -> Option<(u32, u8)> {
+        if self.is_unknown() {
+            None
+        } else {
+            let secs = self.0 / 1000;
+
+            Some((secs / 60, (secs % 60) as u8))
+        }
+    }
+}
+
+impl From<Option<u32>> for PlaybackPosition {
+    fn from(millis: Option<u32>) -> Self {
+        Self(millis.unwrap_or(NO_TRACK_PLAYBACK_POSITION))
+    }
+}
+
+impl core::fmt::Display for PlaybackPosition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some((minutes, seconds)) = self.as_mmss() {
+            write!(f, "{minutes:02}:{seconds:02}")
+        } else {
+            write!(f, "\u{2014}")
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Notification {
     Volume(u8),
     Playback(PlaybackStatus),
-    TrackChanged,
+    /// The current track changed, with the UID of the new track, or `None` if no track is selected.
+    ///
+    /// Targets which do not support browsing report the UID 0 for any selected track.
+    /// A UID is only valid until the next `Notification::Uuids` notification.
+    TrackChanged(Option<u64>),
     TrackStarted,
     TrackEnded,
-    PlaybackPosition(u32),
+    /// The playback position in milliseconds, or `None` if no track is selected.
+    /// Convert it into a `PlaybackPosition` for displaying it.
+    PlaybackPosition(Option<u32>),
     Battery(BatteryStatus),
     SystemStatus,
     AppSettings,
     NowPlaying,
     AvailablePlayers,
     AddressedPlayer,
+    /// The UIDs of the media items of the target changed (e.g. its library was updated),
+    /// so any cached item UIDs are stale and should be refreshed.
+    ///
+    /// ESP IDF does not report the new UID counter with this notification.
     Uuids,
     Other(NotificationType),
 }
 
+impl Notification {
+    /// Returns the type of this notification.
+    pub fn notification_type(&self) -> NotificationType {
+        match self {
+            Self::Volume(_) => NotificationType::Volume,
+            Self::Playback(_) => NotificationType::Playback,
+            Self::TrackChanged(_) => NotificationType::TrackChanged,
+            Self::TrackStarted => NotificationType::TrackStart,
+            Self::TrackEnded => NotificationType::TrackEnd,
+            Self::PlaybackPosition(_) => NotificationType::PlaybackPosition,
+            Self::Battery(_) => NotificationType::BatteryStatus,
+            Self::SystemStatus => NotificationType::SystemStatus,
+            Self::AppSettings => NotificationType::AppSettings,
+            Self::NowPlaying => NotificationType::NowPlaying,
+            Self::AvailablePlayers => NotificationType::AvailablePlayers,
+            Self::AddressedPlayer => NotificationType::AddressedPlayer,
+            Self::Uuids => NotificationType::Uuids,
+            Self::Other(notification_type) => *notification_type,
+        }
+    }
+
+    /// Decodes a change notification from its raw event ID and event parameter.
+    ///
+    /// This is the only place where the member of the `esp_avrc_rn_param_t` union is selected
+    /// based on the notification type, so new notification types should be added here.
+    ///
+    /// Returns `None` if the event ID or the event parameter cannot be decoded.
+    pub fn from_raw(event_id: u8, parameter: &esp_avrc_rn_param_t) -> Option<Self> {
+        let notification_type = NotificationType::try_from(event_id).ok()?;
+
+        // SAFETY: The union member being read is the one matching the notification type
+        let notification = unsafe {
+            match notification_type {
+                NotificationType::Playback => {
+                    Self::Playback(PlaybackStatus::try_from(parameter.playback).ok()?)
+                }
+                NotificationType::TrackChanged => Self::TrackChanged(
+                    Some(u64::from_be_bytes(parameter.elm_id)).filter(|uid| *uid != u64::MAX),
+                ),
+                NotificationType::TrackEnd => Self::TrackEnded,
+                NotificationType::TrackStart => Self::TrackStarted,
+                NotificationType::PlaybackPosition => {
+                    Self::PlaybackPosition(known_position(parameter.play_pos))
+                }
+                NotificationType::BatteryStatus => {
+                    Self::Battery(BatteryStatus::try_from(parameter.batt).ok()?)
+                }
+                NotificationType::SystemStatus => Self::SystemStatus,
+                NotificationType::AppSettings => Self::AppSettings,
+                NotificationType::NowPlaying => Self::NowPlaying,
+                NotificationType::AvailablePlayers => Self::AvailablePlayers,
+                NotificationType::AddressedPlayer => Self::AddressedPlayer,
+                NotificationType::Uuids => Self::Uuids,
+                NotificationType::Volume => Self::Volume(parameter.volume),
+                NotificationType::Unknown1 | NotificationType::Unknown2 => {
+                    Self::Other(notification_type)
+                }
+            }
+        };
+
+        Some(notification)
+    }
+}
+
 #[derive(Debug, EnumSetType)]
 #[enumset(repr = "u16")]
 pub enum Feature {
@@ -185,6 +378,13 @@ pub enum TargetFeature {
     Unknown8 = 15,
 }
 
+impl TargetFeature {
+    /// Returns all the cover art features.
+    pub fn cover_art() -> EnumSet<Self> {
+        Self::GetImageProperties | Self::CoverArtGetImage | Self::CoverArtGetLinkedThumbnail
+    }
+}
+
 #[derive(Debug, EnumSetType, TryFromPrimitive)]
 #[enumset(repr = "u8")]
 #[repr(u8)]
@@ -196,6 +396,25 @@ pub enum MetadataId {
     TracksCount = 4, //esp_avrc_md_attr_mask_t_ESP_AVRC_MD_ATTR_NUM_TRACKS.ilog2() as _,
     Genre = 5,       //esp_avrc_md_attr_mask_t_ESP_AVRC_MD_ATTR_GENRE.ilog2() as _,
     PlayingTime = 6, //esp_avrc_md_attr_mask_t_ESP_AVRC_MD_ATTR_PLAYING_TIME.ilog2() as _,
+    /// The cover art image handle, see `controller::cover_art::ImageHandle`
+    CoverArt = 7, //esp_avrc_md_attr_mask_t_ESP_AVRC_MD_ATTR_COVER_ART.ilog2() as _,
+}
+
+/// Parses the text of a `MetadataId::PlayingTime` metadata response, i.e. the duration
+/// of the track in milliseconds, sent as a decimal string.
+///
+/// Surrounding whitespace and NUL terminators, as well as a fractional part (e.g. `215000.0`)
+/// sent by some targets are tolerated. Returns `None` if the text is not a number,
+/// e.g. if it is empty because the duration is unknown.
+pub fn parse_playing_time(text: &str) -> Option<u32> {
+    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    let millis = text.split('.').next()?;
+
+    if millis.is_empty() || !millis.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    millis.parse().ok()
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
@@ -257,17 +476,62 @@ impl From<PlayerAttributeId> for (esp_avrc_ps_attr_ids_t, u8) {
     }
 }
 
+impl PlayerAttributeId {
+    /// Returns the English name of the setting, as used by the AVRCP specification.
+    ///
+    /// ESP IDF cannot request the target's own wording of its settings
+    /// (the GetPlayerApplicationSettingAttributeText PDU), so a settings UI has to use these names.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::EqualizerMode(_) => "Equalizer",
+            Self::RepeatMode(_) => "Repeat",
+            Self::ShuffleMode(_) => "Shuffle",
+            Self::ScanMode(_) => "Scan",
+        }
+    }
+
+    /// Returns the English name of the value of the setting, as used by the AVRCP specification.
+    pub const fn value_name(&self) -> &'static str {
+        match self {
+            Self::EqualizerMode(EqualizerMode::Off)
+            | Self::RepeatMode(RepeatMode::Off)
+            | Self::ShuffleMode(ShuffleMode::Off)
+            | Self::ScanMode(ScanMode::Off) => "Off",
+            Self::EqualizerMode(EqualizerMode::On) => "On",
+            Self::RepeatMode(RepeatMode::Single) => "Single track",
+            Self::ShuffleMode(ShuffleMode::All) | Self::ScanMode(ScanMode::All) => "All tracks",
+            Self::RepeatMode(RepeatMode::Group)
+            | Self::ShuffleMode(ShuffleMode::Group)
+            | Self::ScanMode(ScanMode::Group) => "Group",
+        }
+    }
+}
+
+impl core::fmt::Display for PlayerAttributeId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {}", self.name(), self.value_name())
+    }
+}
+
 pub mod controller {
     use core::borrow::Borrow;
     use core::convert::{TryFrom, TryInto};
     use core::fmt::{self, Debug};
     use core::marker::PhantomData;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use core::time::Duration;
+
+    use alloc::sync::Arc;
 
     use enumset::EnumSet;
 
-    use ::log::info;
+    use ::log::{info, warn};
 
     use crate::bt::{BdAddr, BtClassicEnabled, BtDriver, BtSingleton};
+    use crate::private::mutex::Mutex;
+    use crate::private::waitable::Waitable;
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    use crate::timer::{EspTaskTimerService, EspTimer};
 
     use super::*;
 
@@ -289,28 +553,174 @@ pub mod controller {
             key_pressed: bool,
             response_code: ResponseCode,
         },
+        /// A passthrough command sent with `EspAvrcc::send_passthrough` got no response within
+        /// the timeout configured with `EspAvrcc::set_passthrough_timeout`, even after the retries.
+        PassthroughTimeout {
+            transaction_label: u8,
+            key_code: KeyCode,
+            key_pressed: bool,
+        },
         Metadata {
             id: MetadataId,
             text: &'a str,
         },
-        PlayStatus,
+        /// The response to `EspAvrcc::request_play_status`.
+        ///
+        /// The length and the position of the track are in milliseconds, and - as with
+        /// `Notification::PlaybackPosition` - are `None` when the target reports them as unknown
+        /// with the `0xFFFFFFFF` sentinel, e.g. when no track is selected. A zero-length track
+        /// is reported as `Some(0)`.
+        ///
+        /// ESP IDF v4 does not report the content of the response, so all the fields are `None` there.
+        PlayStatus {
+            length: Option<u32>,
+            position: Option<u32>,
+            status: Option<PlaybackStatus>,
+        },
+        /// A change notification, i.e. a CHANGED response to a registered notification.
+        ///
+        /// ESP IDF does not report the INTERIM response sent by the target upon registration,
+        /// so the current value (e.g. the current volume) is not delivered upon registration,
+        /// but only after it changes. Note also that the registration is consumed by the change,
+        /// so the notification has to be registered again to receive the next change.
         Notification(Notification),
         RemoteFeatures {
             bd_addr: BdAddr,
+            /// The AVRC features of the peer (`feat_mask`).
             features: EnumSet<Feature>,
+            /// The SDP feature flags of the peer's target role (`tg_feat_flag`), e.g. whether it supports
+            /// cover art (see `TargetFeature::cover_art`). The raw flags are available with `as_repr()`.
             target_features: EnumSet<TargetFeature>,
         },
         NotificationCapabilities {
             allowed: bool,
             capabilities: EnumSet<NotificationType>,
         },
+        /// The response to `EspAvrcc::set_volume`, with the volume actually set by the target.
+        ///
+        /// ESP IDF only delivers accepted responses: if the target rejects the command
+        /// (e.g. because it does not support absolute volume), no event is received at all.
+        /// Applications can detect this with a timeout, and fall back to the `KeyCode::VolumeUp`
+        /// and `KeyCode::VolumeDown` passthrough commands.
         Volume(u8),
+        /// The state of the cover art (OBEX) connection changed, see `EspAvrcc::connect_cover_art`.
+        #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+        CoverArtState {
+            connected: bool,
+        },
+        /// A chunk of a cover art response, see `EspAvrcc::get_image`.
+        ///
+        /// Large images are delivered in several chunks; `final_data` is set on the last one.
+        /// A failed request is reported with a single final, empty chunk and a non-`ESP_BT_STATUS_SUCCESS` status.
+        #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+        CoverArtData {
+            status: esp_bt_status_t,
+            final_data: bool,
+            data: &'a [u8],
+        },
         Other {
             raw_event: esp_avrc_ct_cb_event_t,
             raw_data: EventRawData<'a>,
         },
     }
 
+    impl AvrccEvent<'_> {
+        /// Returns the duration of the track in milliseconds, if this event is a
+        /// `MetadataId::PlayingTime` metadata response with a valid duration (see `parse_playing_time`).
+        ///
+        /// This is useful for a seek bar when the target does not support play status requests.
+        pub fn playing_time(&self) -> Option<u32> {
+            match self {
+                Self::Metadata {
+                    id: MetadataId::PlayingTime,
+                    text,
+                } => parse_playing_time(text),
+                _ => None,
+            }
+        }
+    }
+
+    /// The maximum number of characters of metadata text rendered by the `Display` implementation.
+    const SUMMARY_TEXT_LEN: usize = 16;
+
+    /// A compact, one-line summary of the event, where metadata text is truncated.
+    /// Use `Debug` for the full event.
+    impl fmt::Display for AvrccEvent<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Connected(addr) => write!(f, "Connected({addr})"),
+                Self::Disconnected(addr) => write!(f, "Disconnected({addr})"),
+                Self::Passthrough {
+                    key_code,
+                    key_pressed,
+                    response_code,
+                    ..
+                } => write!(
+                    f,
+                    "Passthrough({key_code:?}, {}, {response_code:?})",
+                    if *key_pressed { "pressed" } else { "released" }
+                ),
+                Self::PassthroughTimeout {
+                    key_code,
+                    key_pressed,
+                    ..
+                } => write!(
+                    f,
+                    "PassthroughTimeout({key_code:?}, {})",
+                    if *key_pressed { "pressed" } else { "released" }
+                ),
+                Self::Metadata { id, text } => {
+                    let truncated = text
+                        .char_indices()
+                        .nth(SUMMARY_TEXT_LEN)
+                        .map(|(index, _)| &text[..index]);
+
+                    match truncated {
+                        Some(truncated) => write!(f, "Metadata({id:?}, \"{truncated}...\")"),
+                        None => write!(f, "Metadata({id:?}, \"{text}\")"),
+                    }
+                }
+                Self::PlayStatus {
+                    length,
+                    position,
+                    status: Some(status),
+                } => write!(
+                    f,
+                    "PlayStatus({status:?}, {}/{})",
+                    PlaybackPosition::from(*position),
+                    PlaybackPosition::from(*length)
+                ),
+                Self::PlayStatus { status: None, .. } => write!(f, "PlayStatus"),
+                Self::Notification(notification) => write!(f, "Notification({notification:?})"),
+                Self::RemoteFeatures { bd_addr, .. } => write!(f, "RemoteFeatures({bd_addr})"),
+                Self::NotificationCapabilities {
+                    allowed,
+                    capabilities,
+                } => write!(
+                    f,
+                    "NotificationCapabilities({} capabilities{})",
+                    capabilities.len(),
+                    if *allowed { "" } else { ", not allowed" }
+                ),
+                Self::Volume(volume) => write!(f, "Volume({volume})"),
+                #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+                Self::CoverArtState { connected } => write!(f, "CoverArtState({connected})"),
+                #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+                Self::CoverArtData {
+                    status,
+                    final_data,
+                    data,
+                } => write!(
+                    f,
+                    "CoverArtData({status}, {} bytes{})",
+                    data.len(),
+                    if *final_data { ", final" } else { "" }
+                ),
+                Self::Other { raw_event, .. } => write!(f, "Other({raw_event})"),
+            }
+        }
+    }
+
     #[allow(non_upper_case_globals)]
     impl<'a> From<(esp_avrc_ct_cb_event_t, &'a esp_avrc_ct_cb_param_t)> for AvrccEvent<'a> {
         fn from(value: (esp_avrc_ct_cb_event_t, &'a esp_avrc_ct_cb_param_t)) -> Self {
@@ -338,38 +748,42 @@ pub mod controller {
                             param.meta_rsp.attr_length as _,
                         )),
                     },
-                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_PLAY_STATUS_RSP_EVT => Self::PlayStatus,
-                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_CHANGE_NOTIFY_EVT => Self::Notification(
-                        match NotificationType::try_from(param.change_ntf.event_id).unwrap() {
-                            NotificationType::Playback => Notification::Playback(
-                                param
-                                    .change_ntf
-                                    .event_parameter
-                                    .playback
-                                    .try_into()
-                                    .unwrap(),
-                            ),
-                            NotificationType::TrackChanged => Notification::TrackChanged,
-                            NotificationType::TrackEnd => Notification::TrackEnded,
-                            NotificationType::TrackStart => Notification::TrackStarted,
-                            NotificationType::PlaybackPosition => Notification::PlaybackPosition(
-                                param.change_ntf.event_parameter.playback,
-                            ),
-                            NotificationType::BatteryStatus => Notification::Battery(
-                                param.change_ntf.event_parameter.batt.try_into().unwrap(),
-                            ),
-                            NotificationType::SystemStatus => Notification::SystemStatus,
-                            NotificationType::AppSettings => Notification::AppSettings,
-                            NotificationType::NowPlaying => Notification::NowPlaying,
-                            NotificationType::AvailablePlayers => Notification::AvailablePlayers,
-                            NotificationType::AddressedPlayer => Notification::AddressedPlayer,
-                            NotificationType::Uuids => Notification::Uuids,
-                            NotificationType::Volume => {
-                                Notification::Volume(param.change_ntf.event_parameter.volume)
+                    #[cfg(not(esp_idf_version_major = "4"))]
+                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_PLAY_STATUS_RSP_EVT => {
+                        if let Ok(status) =
+                            PlaybackStatus::try_from(param.play_status_rsp.play_status)
+                        {
+                            Self::PlayStatus {
+                                length: known_position(param.play_status_rsp.song_length),
+                                position: known_position(param.play_status_rsp.song_position),
+                                status: Some(status),
                             }
-                            other => Notification::Other(other),
-                        },
-                    ),
+                        } else {
+                            Self::Other {
+                                raw_event: event,
+                                raw_data: EventRawData(param),
+                            }
+                        }
+                    }
+                    #[cfg(esp_idf_version_major = "4")]
+                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_PLAY_STATUS_RSP_EVT => Self::PlayStatus {
+                        length: None,
+                        position: None,
+                        status: None,
+                    },
+                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_CHANGE_NOTIFY_EVT => {
+                        if let Some(notification) = Notification::from_raw(
+                            param.change_ntf.event_id,
+                            &param.change_ntf.event_parameter,
+                        ) {
+                            Self::Notification(notification)
+                        } else {
+                            Self::Other {
+                                raw_event: event,
+                                raw_data: EventRawData(param),
+                            }
+                        }
+                    }
                     esp_avrc_ct_cb_event_t_ESP_AVRC_CT_REMOTE_FEATURES_EVT => {
                         Self::RemoteFeatures {
                             bd_addr: param.rmt_feats.remote_bda.into(),
@@ -387,6 +801,24 @@ pub mod controller {
                     esp_avrc_ct_cb_event_t_ESP_AVRC_CT_SET_ABSOLUTE_VOLUME_RSP_EVT => {
                         Self::Volume(param.set_volume_rsp.volume)
                     }
+                    #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_COVER_ART_STATE_EVT => Self::CoverArtState {
+                        connected: param.cover_art_state.state
+                            == esp_avrc_cover_art_conn_state_t_ESP_AVRC_COVER_ART_CONNECTED,
+                    },
+                    #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+                    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_COVER_ART_DATA_EVT => Self::CoverArtData {
+                        status: param.cover_art_data.status,
+                        final_data: param.cover_art_data.final_,
+                        data: if param.cover_art_data.p_data.is_null() {
+                            &[]
+                        } else {
+                            core::slice::from_raw_parts(
+                                param.cover_art_data.p_data,
+                                param.cover_art_data.data_len as _,
+                            )
+                        },
+                    },
                     _ => Self::Other {
                         raw_event: event,
                         raw_data: EventRawData(param),
@@ -412,10 +844,11 @@ pub mod controller {
         T: Borrow<BtDriver<'d, M>>,
     {
         pub fn new(driver: T) -> Result<Self, EspError> {
-            SINGLETON.take()?;
-
-            esp!(unsafe { esp_avrc_ct_register_callback(Some(Self::event_handler)) })?;
-            esp!(unsafe { esp_avrc_ct_init() })?;
+            initialize(
+                &SINGLETON,
+                || esp!(unsafe { esp_avrc_ct_register_callback(Some(Self::event_handler)) }),
+                || esp!(unsafe { esp_avrc_ct_init() }),
+            )?;
 
             Ok(Self {
                 _driver: driver,
@@ -424,6 +857,20 @@ pub mod controller {
             })
         }
 
+        /// Subscribe to the events of the controller.
+        ///
+        /// Events are delivered synchronously - without any queue in between - from the Bluedroid
+        /// task, in the order in which ESP IDF reports them, so no event is ever dropped and the
+        /// ordering within a connection is preserved. The callback should return quickly,
+        /// as it blocks the Bluedroid task.
+        ///
+        /// There are two exceptions, which are delivered later, from the timer task, and might
+        /// therefore be delivered after events reported by ESP IDF in the meantime:
+        /// - the settled volume with `set_volume_debounce`, which might follow events reported
+        ///   after the volume change notification;
+        /// - the `AvrccEvent::PassthroughTimeout` events with `set_passthrough_timeout`.
+        ///
+        /// The calls are serialized, so the callback is never called from both tasks at once.
         pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
         where
             F: FnMut(AvrccEvent) + Send + 'static,
@@ -477,13 +924,13 @@ pub mod controller {
             attribute: PlayerAttributeId,
         ) -> Result<(), EspError> {
             let (attribute_id, attribute_value) = attribute.into();
-            esp!(unsafe {
+            command(esp!(unsafe {
                 esp_avrc_ct_send_set_player_value_cmd(
                     transaction_label,
                     attribute_id as _,
                     attribute_value,
                 )
-            })
+            }))
         }
 
         pub fn register_notification(
@@ -492,21 +939,137 @@ pub mod controller {
             notification: NotificationType,
             playback_pos_reporting_interval_ms: u32,
         ) -> Result<(), EspError> {
-            esp!(unsafe {
+            command(esp!(unsafe {
                 esp_avrc_ct_send_register_notification_cmd(
                     transaction_label,
                     notification as _,
                     playback_pos_reporting_interval_ms,
                 )
-            })
+            }))?;
+
+            REGISTERED.lock().insert(notification);
+
+            Ok(())
         }
 
+        /// Returns the link statistics since boot, or since the last `reset_stats` call.
+        pub fn stats(&self) -> AvrcStats {
+            AvrcStats {
+                connects: STATS.connects.load(Ordering::Relaxed),
+                disconnects: STATS.disconnects.load(Ordering::Relaxed),
+                command_failures: STATS.command_failures.load(Ordering::Relaxed),
+            }
+        }
+
+        /// Resets the link statistics returned by `stats`.
+        pub fn reset_stats(&self) {
+            STATS.connects.store(0, Ordering::Relaxed);
+            STATS.disconnects.store(0, Ordering::Relaxed);
+            STATS.command_failures.store(0, Ordering::Relaxed);
+        }
+
+        /// Returns the notifications which are currently registered, i.e. for which
+        /// `register_notification` was called, and no change was notified yet.
+        ///
+        /// As a registration is consumed by the first change notification, the notification is removed
+        /// from the set when the change is notified. The set is cleared upon disconnection.
+        /// Note that registrations rejected by the target cannot be detected, as ESP IDF
+        /// does not report the rejections.
+        pub fn registered_notifications(&self) -> EnumSet<NotificationType> {
+            *REGISTERED.lock()
+        }
+
+        /// Forget all the registered notifications, e.g. when the user leaves the now-playing screen.
+        ///
+        /// AVRC has no way to deregister a notification, but as a registration is consumed by its
+        /// first change notification, the registrations lapse after their next change as long as
+        /// they are not registered again. This method therefore disables the re-registrations done
+        /// by the controller itself (see `enable_auto_refresh` and `enable_now_playing_refresh`),
+        /// and clears `registered_notifications`.
+        /// Note that the change notification of each in-flight registration might still arrive once.
+        pub fn clear_notifications(&self) -> Result<(), EspError> {
+            self.disable_auto_refresh()?;
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            self.disable_now_playing_refresh()?;
+
+            REGISTERED.lock().clear();
+
+            Ok(())
+        }
+
+        /// Set the absolute volume of the target.
+        ///
+        /// The volume is a 7-bit value in the range `0..=MAX_VOLUME`;
+        /// larger values are rejected with `ESP_ERR_INVALID_ARG`.
         pub fn set_volume(&self, transaction_label: u8, volume: u8) -> Result<(), EspError> {
-            esp!(unsafe { esp_avrc_ct_send_set_absolute_volume_cmd(transaction_label, volume) })
+            if volume > MAX_VOLUME {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
+            command(esp!(unsafe {
+                esp_avrc_ct_send_set_absolute_volume_cmd(transaction_label, volume)
+            }))
+        }
+
+        /// Set the absolute volume of the target as a percentage in the range `0..=100`.
+        ///
+        /// Larger values are rejected with `ESP_ERR_INVALID_ARG`.
+        pub fn set_volume_percent(
+            &self,
+            transaction_label: u8,
+            percent: u8,
+        ) -> Result<(), EspError> {
+            if percent > 100 {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
+            self.set_volume(transaction_label, percent_to_volume(percent))
+        }
+
+        /// Raise the volume of the target by `step`, starting from the `current` volume
+        /// (as last reported by the target with a `Notification::Volume` or a `AvrccEvent::Volume` event).
+        ///
+        /// The new volume saturates at `MAX_VOLUME` and is returned.
+        pub fn volume_up(
+            &self,
+            transaction_label: u8,
+            current: u8,
+            step: u8,
+        ) -> Result<u8, EspError> {
+            let volume = current.saturating_add(step).min(MAX_VOLUME);
+
+            self.set_volume(transaction_label, volume)?;
+
+            Ok(volume)
+        }
+
+        /// Lower the volume of the target by `step`, starting from the `current` volume
+        /// (as last reported by the target with a `Notification::Volume` or a `AvrccEvent::Volume` event).
+        ///
+        /// The new volume saturates at 0 and is returned.
+        pub fn volume_down(
+            &self,
+            transaction_label: u8,
+            current: u8,
+            step: u8,
+        ) -> Result<u8, EspError> {
+            let volume = current.min(MAX_VOLUME).saturating_sub(step);
+
+            self.set_volume(transaction_label, volume)?;
+
+            Ok(volume)
         }
 
+        /// Request the notifications supported by the target (the `EventsSupported` capability),
+        /// delivered with the `AvrccEvent::NotificationCapabilities` event.
+        ///
+        /// ESP IDF only implements this capability: the `CompanyID` capability (the list of
+        /// the vendor company ids of the target) can neither be requested nor received.
         pub fn request_capabilities(&self, transaction_label: u8) -> Result<(), EspError> {
-            esp!(unsafe { esp_avrc_ct_send_get_rn_capabilities_cmd(transaction_label) })
+            command(esp!(unsafe {
+                esp_avrc_ct_send_get_rn_capabilities_cmd(transaction_label)
+            }))
         }
 
         pub fn request_metadata(
@@ -514,7 +1077,162 @@ pub mod controller {
             transaction_label: u8,
             metadata: EnumSet<MetadataId>,
         ) -> Result<(), EspError> {
-            esp!(unsafe { esp_avrc_ct_send_metadata_cmd(transaction_label, metadata.as_repr()) })
+            command(esp!(unsafe {
+                esp_avrc_ct_send_metadata_cmd(transaction_label, metadata.as_repr())
+            }))
+        }
+
+        /// Request the play status of the target, delivered with the `AvrccEvent::PlayStatus` event.
+        pub fn request_play_status(&self, transaction_label: u8) -> Result<(), EspError> {
+            command(esp!(unsafe {
+                esp_avrc_ct_send_get_play_status_cmd(transaction_label)
+            }))
+        }
+
+        /// Automatically refresh the `metadata` and the play status whenever the target reports
+        /// that its addressed player changed, i.e. when the user switched to another media app,
+        /// so that the application does not keep showing the track of the previous app.
+        ///
+        /// This registers the `NotificationType::AddressedPlayer` notification, and registers it
+        /// again after each change. All the commands are sent with `transaction_label`.
+        /// The refreshed metadata and play status are delivered as regular events, right after
+        /// the `Notification::AddressedPlayer` event. Note that ESP IDF does not report the ID
+        /// of the new player.
+        ///
+        /// Auto refresh is off by default. It survives reconnections: nothing is sent while
+        /// disconnected, and the notification is registered again upon reconnection.
+        pub fn enable_auto_refresh(
+            &self,
+            transaction_label: u8,
+            metadata: EnumSet<MetadataId>,
+        ) -> Result<(), EspError> {
+            self.register_notification(transaction_label, NotificationType::AddressedPlayer, 0)?;
+
+            *AUTO_REFRESH.lock() = Some((transaction_label, metadata));
+
+            Ok(())
+        }
+
+        /// Stop the auto refresh enabled with `enable_auto_refresh`.
+        pub fn disable_auto_refresh(&self) -> Result<(), EspError> {
+            *AUTO_REFRESH.lock() = None;
+
+            Ok(())
+        }
+
+        fn update_auto_refresh(event: &AvrccEvent) {
+            let Some((transaction_label, metadata)) = *AUTO_REFRESH.lock() else {
+                return;
+            };
+
+            match event {
+                AvrccEvent::Connected(_) => {
+                    if let Err(err) =
+                        renew_notification(transaction_label, NotificationType::AddressedPlayer)
+                    {
+                        warn!("Registering the addressed player notification failed: {err}");
+                    }
+
+                    return;
+                }
+                AvrccEvent::Notification(Notification::AddressedPlayer) => (),
+                _ => return,
+            }
+
+            let result = renew_notification(transaction_label, NotificationType::AddressedPlayer)
+                .and_then(|_| {
+                    command(esp!(unsafe {
+                        esp_avrc_ct_send_metadata_cmd(transaction_label, metadata.as_repr())
+                    }))
+                })
+                .and_then(|_| {
+                    command(esp!(unsafe {
+                        esp_avrc_ct_send_get_play_status_cmd(transaction_label)
+                    }))
+                });
+
+            if let Err(err) = result {
+                warn!("Refreshing after an addressed player change failed: {err}");
+            }
+        }
+
+        /// Automatically refresh the `metadata` and the play status whenever the target reports
+        /// that its now playing content changed, so that the track information and the progress
+        /// (see `on_progress`) stay fresh without the application re-requesting them.
+        ///
+        /// This registers the `NotificationType::NowPlaying` notification, and registers it again
+        /// after each change. The refresh is debounced: it is only sent once no other change was
+        /// reported for `quiet`, so that a burst of changes - e.g. when a playlist is loaded -
+        /// triggers a single refresh. All the commands are sent with `transaction_label`, and the
+        /// refreshed metadata and play status are delivered as regular events. Only the commands
+        /// are sent from the timer task: their responses arrive from the Bluedroid task, as usual.
+        ///
+        /// The refresh is off by default. It survives reconnections: a pending refresh is cancelled
+        /// upon disconnection, and the notification is registered again upon reconnection.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn enable_now_playing_refresh(
+            &self,
+            transaction_label: u8,
+            metadata: EnumSet<MetadataId>,
+            quiet: Duration,
+        ) -> Result<(), EspError> {
+            let timer = EspTaskTimerService::new()?.timer(move || {
+                let result = command(esp!(unsafe {
+                    esp_avrc_ct_send_metadata_cmd(transaction_label, metadata.as_repr())
+                }))
+                .and_then(|_| {
+                    command(esp!(unsafe {
+                        esp_avrc_ct_send_get_play_status_cmd(transaction_label)
+                    }))
+                });
+
+                if let Err(err) = result {
+                    warn!("Refreshing after a now playing change failed: {err}");
+                }
+            })?;
+
+            self.register_notification(transaction_label, NotificationType::NowPlaying, 0)?;
+
+            let _old = NOW_PLAYING_REFRESH.lock().replace(NowPlayingRefresh {
+                transaction_label,
+                quiet,
+                timer,
+            });
+
+            Ok(())
+        }
+
+        /// Stop the refresh enabled with `enable_now_playing_refresh`, cancelling a pending refresh.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn disable_now_playing_refresh(&self) -> Result<(), EspError> {
+            let _old = NOW_PLAYING_REFRESH.lock().take();
+
+            Ok(())
+        }
+
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        fn update_now_playing_refresh(event: &AvrccEvent) {
+            let refresh = NOW_PLAYING_REFRESH.lock();
+
+            let Some(refresh) = refresh.as_ref() else {
+                return;
+            };
+
+            let result = match event {
+                AvrccEvent::Connected(_) => {
+                    renew_notification(refresh.transaction_label, NotificationType::NowPlaying)
+                }
+                AvrccEvent::Disconnected(_) => refresh.timer.cancel().map(|_| ()),
+                AvrccEvent::Notification(Notification::NowPlaying) => {
+                    renew_notification(refresh.transaction_label, NotificationType::NowPlaying)
+                        .and_then(|_| refresh.timer.after(refresh.quiet))
+                }
+                _ => return,
+            };
+
+            if let Err(err) = result {
+                warn!("Renewing the now playing refresh failed: {err}");
+            }
         }
 
         pub fn send_passthrough(
@@ -523,61 +1241,1830 @@ pub mod controller {
             key_code: KeyCode,
             pressed: bool,
         ) -> Result<(), EspError> {
-            esp!(unsafe {
-                esp_avrc_ct_send_passthrough_cmd(
-                    transaction_label,
-                    key_code as _,
-                    if pressed { 0 } else { 1 },
-                )
-            })
-        }
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            if !pressed {
+                // A release of the repeated key ends the repeat. The repeat is dropped once
+                // the lock is released, as dropping its timer waits for the timer callback,
+                // which locks `KEY_REPEAT` too
+                let _old = {
+                    let mut repeat = KEY_REPEAT.lock();
 
-        unsafe extern "C" fn event_handler(
-            event: esp_avrc_ct_cb_event_t,
-            param: *mut esp_avrc_ct_cb_param_t,
-        ) {
-            if let Some(param) = unsafe { param.as_ref() } {
-                let event = AvrccEvent::from((event, param));
+                    if repeat
+                        .as_ref()
+                        .map(|repeat| repeat.key_code == key_code)
+                        .unwrap_or(false)
+                    {
+                        repeat.take()
+                    } else {
+                        None
+                    }
+                };
+            }
+
+            // Tracked before sending, as the response might arrive before `passthrough` returns
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            if let Some(timeout) = PASSTHROUGH_TIMEOUT.lock().as_mut() {
+                timeout.track(transaction_label, key_code, pressed);
+            }
 
-                info!("Got event {{ {:#?} }}", event);
+            let result = passthrough(transaction_label, key_code, pressed);
 
-                SINGLETON.call(event);
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            if result.is_err() {
+                if let Some(timeout) = PASSTHROUGH_TIMEOUT.lock().as_mut() {
+                    timeout
+                        .pending
+                        .retain(|pending| pending.transaction_label != transaction_label);
+                }
             }
+
+            result
         }
-    }
 
-    impl<'d, M, T> Drop for EspAvrcc<'d, M, T>
-    where
-        M: BtClassicEnabled,
-        T: Borrow<BtDriver<'d, M>>,
-    {
-        fn drop(&mut self) {
-            self.unsubscribe().unwrap();
+        /// Detect the passthrough commands sent with `send_passthrough` which get no response,
+        /// e.g. on a marginal link: a command whose response - correlated by transaction label,
+        /// key and key state - does not arrive within `timeout` is sent again, up to `retries` times,
+        /// and is then reported with an `AvrccEvent::PassthroughTimeout` event.
+        ///
+        /// With `None` - the default - passthrough commands are not tracked. The timeouts are checked
+        /// every quarter of `timeout`, so a timeout is detected up to a quarter of `timeout` late,
+        /// and the events are delivered from the timer task. Pending commands are forgotten when the
+        /// target disconnects. Note that the commands sent by `start_key_repeat` and `enable_keepalive`
+        /// are not tracked.
+        ///
+        /// # Errors
+        ///
+        /// Returns `ESP_ERR_INVALID_ARG` if `timeout` is zero.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn set_passthrough_timeout(
+            &self,
+            timeout: Option<Duration>,
+            retries: u32,
+        ) -> Result<(), EspError> {
+            let _old = PASSTHROUGH_TIMEOUT.lock().take();
 
-            esp!(unsafe { esp_avrc_ct_deinit() }).unwrap();
+            let Some(timeout) = timeout else {
+                return Ok(());
+            };
 
-            // Not possible because this function rejects NULL arguments
-            // esp!(unsafe { esp_avrc_ct_register_callback(Some(None)) })?;
+            if timeout.is_zero() {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
 
-            SINGLETON.release().unwrap();
-        }
-    }
+            let timer = EspTaskTimerService::new()?.timer(check_passthrough_timeouts)?;
 
-    unsafe impl<'d, M, T> Send for EspAvrcc<'d, M, T>
-    where
-        M: BtClassicEnabled,
-        T: Borrow<BtDriver<'d, M>> + Send,
-    {
-    }
+            timer.every((timeout / 4).max(Duration::from_millis(10)))?;
 
-    // Safe because the ESP IDF Bluedroid APIs all do message passing
-    // to a dedicated Bluedroid task
-    unsafe impl<'d, M, T> Sync for EspAvrcc<'d, M, T>
-    where
-        M: BtClassicEnabled,
-        T: Borrow<BtDriver<'d, M>> + Send,
-    {
-    }
+            *PASSTHROUGH_TIMEOUT.lock() = Some(PassthroughTimeout {
+                timeout,
+                retries,
+                pending: heapless::Vec::new(),
+                timer,
+            });
+
+            Ok(())
+        }
+
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        fn update_passthrough_timeout(event: &AvrccEvent) {
+            let mut timeout = PASSTHROUGH_TIMEOUT.lock();
+
+            let Some(timeout) = timeout.as_mut() else {
+                return;
+            };
+
+            match event {
+                AvrccEvent::Passthrough {
+                    transaction_level,
+                    key_code,
+                    key_pressed,
+                    ..
+                } => timeout.pending.retain(|pending| {
+                    pending.transaction_label != *transaction_level
+                        || pending.key_code != *key_code
+                        || pending.pressed != *key_pressed
+                }),
+                AvrccEvent::Disconnected(_) => timeout.pending.clear(),
+                _ => (),
+            }
+        }
+
+        /// Press `key_code` and keep it held, like holding a key of a real remote: after `initial`,
+        /// the press is repeated every `rate`, until `stop_key_repeat` is called or the key is
+        /// released with `send_passthrough`.
+        ///
+        /// Starting a repeat stops - and releases - the key repeated previously, if any.
+        /// The repeat also stops when the target disconnects, and when the controller is dropped.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn start_key_repeat(
+            &self,
+            transaction_label: u8,
+            key_code: KeyCode,
+            initial: Duration,
+            rate: Duration,
+        ) -> Result<(), EspError> {
+            self.stop_key_repeat()?;
+
+            let timer = EspTaskTimerService::new()?.timer(|| {
+                if let Some(repeat) = KEY_REPEAT.lock().as_mut() {
+                    if let Err(err) = passthrough(repeat.transaction_label, repeat.key_code, true) {
+                        warn!("Repeating key {:?} failed: {err}", repeat.key_code);
+                    }
+
+                    if !repeat.repeating {
+                        repeat.repeating = true;
+
+                        if let Err(err) = repeat.timer.every(repeat.rate) {
+                            warn!("Starting the key repeat timer failed: {err}");
+                        }
+                    }
+                }
+            })?;
+
+            passthrough(transaction_label, key_code, true)?;
+
+            let mut repeat = KEY_REPEAT.lock();
+
+            repeat
+                .insert(KeyRepeat {
+                    transaction_label,
+                    key_code,
+                    rate,
+                    repeating: false,
+                    timer,
+                })
+                .timer
+                .after(initial)?;
+
+            Ok(())
+        }
+
+        /// Stop the key repeat started with `start_key_repeat`, and release the key.
+        ///
+        /// Does nothing if no key is being repeated.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn stop_key_repeat(&self) -> Result<(), EspError> {
+            let repeat = KEY_REPEAT.lock().take();
+
+            if let Some(repeat) = repeat {
+                // Stop the timer before releasing the key, so that no press follows the release
+                drop(repeat.timer);
+
+                passthrough(repeat.transaction_label, repeat.key_code, false)?;
+            }
+
+            Ok(())
+        }
+
+        /// Returns whether the connected target supports the passthrough command with `key_code`,
+        /// or `None` if this is not known yet.
+        ///
+        /// AVRC does not allow a controller to query the passthrough commands supported by the target,
+        /// so this is learned from the responses to the commands sent since the connection:
+        /// a key is supported once a command with it was accepted, and unsupported once one was
+        /// rejected or reported as not implemented.
+        pub fn is_key_supported(&self, key_code: KeyCode) -> Option<bool> {
+            KEYS.lock().get(key_code)
+        }
+
+        /// Returns - up to `N` of - the keys known to be supported by the connected target,
+        /// see `is_key_supported`.
+        pub fn supported_keys<const N: usize>(&self) -> heapless::Vec<KeyCode, N> {
+            KEYS.lock().collect(true)
+        }
+
+        /// Returns - up to `N` of - the keys known to be unsupported by the connected target,
+        /// e.g. to gray out the corresponding buttons, see `is_key_supported`.
+        pub fn unsupported_keys<const N: usize>(&self) -> heapless::Vec<KeyCode, N> {
+            KEYS.lock().collect(false)
+        }
+
+        fn update_keys(event: &AvrccEvent) {
+            match event {
+                AvrccEvent::Passthrough {
+                    key_code,
+                    response_code,
+                    ..
+                } => match response_code {
+                    ResponseCode::Accepted => KEYS.lock().set(*key_code, true),
+                    ResponseCode::NotImplemented | ResponseCode::Rejected => {
+                        KEYS.lock().set(*key_code, false)
+                    }
+                    _ => (),
+                },
+                AvrccEvent::Disconnected(_) => *KEYS.lock() = KeySupport::new(),
+                _ => (),
+            }
+        }
+
+        /// Returns the last playback status reported by the target with a `Notification::Playback`
+        /// event, or `None` if none was reported yet since the last connection.
+        pub fn playback_status(&self) -> Option<PlaybackStatus> {
+            *PLAYBACK.lock()
+        }
+
+        /// Returns the UID of the current track, as last reported by the target with
+        /// a `Notification::TrackChanged` event, e.g. to highlight the current item in a listing.
+        ///
+        /// Returns `None` if no track is selected, or if no track change was reported yet since
+        /// the last connection or since the UIDs of the target changed (`Notification::Uuids`),
+        /// which invalidates the UID. As ESP IDF does not report the interim responses
+        /// of notifications, the UID cannot be queried: it is only known after the first track change
+        /// following the registration of the `NotificationType::TrackChanged` notification.
+        pub fn current_track_uid(&self) -> Option<u64> {
+            *TRACK_UID.lock()
+        }
+
+        /// Send a key click (i.e. a press followed by a release) for a media remote action,
+        /// see `KeyCode::from_media_action`. The play/pause toggle uses the `playback_status`,
+        /// which requires registering the `NotificationType::Playback` notification.
+        ///
+        /// Returns the key code which was sent.
+        pub fn send_media_action(
+            &self,
+            transaction_label: u8,
+            action: MediaAction,
+        ) -> Result<KeyCode, EspError> {
+            let key_code = KeyCode::from_media_action(action, self.playback_status());
+
+            self.send_passthrough(transaction_label, key_code, true)?;
+            self.send_passthrough(transaction_label, key_code, false)?;
+
+            Ok(key_code)
+        }
+
+        /// Send a sequence of key clicks (i.e. a press followed by a release), waiting `gap`
+        /// between two consecutive clicks. This blocks the calling task for the whole sequence.
+        ///
+        /// The transaction labels are allocated as with `send_passthrough_auto`.
+        ///
+        /// If a command fails - e.g. with `ESP_ERR_NO_MEM` when all the transaction labels are
+        /// outstanding - the sequence is aborted, and the returned error contains the number of keys
+        /// which were completely sent. If the release of a key fails, it is sent once more, so that
+        /// the key is not left pressed on the target; the error tells whether that failed too.
+        pub fn send_passthrough_sequence(
+            &self,
+            keys: &[KeyCode],
+            gap: core::time::Duration,
+        ) -> Result<(), PassthroughSequenceError> {
+            for (index, key) in keys.iter().enumerate() {
+                if index > 0 {
+                    crate::hal::delay::FreeRtos::delay_ms(
+                        gap.as_millis().try_into().unwrap_or(u32::MAX),
+                    );
+                }
+
+                self.send_passthrough_auto(*key, true).map_err(|error| {
+                    PassthroughSequenceError {
+                        sent: index,
+                        error,
+                        pressed: false,
+                    }
+                })?;
+
+                if let Err(error) = self.send_passthrough_auto(*key, false) {
+                    let pressed = self.send_passthrough_auto(*key, false).is_err();
+
+                    return Err(PassthroughSequenceError {
+                        sent: index,
+                        error,
+                        pressed,
+                    });
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Send a passthrough command with an automatically allocated transaction label,
+        /// and return that label.
+        ///
+        /// A label is outstanding from the moment the command is sent until its response arrives,
+        /// so that two outstanding commands never share a label, and responses are always correlated
+        /// with the right command. As transaction labels are 4 bits wide, and as
+        /// `KEEPALIVE_TRANSACTION_LABEL` is reserved, at most 15 commands can be outstanding:
+        /// when all the labels are outstanding - e.g. because the target stopped responding -
+        /// this fails with `ESP_ERR_NO_MEM` instead of reusing a label.
+        /// Stale transactions can be aborted to free their labels with `release_label`, or
+        /// automatically with `set_passthrough_timeout`. All the labels are freed upon disconnection.
+        ///
+        /// The allocated labels should not be used by the application with the other commands
+        /// while they are outstanding.
+        pub fn send_passthrough_auto(
+            &self,
+            key_code: KeyCode,
+            pressed: bool,
+        ) -> Result<u8, EspError> {
+            let label = LABELS.lock().allocate()?;
+
+            if let Err(err) = self.send_passthrough(label, key_code, pressed) {
+                LABELS.lock().release(label);
+
+                return Err(err);
+            }
+
+            Ok(label)
+        }
+
+        /// Abort the outstanding transaction with `transaction_label`, allocated by
+        /// `send_passthrough_auto`, so that the label can be allocated again. A late response
+        /// to the aborted transaction might then be taken for the response of the next command
+        /// using the label.
+        pub fn release_label(&self, transaction_label: u8) {
+            LABELS.lock().release(transaction_label);
+        }
+
+        /// Returns the number of transaction labels allocated by `send_passthrough_auto`
+        /// which are awaiting their response.
+        pub fn outstanding_labels(&self) -> u32 {
+            LABELS.lock().outstanding.count_ones()
+        }
+
+        fn update_labels(event: &AvrccEvent) {
+            match event {
+                AvrccEvent::Passthrough {
+                    transaction_level, ..
+                } => LABELS.lock().release(*transaction_level),
+                AvrccEvent::Disconnected(_) => *LABELS.lock() = Labels::new(),
+                _ => (),
+            }
+        }
+
+        /// Periodically send a harmless command (a notification capabilities request) to the target,
+        /// so that targets which drop idle connections keep the connection alive.
+        ///
+        /// The command is sent with transaction label `KEEPALIVE_TRANSACTION_LABEL`, which should
+        /// therefore not be used by the application while the keepalive is enabled.
+        /// Its responses are delivered as regular `AvrccEvent::NotificationCapabilities` events.
+        ///
+        /// The keepalive is stopped when the connection is lost, when `disable_keepalive` is called,
+        /// or when the controller is dropped.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn enable_keepalive(&self, interval: Duration) -> Result<(), EspError> {
+            let timer = EspTaskTimerService::new()?.timer(|| {
+                if let Err(err) = command(esp!(unsafe {
+                    esp_avrc_ct_send_get_rn_capabilities_cmd(KEEPALIVE_TRANSACTION_LABEL)
+                })) {
+                    warn!("Sending keepalive failed: {err}");
+                }
+            })?;
+
+            timer.every(interval)?;
+
+            let _old = KEEPALIVE.lock().replace(timer);
+
+            Ok(())
+        }
+
+        /// Stop sending the periodic keepalive command enabled with `enable_keepalive`.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn disable_keepalive(&self) -> Result<(), EspError> {
+            let _old = KEEPALIVE.lock().take();
+
+            Ok(())
+        }
+
+        /// Automatically reconnect to the device with address `addr` whenever it disconnects.
+        ///
+        /// As AVRC connections are established by ESP IDF on top of A2DP connections, `connect`
+        /// should initiate the A2DP connection to the device, e.g. with `EspA2dp::connect_sink`.
+        /// After a `Disconnected` event for `addr`, `connect` is called every `retry`,
+        /// until a `Connected` event for `addr` is received.
+        ///
+        /// The reconnection loop is stopped by `stop_auto_reconnect`, or when the controller is dropped.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn auto_reconnect<F>(
+            &self,
+            addr: BdAddr,
+            retry: Duration,
+            mut connect: F,
+        ) -> Result<(), EspError>
+        where
+            F: FnMut(&BdAddr) -> Result<(), EspError> + Send + 'static,
+        {
+            let timer = EspTaskTimerService::new()?.timer(move || {
+                if let Err(err) = connect(&addr) {
+                    warn!("Reconnecting to {addr} failed: {err}");
+                }
+            })?;
+
+            let _old = AUTO_RECONNECT
+                .lock()
+                .replace(AutoReconnect { addr, retry, timer });
+
+            Ok(())
+        }
+
+        /// Stop the reconnection loop started with `auto_reconnect`.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn stop_auto_reconnect(&self) -> Result<(), EspError> {
+            let _old = AUTO_RECONNECT.lock().take();
+
+            Ok(())
+        }
+
+        /// Connect to the device with address `addr`, waiting at most `timeout` for the AVRC connection.
+        ///
+        /// As with `auto_reconnect`, `connect` should initiate the A2DP connection to the device,
+        /// e.g. with `EspA2dp::connect_sink`. If no `Connected` event for `addr` is received within
+        /// `timeout`, `cancel` is called to abort the pending connection attempt - e.g. with
+        /// `EspA2dp::disconnect` - so that the stack does not keep attempting it,
+        /// and `ESP_ERR_TIMEOUT` is returned.
+        ///
+        /// This blocks the calling task, and must therefore not be called from the callback
+        /// of `subscribe`, which would prevent the `Connected` event from being received.
+        ///
+        /// # Errors
+        ///
+        /// Returns `ESP_ERR_INVALID_STATE` if another connection with a timeout is in progress.
+        pub fn connect_with_timeout<C, D>(
+            &self,
+            addr: BdAddr,
+            timeout: Duration,
+            connect: C,
+            cancel: D,
+        ) -> Result<(), EspError>
+        where
+            C: FnOnce(&BdAddr) -> Result<(), EspError>,
+            D: FnOnce(&BdAddr) -> Result<(), EspError>,
+        {
+            let waitable = Arc::new(Waitable::new(false));
+
+            {
+                let mut connecting = CONNECTING.lock();
+
+                if connecting.is_some() {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+                }
+
+                *connecting = Some((addr, waitable.clone()));
+            }
+
+            let result = connect(&addr).and_then(|_| {
+                waitable.wait_timeout_while_and_get(timeout, |connected| Ok(!*connected), |_| ())
+            });
+
+            let _old = CONNECTING.lock().take();
+
+            let (timed_out, _) = result?;
+
+            if timed_out {
+                warn!("Connecting to {addr} timed out, cancelling");
+
+                if let Err(err) = cancel(&addr) {
+                    warn!("Cancelling the connection to {addr} failed: {err}");
+                }
+
+                Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())?;
+            }
+
+            Ok(())
+        }
+
+        fn update_connecting(event: &AvrccEvent) {
+            if let AvrccEvent::Connected(addr) = event {
+                if let Some((_, waitable)) = CONNECTING
+                    .lock()
+                    .as_ref()
+                    .filter(|(connecting, _)| connecting == addr)
+                {
+                    *waitable.state.lock() = true;
+                    waitable.cvar.notify_all();
+                }
+            }
+        }
+
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        fn update_auto_reconnect(event: &AvrccEvent) {
+            if let Some(auto_reconnect) = AUTO_RECONNECT.lock().as_ref() {
+                let result = match event {
+                    AvrccEvent::Disconnected(addr) if *addr == auto_reconnect.addr => {
+                        info!("Disconnected from {addr}, reconnecting");
+
+                        auto_reconnect.timer.every(auto_reconnect.retry)
+                    }
+                    AvrccEvent::Connected(addr) if *addr == auto_reconnect.addr => {
+                        auto_reconnect.timer.cancel().map(|_| ())
+                    }
+                    _ => Ok(()),
+                };
+
+                if let Err(err) = result {
+                    warn!("Updating the reconnection timer failed: {err}");
+                }
+            }
+        }
+
+        /// Call `callback` every `interval` with the playback progress of the current track,
+        /// i.e. its position and length in milliseconds, and the playback status.
+        ///
+        /// The progress is tracked from the `Notification::PlaybackPosition`, `Notification::Playback`
+        /// and `Notification::TrackChanged` notifications, from the `AvrccEvent::PlayStatus` responses,
+        /// and from `MetadataId::PlayingTime` metadata responses for the length, which the application
+        /// should therefore register and request (e.g. polling the play status with `request_play_status`).
+        /// Between two position notifications - which some targets send rarely, or never -
+        /// the position is interpolated from the last known position and the time elapsed while playing,
+        /// so that a seek bar advances smoothly. The interpolation restarts from 0 on track changes,
+        /// and is frozen while the playback is paused, stopped or seeking.
+        ///
+        /// Unknown values are passed as `None`, e.g. the length if no playing time was received
+        /// for the current track.
+        ///
+        /// The callback is called from the timer task, until `stop_progress` is called,
+        /// or the controller is dropped.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn on_progress<F>(&self, interval: Duration, mut callback: F) -> Result<(), EspError>
+        where
+            F: FnMut(Option<u32>, Option<u32>, Option<PlaybackStatus>) + Send + 'static,
+        {
+            let timer = EspTaskTimerService::new()?.timer(move || {
+                let progress = *PROGRESS.lock();
+                let now = unsafe { esp_timer_get_time() };
+
+                callback(progress.position_at(now), progress.length, progress.status);
+            })?;
+
+            timer.every(interval)?;
+
+            let _old = PROGRESS_TIMER.lock().replace(timer);
+
+            Ok(())
+        }
+
+        /// Stop calling the callback registered with `on_progress`.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn stop_progress(&self) -> Result<(), EspError> {
+            let _old = PROGRESS_TIMER.lock().take();
+
+            Ok(())
+        }
+
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        fn update_progress(event: &AvrccEvent) {
+            let now = unsafe { esp_timer_get_time() };
+            let mut progress = PROGRESS.lock();
+
+            match event {
+                AvrccEvent::Notification(Notification::PlaybackPosition(position)) => {
+                    progress.position = *position;
+                    progress.since = now;
+                }
+                AvrccEvent::Notification(Notification::Playback(status)) => {
+                    progress.position = progress.position_at(now);
+                    progress.since = now;
+                    progress.status = Some(*status);
+                }
+                AvrccEvent::PlayStatus {
+                    length,
+                    position,
+                    status: Some(status),
+                } => {
+                    progress.position = *position;
+                    progress.length = *length;
+                    progress.since = now;
+                    progress.status = Some(*status);
+                }
+                AvrccEvent::Notification(Notification::TrackChanged(_)) => {
+                    progress.position = Some(0);
+                    progress.length = None;
+                    progress.since = now;
+                }
+                AvrccEvent::Disconnected(_) => *progress = Progress::new(),
+                event => {
+                    if let Some(length) = event.playing_time() {
+                        progress.length = Some(length);
+                    }
+                }
+            }
+        }
+
+        /// Returns the last volume reported by the target, either with a `Notification::Volume`
+        /// or with a `AvrccEvent::Volume` event, or `None` if no volume was reported yet
+        /// since the last connection.
+        ///
+        /// The volume is updated on every event, even when debouncing is enabled
+        /// with `set_volume_debounce`.
+        pub fn current_volume(&self) -> Option<u8> {
+            *VOLUME.lock()
+        }
+
+        /// Returns `true` if the connected target supports absolute volume, i.e. `set_volume`,
+        /// e.g. to show a volume slider rather than the `KeyCode::VolumeUp` and `KeyCode::VolumeDown` buttons.
+        ///
+        /// Once the notification capabilities of the target are received (see `request_capabilities`),
+        /// absolute volume is supported if they include `NotificationType::Volume`. Until then, it is
+        /// derived from the `AvrccEvent::RemoteFeatures` event: the target must support
+        /// `Feature::AdvancedControl` (AVRCP 1.4) and `TargetFeature::Category2` (amplifiers),
+        /// which is a good indication, but not a guarantee. Returns `false` if neither was received
+        /// since the last connection.
+        pub fn supports_absolute_volume(&self) -> bool {
+            let absolute_volume = ABSOLUTE_VOLUME.lock();
+
+            absolute_volume
+                .capabilities
+                .or(absolute_volume.features)
+                .unwrap_or(false)
+        }
+
+        /// Debounce the volume change notifications sent by the target, e.g. when the user turns
+        /// the volume knob of a speaker.
+        ///
+        /// With `Some(quiet)`, `Notification::Volume` events are only delivered once no other
+        /// volume change notification was received for `quiet`, and carry the settled volume.
+        /// With `None` - the default - every notification is delivered immediately.
+        ///
+        /// Note that with debouncing enabled, the settled volume is delivered from the timer task,
+        /// rather than from the Bluedroid task.
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        pub fn set_volume_debounce(&self, quiet: Option<Duration>) -> Result<(), EspError> {
+            let debounce = if let Some(quiet) = quiet {
+                let timer = EspTaskTimerService::new()?.timer(|| {
+                    if let Some(volume) = *VOLUME.lock() {
+                        dispatch(AvrccEvent::Notification(Notification::Volume(volume)));
+                    }
+                })?;
+
+                Some((quiet, timer))
+            } else {
+                None
+            };
+
+            *VOLUME_DEBOUNCE.lock() = debounce;
+
+            Ok(())
+        }
+
+        /// Update the volume tracking, and return `true` if the event should be delivered later,
+        /// once the volume has settled.
+        fn update_volume(event: &AvrccEvent) -> bool {
+            match event {
+                AvrccEvent::Volume(volume) => {
+                    *VOLUME.lock() = Some(*volume);
+                }
+                AvrccEvent::Notification(Notification::Volume(volume)) => {
+                    *VOLUME.lock() = Some(*volume);
+
+                    #[cfg(esp_idf_comp_esp_timer_enabled)]
+                    if let Some((quiet, timer)) = VOLUME_DEBOUNCE.lock().as_ref() {
+                        if let Err(err) = timer.after(*quiet) {
+                            warn!("Restarting the volume debounce timer failed: {err}");
+                        } else {
+                            return true;
+                        }
+                    }
+                }
+                AvrccEvent::RemoteFeatures {
+                    features,
+                    target_features,
+                    ..
+                } => {
+                    ABSOLUTE_VOLUME.lock().features = Some(
+                        features.contains(Feature::AdvancedControl)
+                            && target_features.contains(TargetFeature::Category2),
+                    );
+                }
+                AvrccEvent::NotificationCapabilities { capabilities, .. } => {
+                    ABSOLUTE_VOLUME.lock().capabilities =
+                        Some(capabilities.contains(NotificationType::Volume));
+                }
+                AvrccEvent::Disconnected(_) => {
+                    *VOLUME.lock() = None;
+                    *ABSOLUTE_VOLUME.lock() = AbsoluteVolume::new();
+                }
+                _ => (),
+            }
+
+            false
+        }
+
+        fn update_playback(event: &AvrccEvent) {
+            match event {
+                AvrccEvent::Notification(Notification::Playback(playback)) => {
+                    *PLAYBACK.lock() = Some(*playback);
+                }
+                AvrccEvent::Notification(Notification::TrackChanged(uid)) => {
+                    *TRACK_UID.lock() = *uid;
+                }
+                AvrccEvent::Notification(Notification::Uuids) => {
+                    *TRACK_UID.lock() = None;
+                }
+                AvrccEvent::Disconnected(_) => {
+                    *PLAYBACK.lock() = None;
+                    *TRACK_UID.lock() = None;
+                }
+                _ => (),
+            }
+        }
+
+        fn update_registered(event: &AvrccEvent) {
+            match event {
+                AvrccEvent::Notification(notification) => {
+                    REGISTERED.lock().remove(notification.notification_type());
+                }
+                AvrccEvent::Disconnected(_) => {
+                    REGISTERED.lock().clear();
+                }
+                _ => (),
+            }
+        }
+
+        unsafe extern "C" fn event_handler(
+            event: esp_avrc_ct_cb_event_t,
+            param: *mut esp_avrc_ct_cb_param_t,
+        ) {
+            if let Some(param) = unsafe { param.as_ref() } {
+                let event = AvrccEvent::from((event, param));
+
+                #[cfg(esp_idf_comp_esp_timer_enabled)]
+                if matches!(event, AvrccEvent::Disconnected(_)) {
+                    let _old = KEEPALIVE.lock().take();
+                    let _old = KEY_REPEAT.lock().take();
+                }
+
+                #[cfg(esp_idf_comp_esp_timer_enabled)]
+                Self::update_auto_reconnect(&event);
+
+                Self::update_connecting(&event);
+
+                info!("Got event {{ {event} }}");
+
+                update_stats(&event);
+
+                Self::update_registered(&event);
+
+                Self::update_playback(&event);
+
+                Self::update_keys(&event);
+
+                Self::update_labels(&event);
+
+                #[cfg(esp_idf_comp_esp_timer_enabled)]
+                Self::update_passthrough_timeout(&event);
+
+                #[cfg(esp_idf_comp_esp_timer_enabled)]
+                Self::update_progress(&event);
+
+                Self::update_auto_refresh(&event);
+
+                #[cfg(esp_idf_comp_esp_timer_enabled)]
+                Self::update_now_playing_refresh(&event);
+
+                #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+                cover_art::update_supported(&event);
+
+                if !Self::update_volume(&event) {
+                    dispatch(event);
+                }
+            }
+        }
+    }
+
+    impl<'d, M, T> Drop for EspAvrcc<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+    {
+        fn drop(&mut self) {
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            self.disable_keepalive().unwrap();
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            if let Err(err) = self.stop_key_repeat() {
+                warn!("Releasing the repeated key failed: {err}");
+            }
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            self.stop_auto_reconnect().unwrap();
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            self.set_passthrough_timeout(None, 0).unwrap();
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            self.set_volume_debounce(None).unwrap();
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            self.disable_now_playing_refresh().unwrap();
+
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            {
+                self.stop_progress().unwrap();
+                *PROGRESS.lock() = Progress::new();
+            }
+
+            *VOLUME.lock() = None;
+            *ABSOLUTE_VOLUME.lock() = AbsoluteVolume::new();
+            *PLAYBACK.lock() = None;
+            *TRACK_UID.lock() = None;
+            *KEYS.lock() = KeySupport::new();
+            *LABELS.lock() = Labels::new();
+            REGISTERED.lock().clear();
+            *AUTO_REFRESH.lock() = None;
+
+            #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+            cover_art::clear_supported();
+
+            self.unsubscribe().unwrap();
+
+            esp!(unsafe { esp_avrc_ct_deinit() }).unwrap();
+
+            // Not possible because this function rejects NULL arguments
+            // esp!(unsafe { esp_avrc_ct_register_callback(Some(None)) })?;
+
+            SINGLETON.release().unwrap();
+        }
+    }
+
+    unsafe impl<'d, M, T> Send for EspAvrcc<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>> + Send,
+    {
+    }
+
+    // Safe because the ESP IDF Bluedroid APIs all do message passing
+    // to a dedicated Bluedroid task
+    unsafe impl<'d, M, T> Sync for EspAvrcc<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>> + Send,
+    {
+    }
+
+    /// Keeps a local volume - e.g. the gain of the DAC of a speaker - and the volume of the target
+    /// in sync, in both directions.
+    ///
+    /// Volume changes reported by the target are passed to the `on_remote` callback, and local volume
+    /// changes (`set_local`) are sent to the target. The target reports a local change back - as the
+    /// response to the set volume command and as a volume change notification - and this echo is not
+    /// passed to the callback, so that the two volumes do not oscillate.
+    ///
+    /// `handle_event` should be called with every event of the controller, from the callback
+    /// of `EspAvrcc::subscribe`. The application should register the `NotificationType::Volume`
+    /// notification once connected; as AVRC notifications only fire once per registration,
+    /// the helper then re-registers it after each notification.
+    pub struct VolumeSync<F> {
+        transaction_label: u8,
+        on_remote: Mutex<F>,
+        state: Mutex<VolumeSyncState>,
+    }
+
+    struct VolumeSyncState {
+        volume: Option<u8>,
+        /// The volume sent to the target and not reported back yet
+        pending: Option<u8>,
+    }
+
+    impl<F> VolumeSync<F>
+    where
+        F: FnMut(u8) + Send,
+    {
+        /// Create a new helper sending the commands with `transaction_label`,
+        /// and calling `on_remote` with the volume changes of the target.
+        pub const fn new(transaction_label: u8, on_remote: F) -> Self {
+            Self {
+                transaction_label,
+                on_remote: Mutex::new(on_remote),
+                state: Mutex::new(VolumeSyncState {
+                    volume: None,
+                    pending: None,
+                }),
+            }
+        }
+
+        /// Returns the synchronized volume, or `None` if it is not known yet.
+        pub fn volume(&self) -> Option<u8> {
+            self.state.lock().volume
+        }
+
+        /// Handle an event of the controller.
+        pub fn handle_event<'d, M, T>(
+            &self,
+            avrcc: &EspAvrcc<'d, M, T>,
+            event: &AvrccEvent,
+        ) -> Result<(), EspError>
+        where
+            M: BtClassicEnabled,
+            T: Borrow<BtDriver<'d, M>>,
+        {
+            match event {
+                AvrccEvent::Notification(Notification::Volume(volume)) => {
+                    self.update(*volume);
+
+                    avrcc.register_notification(
+                        self.transaction_label,
+                        NotificationType::Volume,
+                        0,
+                    )?;
+                }
+                AvrccEvent::Volume(volume) => self.update(*volume),
+                AvrccEvent::Disconnected(_) => {
+                    let mut state = self.state.lock();
+
+                    state.volume = None;
+                    state.pending = None;
+                }
+                _ => (),
+            }
+
+            Ok(())
+        }
+
+        /// Send a local volume change to the target.
+        ///
+        /// Does nothing if `volume` is already the synchronized volume.
+        pub fn set_local<'d, M, T>(
+            &self,
+            avrcc: &EspAvrcc<'d, M, T>,
+            volume: u8,
+        ) -> Result<(), EspError>
+        where
+            M: BtClassicEnabled,
+            T: Borrow<BtDriver<'d, M>>,
+        {
+            if volume > MAX_VOLUME {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
+            // The state is updated before sending, as the response might arrive before
+            // `set_volume` returns, and rolled back if sending fails
+            let previous = {
+                let mut state = self.state.lock();
+
+                if state.volume == Some(volume) {
+                    return Ok(());
+                }
+
+                let previous = (state.volume, state.pending);
+
+                state.volume = Some(volume);
+                state.pending = Some(volume);
+
+                previous
+            };
+
+            let result = avrcc.set_volume(self.transaction_label, volume);
+
+            if result.is_err() {
+                let mut state = self.state.lock();
+
+                // Unless the state was changed meanwhile, e.g. by a notification of the target
+                if state.volume == Some(volume) && state.pending == Some(volume) {
+                    (state.volume, state.pending) = previous;
+                }
+            }
+
+            result
+        }
+
+        fn update(&self, volume: u8) {
+            let notify = {
+                let mut state = self.state.lock();
+
+                // The target might also set a different volume than the one sent, e.g. by rounding it
+                let echo = state.pending.take() == Some(volume);
+                let changed = state.volume != Some(volume);
+
+                state.volume = Some(volume);
+
+                changed && !echo
+            };
+
+            if notify {
+                (*self.on_remote.lock())(volume);
+            }
+        }
+    }
+
+    /// The error returned by `EspAvrcc::send_passthrough_sequence`.
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct PassthroughSequenceError {
+        /// The number of keys which were sent before the failure.
+        pub sent: usize,
+        /// The error of the failed command.
+        pub error: EspError,
+        /// `true` if the key at index `sent` was left pressed on the target, i.e. if it was pressed
+        /// but could not be released.
+        pub pressed: bool,
+    }
+
+    impl fmt::Display for PassthroughSequenceError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Sending key {} failed: {}", self.sent, self.error)?;
+
+            if self.pressed {
+                write!(f, " (the key was left pressed)")?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::error::Error for PassthroughSequenceError {}
+
+    /// Link statistics of the controller, see `EspAvrcc::stats`.
+    ///
+    /// The counters are kept across controller instances, so that they cover the whole uptime.
+    /// Rejected notification registrations are not counted, as ESP IDF does not report them.
+    #[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+    pub struct AvrcStats {
+        /// The number of connections.
+        pub connects: u32,
+        /// The number of disconnections.
+        pub disconnects: u32,
+        /// The number of commands which could not be sent, plus the number of passthrough
+        /// commands which the target rejected or did not implement.
+        pub command_failures: u32,
+    }
+
+    struct AtomicStats {
+        connects: AtomicU32,
+        disconnects: AtomicU32,
+        command_failures: AtomicU32,
+    }
+
+    static STATS: AtomicStats = AtomicStats {
+        connects: AtomicU32::new(0),
+        disconnects: AtomicU32::new(0),
+        command_failures: AtomicU32::new(0),
+    };
+
+    /// The maximum absolute volume, as AVRC volumes are 7-bit values.
+    pub const MAX_VOLUME: u8 = 127;
+
+    /// Convert a percentage in the range `0..=100` to an absolute volume in the range `0..=MAX_VOLUME`.
+    pub fn percent_to_volume(percent: u8) -> u8 {
+        ((percent.min(100) as u32 * MAX_VOLUME as u32 + 50) / 100) as u8
+    }
+
+    /// The transaction label used by the keepalive command, see `EspAvrcc::enable_keepalive`.
+    pub const KEEPALIVE_TRANSACTION_LABEL: u8 = 15;
 
     static SINGLETON: BtSingleton<AvrccEvent, ()> = BtSingleton::new(());
+
+    /// Take the singleton, then register the callback and initialize the controller.
+    ///
+    /// On failure, the singleton is released, so that a subsequent `EspAvrcc::new` call can retry
+    /// the initialization. The error is the one of the failed step.
+    ///
+    /// The callback stays registered, as ESP IDF rejects unregistering it (see `EspAvrcc::drop`).
+    /// This is harmless: with the singleton released, the events it receives - if any - have no
+    /// subscriber, and the callback is registered again on retry.
+    pub(super) fn initialize(
+        singleton: &BtSingleton<AvrccEvent, ()>,
+        register: impl FnOnce() -> Result<(), EspError>,
+        init: impl FnOnce() -> Result<(), EspError>,
+    ) -> Result<(), EspError> {
+        singleton.take()?;
+
+        let result = register().and_then(|_| init());
+
+        if let Err(err) = result {
+            if let Err(release_err) = singleton.release() {
+                warn!("Releasing the singleton failed: {release_err}");
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn passthrough(
+        transaction_label: u8,
+        key_code: KeyCode,
+        pressed: bool,
+    ) -> Result<(), EspError> {
+        command(esp!(unsafe {
+            esp_avrc_ct_send_passthrough_cmd(
+                transaction_label,
+                key_code as _,
+                if pressed { 0 } else { 1 },
+            )
+        }))
+    }
+
+    /// Register `notification` again, for the re-registrations done by the controller itself.
+    fn renew_notification(
+        transaction_label: u8,
+        notification: NotificationType,
+    ) -> Result<(), EspError> {
+        command(esp!(unsafe {
+            esp_avrc_ct_send_register_notification_cmd(transaction_label, notification as _, 0)
+        }))?;
+
+        REGISTERED.lock().insert(notification);
+
+        Ok(())
+    }
+
+    /// Count the failure of a command, see `EspAvrcc::stats`.
+    fn command(result: Result<(), EspError>) -> Result<(), EspError> {
+        if result.is_err() {
+            STATS.command_failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    fn update_stats(event: &AvrccEvent) {
+        let counter = match event {
+            AvrccEvent::Connected(_) => &STATS.connects,
+            AvrccEvent::Disconnected(_) => &STATS.disconnects,
+            AvrccEvent::Passthrough {
+                response_code: ResponseCode::NotImplemented | ResponseCode::Rejected,
+                ..
+            } => &STATS.command_failures,
+            _ => return,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn dispatch(event: AvrccEvent) {
+        // Events might be delivered from the timer task too, so serialize the calls
+        let _guard = DISPATCH.lock();
+
+        unsafe { SINGLETON.call(event) };
+    }
+
+    static DISPATCH: Mutex<()> = Mutex::new(());
+
+    static CONNECTING: Mutex<Option<(BdAddr, Arc<Waitable<bool>>)>> = Mutex::new(None);
+
+    static VOLUME: Mutex<Option<u8>> = Mutex::new(None);
+
+    /// The support of absolute volume by the target, as derived from its features and from its
+    /// notification capabilities, see `EspAvrcc::supports_absolute_volume`.
+    struct AbsoluteVolume {
+        features: Option<bool>,
+        capabilities: Option<bool>,
+    }
+
+    impl AbsoluteVolume {
+        const fn new() -> Self {
+            Self {
+                features: None,
+                capabilities: None,
+            }
+        }
+    }
+
+    static ABSOLUTE_VOLUME: Mutex<AbsoluteVolume> = Mutex::new(AbsoluteVolume::new());
+
+    static PLAYBACK: Mutex<Option<PlaybackStatus>> = Mutex::new(None);
+
+    static TRACK_UID: Mutex<Option<u64>> = Mutex::new(None);
+
+    static REGISTERED: Mutex<EnumSet<NotificationType>> = Mutex::new(EnumSet::EMPTY);
+
+    /// The passthrough keys known to be supported - or not - by the target,
+    /// as bit masks indexed by the key codes (which are 7-bit values).
+    struct KeySupport {
+        supported: u128,
+        unsupported: u128,
+    }
+
+    impl KeySupport {
+        const fn new() -> Self {
+            Self {
+                supported: 0,
+                unsupported: 0,
+            }
+        }
+
+        fn get(&self, key_code: KeyCode) -> Option<bool> {
+            let mask = 1_u128 << (key_code as u8 & 0x7f);
+
+            if self.supported & mask != 0 {
+                Some(true)
+            } else if self.unsupported & mask != 0 {
+                Some(false)
+            } else {
+                None
+            }
+        }
+
+        fn set(&mut self, key_code: KeyCode, supported: bool) {
+            let mask = 1_u128 << (key_code as u8 & 0x7f);
+
+            if supported {
+                self.supported |= mask;
+                self.unsupported &= !mask;
+            } else {
+                self.unsupported |= mask;
+                self.supported &= !mask;
+            }
+        }
+
+        fn collect<const N: usize>(&self, supported: bool) -> heapless::Vec<KeyCode, N> {
+            (0..128_u8)
+                .filter_map(|code| KeyCode::try_from(code).ok())
+                .filter(|key_code| self.get(*key_code) == Some(supported))
+                .take(N)
+                .collect()
+        }
+    }
+
+    static KEYS: Mutex<KeySupport> = Mutex::new(KeySupport::new());
+
+    /// The transaction labels allocated by `EspAvrcc::send_passthrough_auto`.
+    struct Labels {
+        /// The outstanding labels, one bit per label
+        outstanding: u16,
+        /// The label from which the search for a free label starts, so that a label which was
+        /// just freed is not reused immediately
+        next: u8,
+    }
+
+    impl Labels {
+        const fn new() -> Self {
+            Self {
+                outstanding: 0,
+                next: 0,
+            }
+        }
+
+        fn allocate(&mut self) -> Result<u8, EspError> {
+            let label = (0..KEEPALIVE_TRANSACTION_LABEL)
+                .map(|offset| (self.next + offset) % KEEPALIVE_TRANSACTION_LABEL)
+                .find(|label| self.outstanding & (1 << *label) == 0)
+                .ok_or(EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+            self.outstanding |= 1 << label;
+            self.next = (label + 1) % KEEPALIVE_TRANSACTION_LABEL;
+
+            Ok(label)
+        }
+
+        fn release(&mut self, label: u8) {
+            if label < 16 {
+                self.outstanding &= !(1 << label);
+            }
+        }
+    }
+
+    static LABELS: Mutex<Labels> = Mutex::new(Labels::new());
+
+    static AUTO_REFRESH: Mutex<Option<(u8, EnumSet<MetadataId>)>> = Mutex::new(None);
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static VOLUME_DEBOUNCE: Mutex<Option<(Duration, EspTimer<'static>)>> = Mutex::new(None);
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    struct NowPlayingRefresh {
+        transaction_label: u8,
+        quiet: Duration,
+        timer: EspTimer<'static>,
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static NOW_PLAYING_REFRESH: Mutex<Option<NowPlayingRefresh>> = Mutex::new(None);
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static KEEPALIVE: Mutex<Option<EspTimer<'static>>> = Mutex::new(None);
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    struct AutoReconnect {
+        addr: BdAddr,
+        retry: Duration,
+        timer: EspTimer<'static>,
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static AUTO_RECONNECT: Mutex<Option<AutoReconnect>> = Mutex::new(None);
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    struct KeyRepeat {
+        transaction_label: u8,
+        key_code: KeyCode,
+        rate: Duration,
+        repeating: bool,
+        timer: EspTimer<'static>,
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static KEY_REPEAT: Mutex<Option<KeyRepeat>> = Mutex::new(None);
+
+    /// A passthrough command awaiting its response, see `EspAvrcc::set_passthrough_timeout`.
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    #[derive(Copy, Clone)]
+    struct PendingPassthrough {
+        transaction_label: u8,
+        key_code: KeyCode,
+        pressed: bool,
+        /// When the command times out, in microseconds since boot
+        deadline: i64,
+        /// How many times the command was sent again
+        retries: u32,
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    struct PassthroughTimeout {
+        timeout: Duration,
+        retries: u32,
+        pending: heapless::Vec<PendingPassthrough, 16>,
+        timer: EspTimer<'static>,
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    impl PassthroughTimeout {
+        fn deadline(&self) -> i64 {
+            let now = unsafe { esp_timer_get_time() };
+
+            now + self.timeout.as_micros() as i64
+        }
+
+        fn track(&mut self, transaction_label: u8, key_code: KeyCode, pressed: bool) {
+            // A new command with the same transaction label supersedes the previous one
+            self.pending
+                .retain(|pending| pending.transaction_label != transaction_label);
+
+            if self.pending.is_full() {
+                self.pending.remove(0);
+            }
+
+            let _ = self.pending.push(PendingPassthrough {
+                transaction_label,
+                key_code,
+                pressed,
+                deadline: self.deadline(),
+                retries: 0,
+            });
+        }
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static PASSTHROUGH_TIMEOUT: Mutex<Option<PassthroughTimeout>> = Mutex::new(None);
+
+    /// Send again, or report, the passthrough commands whose response did not arrive in time.
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    fn check_passthrough_timeouts() {
+        let mut timed_out = heapless::Vec::<PendingPassthrough, 16>::new();
+
+        {
+            let mut timeout = PASSTHROUGH_TIMEOUT.lock();
+
+            let Some(timeout) = timeout.as_mut() else {
+                return;
+            };
+
+            let now = unsafe { esp_timer_get_time() };
+            let deadline = timeout.deadline();
+            let retries = timeout.retries;
+
+            timeout.pending.retain_mut(|pending| {
+                if pending.deadline > now {
+                    return true;
+                }
+
+                if pending.retries < retries {
+                    pending.retries += 1;
+                    pending.deadline = deadline;
+
+                    warn!(
+                        "No response to passthrough {:?}, retrying ({}/{retries})",
+                        pending.key_code, pending.retries
+                    );
+
+                    if passthrough(pending.transaction_label, pending.key_code, pending.pressed)
+                        .is_ok()
+                    {
+                        return true;
+                    }
+                }
+
+                let _ = timed_out.push(*pending);
+
+                false
+            });
+        }
+
+        for pending in timed_out {
+            LABELS.lock().release(pending.transaction_label);
+
+            dispatch(AvrccEvent::PassthroughTimeout {
+                transaction_label: pending.transaction_label,
+                key_code: pending.key_code,
+                key_pressed: pending.pressed,
+            });
+        }
+    }
+
+    /// The playback progress tracked for `EspAvrcc::on_progress`.
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    #[derive(Copy, Clone)]
+    struct Progress {
+        /// The last known position, in milliseconds
+        position: Option<u32>,
+        /// When `position` was last updated, in microseconds since boot
+        since: i64,
+        length: Option<u32>,
+        status: Option<PlaybackStatus>,
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    impl Progress {
+        const fn new() -> Self {
+            Self {
+                position: None,
+                since: 0,
+                length: None,
+                status: None,
+            }
+        }
+
+        /// The position at `now`, interpolated while playing, and capped to the length of the track.
+        fn position_at(&self, now: i64) -> Option<u32> {
+            let mut position = self.position?;
+
+            if matches!(self.status, Some(PlaybackStatus::Playing)) {
+                let elapsed = (now - self.since).max(0) / 1000;
+
+                position = position.saturating_add(elapsed.try_into().unwrap_or(u32::MAX));
+            }
+
+            Some(self.length.map_or(position, |length| position.min(length)))
+        }
+    }
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static PROGRESS: Mutex<Progress> = Mutex::new(Progress::new());
+
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    static PROGRESS_TIMER: Mutex<Option<EspTimer<'static>>> = Mutex::new(None);
+
+    /// Cover art support, i.e. fetching the album art of the current track over the
+    /// Basic Imaging Profile (BIP) OBEX channel.
+    ///
+    /// The cover art channel is a separate L2CAP channel on top of the AVRC connection.
+    /// Its PSM is not negotiated by the user: ESP IDF reads it from the BIP additional protocol
+    /// descriptor list of the target's AVRC SDP record when `EspAvrcc::connect_cover_art` is called,
+    /// and then opens the OBEX session with the given maximum packet size.
+    /// Hence cover art is only available with targets which advertise it in their SDP record,
+    /// which is reported with the `TargetFeature::cover_art` flags of the `AvrccEvent::RemoteFeatures` event.
+    ///
+    /// A typical flow is:
+    /// - Wait for `AvrccEvent::RemoteFeatures` advertising `TargetFeature::CoverArtGetImage`
+    /// - `EspAvrcc::connect_cover_art` and wait for `AvrccEvent::CoverArtState { connected: true }`
+    /// - Request the `MetadataId::CoverArt` metadata and parse it with `ImageHandle::from_metadata`
+    /// - `EspAvrcc::get_image` and collect the `AvrccEvent::CoverArtData` chunks until `final_data` is set
+    ///
+    /// Note that the image handle changes with the track, and is only valid while the cover art
+    /// channel is connected.
+    #[cfg(esp_idf_bt_avrcp_ct_cover_art_en)]
+    pub mod cover_art {
+        use core::borrow::Borrow;
+        use core::str::FromStr;
+
+        use enumset::EnumSet;
+
+        use crate::bt::{BtClassicEnabled, BtDriver};
+        use crate::private::mutex::Mutex;
+        use crate::sys::*;
+
+        use super::super::TargetFeature;
+        use super::{AvrccEvent, EspAvrcc};
+
+        /// The length of an image handle, in digits.
+        pub const IMAGE_HANDLE_LEN: usize = ESP_AVRC_CA_IMAGE_HANDLE_LEN as _;
+
+        /// The handle of a cover art image, as reported by the `MetadataId::CoverArt` metadata.
+        #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+        pub struct ImageHandle([u8; IMAGE_HANDLE_LEN]);
+
+        impl ImageHandle {
+            /// Parse the image handle from the text of a `MetadataId::CoverArt` metadata response.
+            ///
+            /// Returns `None` if the text is not a valid image handle, e.g. if it is empty because
+            /// the current track has no cover art.
+            pub fn from_metadata(text: &str) -> Option<Self> {
+                let bytes = text.as_bytes();
+
+                if bytes.len() == IMAGE_HANDLE_LEN && bytes.iter().all(u8::is_ascii_digit) {
+                    let mut handle = [0; IMAGE_HANDLE_LEN];
+                    handle.copy_from_slice(bytes);
+
+                    Some(Self(handle))
+                } else {
+                    None
+                }
+            }
+
+            pub fn as_bytes(&self) -> &[u8; IMAGE_HANDLE_LEN] {
+                &self.0
+            }
+        }
+
+        impl FromStr for ImageHandle {
+            type Err = EspError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::from_metadata(s).ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            }
+        }
+
+        impl<'d, M, T> EspAvrcc<'d, M, T>
+        where
+            M: BtClassicEnabled,
+            T: Borrow<BtDriver<'d, M>>,
+        {
+            /// Return the cover art features advertised by the connected target.
+            pub fn cover_art_features(&self) -> EnumSet<TargetFeature> {
+                *SUPPORTED.lock()
+            }
+
+            /// Return `true` if the connected target supports cover art, i.e. if it advertised
+            /// `TargetFeature::CoverArtGetImage`, which is the precondition of `connect_cover_art`.
+            ///
+            /// Cover art support is only advertised with the SDP feature flags: no company id
+            /// indicates it, as the `CompanyID` capability only lists the vendors whose
+            /// vendor-dependent commands the target implements (and ESP IDF does not report it anyway).
+            pub fn supports_cover_art(&self) -> bool {
+                SUPPORTED.lock().contains(TargetFeature::CoverArtGetImage)
+            }
+
+            /// Open the cover art channel with the connected target, using `mtu` as the maximum
+            /// OBEX packet size.
+            ///
+            /// The outcome is reported with the `AvrccEvent::CoverArtState` event.
+            /// Fails with `ESP_ERR_NOT_SUPPORTED` if the target did not advertise `TargetFeature::CoverArtGetImage`.
+            pub fn connect_cover_art(&self, mtu: u16) -> Result<(), EspError> {
+                check_supported(TargetFeature::CoverArtGetImage)?;
+
+                super::command(esp!(unsafe { esp_avrc_ct_cover_art_connect(mtu) }))
+            }
+
+            pub fn disconnect_cover_art(&self) -> Result<(), EspError> {
+                esp!(unsafe { esp_avrc_ct_cover_art_disconnect() })
+            }
+
+            /// Request the properties (the supported encodings and sizes) of an image.
+            ///
+            /// The properties XML document is delivered with `AvrccEvent::CoverArtData` events.
+            /// Fails with `ESP_ERR_NOT_SUPPORTED` if the target did not advertise `TargetFeature::GetImageProperties`.
+            pub fn get_image_properties(&self, handle: &ImageHandle) -> Result<(), EspError> {
+                check_supported(TargetFeature::GetImageProperties)?;
+
+                let mut handle = handle.0;
+
+                super::command(esp!(unsafe {
+                    esp_avrc_ct_cover_art_get_image_properties(handle.as_mut_ptr())
+                }))
+            }
+
+            /// Request an image.
+            ///
+            /// `descriptor` is the optional BIP image descriptor XML document selecting the encoding
+            /// and the size of the image. Without it, the target sends its native image.
+            ///
+            /// The image is delivered with `AvrccEvent::CoverArtData` events.
+            /// Fails with `ESP_ERR_NOT_SUPPORTED` if the target did not advertise `TargetFeature::CoverArtGetImage`.
+            pub fn get_image(
+                &self,
+                handle: &ImageHandle,
+                descriptor: Option<&[u8]>,
+            ) -> Result<(), EspError> {
+                check_supported(TargetFeature::CoverArtGetImage)?;
+
+                let descriptor = descriptor.unwrap_or(&[]);
+                let descriptor_len: u16 = descriptor
+                    .len()
+                    .try_into()
+                    .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+                let mut handle = handle.0;
+
+                super::command(esp!(unsafe {
+                    esp_avrc_ct_cover_art_get_image(
+                        handle.as_mut_ptr(),
+                        if descriptor.is_empty() {
+                            core::ptr::null_mut()
+                        } else {
+                            descriptor.as_ptr() as *mut _
+                        },
+                        descriptor_len,
+                    )
+                }))
+            }
+
+            /// Request the JPEG thumbnail (200x200 pixels) of an image.
+            ///
+            /// The thumbnail is delivered with `AvrccEvent::CoverArtData` events.
+            /// Fails with `ESP_ERR_NOT_SUPPORTED` if the target did not advertise `TargetFeature::CoverArtGetLinkedThumbnail`.
+            pub fn get_linked_thumbnail(&self, handle: &ImageHandle) -> Result<(), EspError> {
+                check_supported(TargetFeature::CoverArtGetLinkedThumbnail)?;
+
+                let mut handle = handle.0;
+
+                super::command(esp!(unsafe {
+                    esp_avrc_ct_cover_art_get_linked_thumbnail(handle.as_mut_ptr())
+                }))
+            }
+        }
+
+        fn check_supported(feature: TargetFeature) -> Result<(), EspError> {
+            if SUPPORTED.lock().contains(feature) {
+                Ok(())
+            } else {
+                Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+            }
+        }
+
+        pub(super) fn update_supported(event: &AvrccEvent) {
+            match event {
+                AvrccEvent::RemoteFeatures {
+                    target_features, ..
+                } => {
+                    *SUPPORTED.lock() = *target_features & TargetFeature::cover_art();
+                }
+                AvrccEvent::Disconnected(_) => clear_supported(),
+                _ => (),
+            }
+        }
+
+        pub(super) fn clear_supported() {
+            *SUPPORTED.lock() = EnumSet::EMPTY;
+        }
+
+        static SUPPORTED: Mutex<EnumSet<TargetFeature>> = Mutex::new(EnumSet::EMPTY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initialize_failure_then_retry() {
+        let singleton = crate::bt::BtSingleton::new(());
+        let failure = EspError::from_infallible::<ESP_FAIL>();
+
+        assert_eq!(
+            controller::initialize(&singleton, || Ok(()), || Err(failure)),
+            Err(failure)
+        );
+
+        assert_eq!(
+            controller::initialize(&singleton, || Err(failure), || unreachable!()),
+            Err(failure)
+        );
+
+        assert_eq!(
+            controller::initialize(&singleton, || Ok(()), || Ok(())),
+            Ok(())
+        );
+
+        // The singleton is taken by the successful initialization only
+        assert!(singleton.take().is_err());
+    }
+
+    fn decode(notification_type: NotificationType, parameter: esp_avrc_rn_param_t) -> Notification {
+        Notification::from_raw(notification_type as _, &parameter).unwrap()
+    }
+
+    #[test]
+    fn percent_to_volume() {
+        assert_eq!(controller::percent_to_volume(0), 0);
+        assert_eq!(controller::percent_to_volume(50), 64);
+        assert_eq!(controller::percent_to_volume(100), controller::MAX_VOLUME);
+        assert_eq!(controller::percent_to_volume(200), controller::MAX_VOLUME);
+    }
+
+    #[test]
+    fn parse_playing_time() {
+        assert_eq!(super::parse_playing_time("215000"), Some(215000));
+        assert_eq!(super::parse_playing_time(" 215000\0"), Some(215000));
+        assert_eq!(super::parse_playing_time("215000.0"), Some(215000));
+        assert_eq!(super::parse_playing_time(""), None);
+        assert_eq!(super::parse_playing_time("-1"), None);
+    }
+
+    #[test]
+    fn playback_position_display() {
+        assert_eq!(PlaybackPosition(215_999).to_string(), "03:35");
+        assert_eq!(PlaybackPosition(6_000_000).as_mmss(), Some((100, 0)));
+        assert_eq!(PlaybackPosition::from(None).to_string(), "\u{2014}");
+    }
+
+    #[test]
+    fn media_action_play_pause() {
+        let key = |playback| KeyCode::from_media_action(MediaAction::PlayPause, playback);
+
+        assert_eq!(key(Some(PlaybackStatus::Playing)), KeyCode::Pause);
+        assert_eq!(key(Some(PlaybackStatus::Paused)), KeyCode::Play);
+        assert_eq!(key(None), KeyCode::Play);
+    }
+
+    #[test]
+    fn decode_volume() {
+        assert_eq!(
+            decode(NotificationType::Volume, esp_avrc_rn_param_t { volume: 42 }),
+            Notification::Volume(42)
+        );
+    }
+
+    #[test]
+    fn decode_playback() {
+        assert_eq!(
+            decode(
+                NotificationType::Playback,
+                esp_avrc_rn_param_t {
+                    playback: esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_PAUSED
+                }
+            ),
+            Notification::Playback(PlaybackStatus::Paused)
+        );
+    }
+
+    #[test]
+    fn decode_playback_position() {
+        assert_eq!(
+            decode(
+                NotificationType::PlaybackPosition,
+                esp_avrc_rn_param_t { play_pos: 12345 }
+            ),
+            Notification::PlaybackPosition(Some(12345))
+        );
+    }
+
+    #[test]
+    fn decode_playback_position_no_track() {
+        assert_eq!(
+            decode(
+                NotificationType::PlaybackPosition,
+                esp_avrc_rn_param_t { play_pos: u32::MAX }
+            ),
+            Notification::PlaybackPosition(None)
+        );
+    }
+
+    #[test]
+    fn decode_battery() {
+        assert_eq!(
+            decode(
+                NotificationType::BatteryStatus,
+                esp_avrc_rn_param_t {
+                    batt: esp_avrc_batt_stat_t_ESP_AVRC_BATT_WARNING
+                }
+            ),
+            Notification::Battery(BatteryStatus::Warning)
+        );
+    }
+
+    #[test]
+    fn decode_parameterless() {
+        let parameter = esp_avrc_rn_param_t { play_pos: 0 };
+
+        for (notification_type, notification) in [
+            (NotificationType::TrackEnd, Notification::TrackEnded),
+            (NotificationType::TrackStart, Notification::TrackStarted),
+            (NotificationType::SystemStatus, Notification::SystemStatus),
+            (NotificationType::AppSettings, Notification::AppSettings),
+            (NotificationType::NowPlaying, Notification::NowPlaying),
+            (
+                NotificationType::AvailablePlayers,
+                Notification::AvailablePlayers,
+            ),
+            (
+                NotificationType::AddressedPlayer,
+                Notification::AddressedPlayer,
+            ),
+            (NotificationType::Uuids, Notification::Uuids),
+            (
+                NotificationType::Unknown1,
+                Notification::Other(NotificationType::Unknown1),
+            ),
+        ] {
+            assert_eq!(decode(notification_type, parameter), notification);
+        }
+    }
+
+    #[test]
+    fn decode_track_changed() {
+        for (elm_id, uid) in [
+            ([0; 8], Some(0)),
+            ([0, 0, 0, 0, 0, 0, 0x12, 0x34], Some(0x1234)),
+            ([0xff; 8], None),
+        ] {
+            assert_eq!(
+                decode(
+                    NotificationType::TrackChanged,
+                    esp_avrc_rn_param_t { elm_id }
+                ),
+                Notification::TrackChanged(uid)
+            );
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let parameter = esp_avrc_rn_param_t { batt: 0xff };
+
+        assert_eq!(Notification::from_raw(0, &parameter), None);
+        assert_eq!(
+            Notification::from_raw(NotificationType::BatteryStatus as _, &parameter),
+            None
+        );
+    }
 }
@@ -1,23 +1,36 @@
 use core::borrow::BorrowMut;
 
 use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
 use config::{FatFsType, FormatConfiguration};
 
-use ::log::warn;
+use embedded_svc::io;
+
+use enumset::{EnumSet, EnumSetType};
+
+use ::log::{debug, warn};
 
 use crate::hal::sd::SdCardDriver;
+use crate::private::cstr::{from_cstr_ptr, to_cstring_arg};
+use crate::private::mutex::{Condvar, Mutex};
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+use crate::private::sha256::Sha256;
 use crate::sys::*;
 
 extern crate alloc;
 
 pub mod config {
     /// Type of FAT filesystem to create when formatting the partition.
-    #[derive(Copy, Clone, Eq, PartialEq)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub enum FatFsType {
         /// Automatically choose the best FAT type depending on volume and cluster size.
         Auto,
-        /// FAT12 filesystem.
+        /// FAT12 or FAT16 filesystem, depending on the number of clusters.
         Fat,
         /// FAT32 filesystem.
         Fat32,
@@ -65,9 +78,13 @@ pub mod config {
     }
 }
 
+/// The SD card clock used in the safe mode, see `Fatfs::set_sdcard_safe_mode`.
+pub const SDCARD_SAFE_MODE_FREQ_KHZ: u32 = 5000;
+
 enum Partition<T> {
-    SdCard(T),
+    SdCard { driver: T, safe_mode: bool },
     RawPartition,
+    BlockDevice,
 }
 
 /// Represents a mounted FAT filesystem instance that can be used to interact with the filesystem.
@@ -78,15 +95,2175 @@ enum Partition<T> {
 pub struct MountedFatfs<'a, T> {
     fs: &'a mut Fatfs<T>,
     fatfs: Box<FATFS>,
+    config_locks: Mutex<Vec<String>>,
+    config_unlocked: Condvar,
+    low_space: Mutex<LowSpaceWatch>,
+    yield_interval: Option<usize>,
+    read_only: bool,
+    #[cfg(feature = "fatfs-handle-tracking")]
+    open_handles: Mutex<Vec<(usize, String)>>,
+}
+
+/// The default number of bytes after which the long-running operations of `MountedFatfs` yield,
+/// see `MountedFatfs::set_yield_interval`.
+pub const DEFAULT_YIELD_INTERVAL: usize = 256 * 1024;
+
+impl<T> MountedFatfs<'_, T> {
+    /// Get the underlying FATFS instance.
+    pub fn fatfs(&self) -> &FATFS {
+        &self.fatfs
+    }
+
+    /// Get the sector cache configuration of the mounted filesystem.
+    pub fn cache_config(&self) -> CacheConfig {
+        CacheConfig {
+            sector_size: sector_size(&self.fatfs) as _,
+            max_sector_size: FF_MAX_SS as _,
+            per_file_cache: cfg!(esp_idf_fatfs_per_file_cache),
+            dynamic_buffers: cfg!(esp_idf_fatfs_use_dyn_buffers),
+        }
+    }
+
+    /// Returns `true` if the filesystem is currently mounted read-only, see `remount_ro`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Unmount the filesystem and mount it again on the same drive in read-only mode,
+    /// e.g. when a product stops logging, without tearing down the `Fatfs` instance
+    /// or the underlying driver.
+    ///
+    /// FATFS has no read-only mount option, so the read-only mode is enforced by this API:
+    /// opening a file for writing, removing, renaming, creating directories and changing attributes
+    /// fail with `ESP_ERR_INVALID_STATE` until `remount_rw` is called.
+    ///
+    /// As the remount requires `&mut self`, no file or directory handle can be open
+    /// during the mode switch. The volume is re-mounted lazily, i.e. errors of the storage
+    /// show up on the next access, like with `Fatfs::mount`.
+    pub fn remount_ro(&mut self) -> Result<(), EspError> {
+        self.remount(true)
+    }
+
+    /// Unmount the filesystem and mount it again on the same drive in read-write mode,
+    /// e.g. when a product which booted read-only starts logging.
+    ///
+    /// See `remount_ro` for details.
+    pub fn remount_rw(&mut self) -> Result<(), EspError> {
+        self.remount(false)
+    }
+
+    fn remount(&mut self, read_only: bool) -> Result<(), EspError> {
+        #[cfg(feature = "fatfs-handle-tracking")]
+        if !self.open_handles.lock().is_empty() {
+            // Only possible if `FatFile` instances were leaked with `core::mem::forget`
+            warn!("Remount failed: files are still open");
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+        }
+
+        let drive_path = self.fs.drive_path();
+
+        let res = unsafe { f_mount(core::ptr::null_mut(), drive_path.as_ptr(), 0) };
+
+        if res != FRESULT_FR_OK {
+            warn!("Unmount failed: {res}");
+            Err(EspError::from_infallible::<ESP_FAIL>())?
+        }
+
+        *self.fatfs = Default::default();
+
+        let res = unsafe { f_mount(&mut *self.fatfs, drive_path.as_ptr(), 0) };
+
+        if res != FRESULT_FR_OK {
+            warn!("Mount failed: {res}");
+            Err(EspError::from_infallible::<ESP_FAIL>())?
+        }
+
+        self.read_only = read_only;
+
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), EspError> {
+        if self.read_only {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+        }
+
+        Ok(())
+    }
+
+    /// Open a file on the filesystem.
+    ///
+    /// # Arguments
+    /// - Path of the file, relative to the root of the filesystem (i.e. `/logs/log.txt`).
+    /// - FATFS open mode flags (i.e. `FA_READ`, `FA_WRITE | FA_CREATE_ALWAYS` and so on).
+    ///
+    /// Opening a file for writing fails with `ESP_ERR_INVALID_STATE` if the filesystem
+    /// is mounted read-only (see `remount_ro`).
+    pub fn open(&self, path: &str, mode: u32) -> Result<FatFile<'_>, EspError> {
+        if mode & (FA_WRITE | FA_CREATE_NEW | FA_CREATE_ALWAYS | FA_OPEN_ALWAYS | FA_OPEN_APPEND)
+            != 0
+        {
+            self.check_writable()?;
+        }
+
+        let fs_path = self.fs.path(path)?;
+
+        let mut fil: Box<FIL> = Box::default();
+
+        check(unsafe { f_open(&mut *fil, fs_path.as_ptr(), mode as _) })?;
+
+        #[cfg(feature = "fatfs-handle-tracking")]
+        self.open_handles
+            .lock()
+            .push((&*fil as *const FIL as usize, path.into()));
+
+        Ok(FatFile {
+            fil,
+            low_space: &self.low_space,
+            #[cfg(feature = "fatfs-handle-tracking")]
+            open_handles: &self.open_handles,
+        })
+    }
+
+    /// Returns the paths of the files which are currently open, i.e. opened with `open`
+    /// and not dropped yet, e.g. to find the leaked file handles of an application which
+    /// runs out of them.
+    ///
+    /// This is best-effort instrumentation intended for development, and is only available
+    /// with the `fatfs-handle-tracking` feature: it has some overhead, as the path of each file
+    /// is recorded - i.e. allocated - on open and removed on close, under a lock.
+    /// Only the files opened with `open` are tracked, not the ones opened through the VFS
+    /// (e.g. with `std::fs`) or directly with the FATFS API.
+    #[cfg(feature = "fatfs-handle-tracking")]
+    pub fn open_handles(&self) -> Vec<String> {
+        self.open_handles
+            .lock()
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect()
+    }
+
+    /// Get the attributes of a file or a directory.
+    pub fn attributes(&self, path: &str) -> Result<EnumSet<FatAttribute>, EspError> {
+        let info = self.stat(path)?;
+
+        Ok(EnumSet::from_repr_truncated(info.fattrib))
+    }
+
+    /// List a page of at most `N` entries of a directory, skipping the first `offset` entries.
+    ///
+    /// Entries are returned in FATFS directory order, i.e. the order in which they are stored
+    /// on the volume, which is stable as long as the directory is not modified.
+    /// Together with the entries, `true` is returned if the directory has more entries after the page.
+    ///
+    /// Each page is listed independently, so if the directory is modified between two pages,
+    /// entries might be skipped or listed twice, but the listing itself never fails because of that.
+    pub fn read_dir_paged<const N: usize>(
+        &self,
+        path: &str,
+        offset: usize,
+    ) -> Result<(heapless::Vec<FatDirEntry, N>, bool), EspError> {
+        let path = self.fs.path(path)?;
+
+        let mut dir: DIR = Default::default();
+        check(unsafe { f_opendir(&mut dir, path.as_ptr()) })?;
+
+        let mut entries = heapless::Vec::new();
+        let mut index = 0;
+
+        let result = loop {
+            let mut info: FILINFO = Default::default();
+
+            let res = unsafe { f_readdir(&mut dir, &mut info) };
+            if res != FRESULT_FR_OK {
+                break check(res).map(|_| false);
+            }
+
+            if info.fname[0] == 0 {
+                break Ok(false);
+            }
+
+            if index >= offset {
+                let entry = FatDirEntry {
+                    name: unsafe { from_cstr_ptr(info.fname.as_ptr()) }.into(),
+                    size: info.fsize as _,
+                    attributes: EnumSet::from_repr_truncated(info.fattrib),
+                };
+
+                if entries.push(entry).is_err() {
+                    break Ok(true);
+                }
+            }
+
+            index += 1;
+        };
+
+        unsafe { f_closedir(&mut dir) };
+
+        Ok((entries, result?))
+    }
+
+    /// Set the attributes of a file or a directory.
+    ///
+    /// Only the `ReadOnly`, `Hidden`, `System` and `Archive` attributes can be changed;
+    /// `Directory` is ignored.
+    pub fn set_attributes(
+        &self,
+        path: &str,
+        attributes: EnumSet<FatAttribute>,
+    ) -> Result<(), EspError> {
+        let mask = FatAttribute::ReadOnly
+            | FatAttribute::Hidden
+            | FatAttribute::System
+            | FatAttribute::Archive;
+
+        self.check_writable()?;
+
+        // Fail early with a clear error if the path does not exist
+        self.stat(path)?;
+
+        let path = self.fs.path(path)?;
+
+        check(unsafe { f_chmod(path.as_ptr(), (attributes & mask).as_repr(), mask.as_repr()) })
+    }
+
+    /// Configure how often the long-running operations which read the whole volume or a whole file
+    /// (like `scrub` or `hash_file`) yield to other tasks for one tick, so that they do not starve them or trip the task watchdog,
+    /// which is especially likely on single-core chips. `None` disables yielding, e.g. for operations
+    /// which must complete as fast as possible.
+    ///
+    /// By default, the operations yield every `DEFAULT_YIELD_INTERVAL` bytes read
+    /// (i.e. every few milliseconds with SD cards).
+    pub fn set_yield_interval(&mut self, bytes: Option<usize>) {
+        self.yield_interval = bytes;
+    }
+
+    /// Yield for one tick if `bytes` more bytes, added to the bytes already `read` since
+    /// the last yield, reach the yield interval.
+    fn cooperative_yield(&self, read: &mut usize, bytes: usize) {
+        if let Some(interval) = self.yield_interval.filter(|interval| *interval > 0) {
+            *read += bytes;
+
+            if *read >= interval {
+                *read = 0;
+
+                unsafe { vTaskDelay(1) };
+            }
+        }
+    }
+
+    /// Compute the CRC32 or the SHA-256 of the file at `path`, e.g. to check that a file
+    /// was uploaded intact by comparing the digest with the one computed by the server.
+    ///
+    /// The file is read in chunks, yielding as configured with `set_yield_interval`,
+    /// so that the task watchdog is not triggered with large files.
+    pub fn hash_file(&self, path: &str, algorithm: HashAlgorithm) -> Result<FileDigest, EspError> {
+        let mut file = self.open(path, FA_READ)?;
+        let mut buf = vec![0_u8; HASH_CHUNK_LEN];
+        let mut read = 0;
+
+        match algorithm {
+            HashAlgorithm::Crc32 => {
+                let mut crc = 0;
+
+                loop {
+                    let len = file.read(&mut buf)?;
+
+                    if len == 0 {
+                        break;
+                    }
+
+                    crc = unsafe { esp_rom_crc32_le(crc, buf.as_ptr(), len as _) };
+
+                    self.cooperative_yield(&mut read, len);
+                }
+
+                Ok(FileDigest::Crc32(crc))
+            }
+            #[cfg(esp_idf_comp_mbedtls_enabled)]
+            HashAlgorithm::Sha256 => {
+                let mut sha = Sha256::new()?;
+
+                loop {
+                    let len = file.read(&mut buf)?;
+
+                    if len == 0 {
+                        break;
+                    }
+
+                    sha.update(&buf[..len])?;
+
+                    self.cooperative_yield(&mut read, len);
+                }
+
+                Ok(FileDigest::Sha256(sha.finish()?))
+            }
+        }
+    }
+
+    /// Read all allocated clusters of the filesystem, so as to detect sectors which can no longer be read.
+    ///
+    /// Returns the numbers of the sectors which failed to read.
+    ///
+    /// The scan talks to the disk directly and does not lock the filesystem, so other operations
+    /// can happen while the scan is in progress. Clusters allocated after they were visited
+    /// are not scanned.
+    ///
+    /// Scrubbing exFAT volumes is not supported.
+    ///
+    /// # Arguments
+    /// - Progress callback, called with the number of clusters scanned so far and the total number
+    ///   of clusters. If the callback returns `false`, the scan is interrupted.
+    pub fn scrub<F>(&self, mut progress: F) -> Result<Vec<u64>, EspError>
+    where
+        F: FnMut(u64, u64) -> bool,
+    {
+        self.ensure_mounted()?;
+
+        let fs = &*self.fatfs;
+
+        let mut fat = FatTable::new(fs)?;
+        let mut buf = vec![0_u8; fat.sector_size];
+
+        let clusters = fs.n_fatent as u64 - 2;
+        let mut bad_sectors = Vec::new();
+        let mut read = 0;
+
+        for cluster in 2..fs.n_fatent {
+            if !progress(cluster as u64 - 2, clusters) {
+                return Ok(bad_sectors);
+            }
+
+            // Account for the FAT entry of the cluster
+            self.cooperative_yield(&mut read, 4);
+
+            if fat.entry(cluster)? == 0 {
+                // Free cluster
+                continue;
+            }
+
+            self.cooperative_yield(&mut read, fs.csize as usize * fat.sector_size);
+
+            let first_sector = fs.database as u64 + (cluster as u64 - 2) * fs.csize as u64;
+
+            for sector in first_sector..first_sector + fs.csize as u64 {
+                if read_sector(fs.pdrv, &mut buf, sector).is_err() {
+                    bad_sectors.push(sector);
+                }
+            }
+        }
+
+        progress(clusters, clusters);
+
+        Ok(bad_sectors)
+    }
+
+    /// Diagnose the fragmentation of the file at `path`, by walking its cluster chain
+    /// in the FAT, e.g. to understand slow writes.
+    ///
+    /// This is a diagnostic which reads the FAT directly, bypassing FATFS. It is not supported
+    /// on exFAT volumes.
+    pub fn fragmentation(&self, path: &str) -> Result<FragInfo, EspError> {
+        let file = self.open(path, FA_READ)?;
+
+        let fs = &*self.fatfs;
+
+        let mut fat = FatTable::new(fs)?;
+
+        let mut info = FragInfo {
+            clusters: 0,
+            fragments: 0,
+            run_histogram: [0; 16],
+        };
+
+        let record_run = |info: &mut FragInfo, run: u32| {
+            info.fragments += 1;
+            info.run_histogram[(run.ilog2() as usize).min(15)] += 1;
+        };
+
+        let mut cluster = file.fil.obj.sclust;
+        let mut run = 0;
+
+        // Bound the walk by the number of clusters, so that a corrupt - looping - chain terminates
+        while cluster >= 2 && cluster < fs.n_fatent && info.clusters < fs.n_fatent {
+            info.clusters += 1;
+            run += 1;
+
+            let next = fat.entry(cluster)?;
+
+            if next != cluster + 1 {
+                record_run(&mut info, run);
+                run = 0;
+            }
+
+            cluster = next;
+        }
+
+        if run > 0 {
+            record_run(&mut info, run);
+        }
+
+        Ok(info)
+    }
+
+    /// Get a snapshot of the usage of the filesystem.
+    pub fn usage(&self) -> Result<DiskUsage, EspError> {
+        disk_usage(self.fs.drive)
+    }
+
+    /// Check that `bytes` can be written to the filesystem before starting a large write
+    /// (e.g. staging a firmware image), so that it fails upfront rather than with a full volume halfway.
+    ///
+    /// The space is counted in whole clusters. With `contiguous`, a single run of free clusters
+    /// large enough is also required, so that the file can be written without fragmentation;
+    /// this reads the FAT directly, like `fragmentation`, and is not supported on exFAT volumes.
+    ///
+    /// Nothing is reserved: other writes can still take the space after the check.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NO_MEM` if there is not enough (contiguous) free space.
+    pub fn ensure_space(&self, bytes: u64, contiguous: bool) -> Result<(), EspError> {
+        let usage = self.usage()?;
+
+        let clusters = bytes.div_ceil(usage.cluster_size as u64);
+
+        if clusters * usage.cluster_size as u64 > usage.free_bytes {
+            Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+        }
+
+        if contiguous && clusters > 0 {
+            let fs = &*self.fatfs;
+
+            let mut fat = FatTable::new(fs)?;
+
+            let mut run = 0;
+
+            for cluster in 2..fs.n_fatent {
+                if fat.entry(cluster)? == 0 {
+                    run += 1;
+
+                    if run >= clusters {
+                        return Ok(());
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+
+            Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+        }
+
+        Ok(())
+    }
+
+    /// Scan the FAT tables and the directory tree for inconsistencies, without modifying anything.
+    fn check_consistency(&self) -> Result<FsCheckResult, EspError> {
+        let path = self.fs.path("/")?;
+
+        let mut dir: DIR = Default::default();
+
+        // FATFS mounts lazily, so this is where a damaged boot sector is detected
+        let res = unsafe { f_opendir(&mut dir, path.as_ptr()) };
+        if res == FRESULT_FR_NO_FILESYSTEM {
+            return Ok(FsCheckResult::UNRECOGNIZED);
+        }
+
+        check(res)?;
+        check(unsafe { f_closedir(&mut dir) })?;
+
+        let fs = &*self.fatfs;
+
+        let mut fat = FatTable::new(fs)?;
+
+        let mut result = FsCheckResult {
+            unrecognized: false,
+            lost_clusters: 0,
+            cross_linked_clusters: 0,
+            fat_mismatched_sectors: 0,
+        };
+
+        // Compare the copies of the FAT with each other
+        if fs.n_fats > 1 {
+            let mut first = vec![0_u8; fat.sector_size];
+            let mut other = vec![0_u8; fat.sector_size];
+
+            for sector in 0..fs.fsize as u64 {
+                read_sector(fs.pdrv, &mut first, fs.fatbase as u64 + sector)?;
+
+                for copy in 1..fs.n_fats as u64 {
+                    read_sector(
+                        fs.pdrv,
+                        &mut other,
+                        fs.fatbase as u64 + copy * fs.fsize as u64 + sector,
+                    )?;
+
+                    if first != other {
+                        result.fat_mismatched_sectors += 1;
+                    }
+                }
+            }
+        }
+
+        // Mark all clusters referenced by the directory tree
+        let mut referenced = vec![0_u8; (fs.n_fatent as usize).div_ceil(8)];
+
+        let mut mark_chain = |fat: &mut FatTable, mut cluster: u32| -> Result<(), EspError> {
+            while cluster >= 2 && cluster < fs.n_fatent {
+                let (byte, bit) = (cluster as usize / 8, 1 << (cluster % 8));
+
+                if referenced[byte] & bit != 0 {
+                    result.cross_linked_clusters += 1;
+                    break;
+                }
+
+                referenced[byte] |= bit;
+                cluster = fat.entry(cluster)?;
+            }
+
+            Ok(())
+        };
+
+        if fs.fs_type as u32 == FS_FAT32 {
+            mark_chain(&mut fat, fs.dirbase as _)?;
+        }
+
+        let mut dirs = vec![Fatfs::<()>::path_from(fs.pdrv, "")?];
+
+        while let Some(dir_path) = dirs.pop() {
+            let mut dir: DIR = Default::default();
+            check(unsafe { f_opendir(&mut dir, dir_path.as_ptr()) })?;
+
+            loop {
+                let mut info: FILINFO = Default::default();
+
+                let res = unsafe { f_readdir(&mut dir, &mut info) };
+                if res != FRESULT_FR_OK || info.fname[0] == 0 {
+                    unsafe { f_closedir(&mut dir) };
+                    check(res)?;
+                    break;
+                }
+
+                let name = unsafe { from_cstr_ptr(info.fname.as_ptr()) };
+                let path = to_cstring_arg(&format!("{}/{}", dir_path.to_str().unwrap(), name))?;
+
+                if info.fattrib as u32 & AM_DIR != 0 {
+                    let mut sub_dir: DIR = Default::default();
+                    check(unsafe { f_opendir(&mut sub_dir, path.as_ptr()) })?;
+                    let cluster = sub_dir.obj.sclust;
+                    check(unsafe { f_closedir(&mut sub_dir) })?;
+
+                    mark_chain(&mut fat, cluster)?;
+
+                    dirs.push(path);
+                } else {
+                    let mut fil: Box<FIL> = Box::default();
+                    check(unsafe { f_open(&mut *fil, path.as_ptr(), FA_READ as _) })?;
+                    let cluster = fil.obj.sclust;
+                    check(unsafe { f_close(&mut *fil) })?;
+
+                    mark_chain(&mut fat, cluster)?;
+                }
+            }
+        }
+
+        // Any allocated cluster which is not referenced is lost
+        let bad_cluster = match fs.fs_type as u32 {
+            FS_FAT12 => 0xff7,
+            FS_FAT16 => 0xfff7,
+            _ => 0x0fff_fff7,
+        };
+
+        for cluster in 2..fs.n_fatent {
+            let entry = fat.entry(cluster)?;
+
+            if entry != 0
+                && entry != bad_cluster
+                && referenced[cluster as usize / 8] & (1 << (cluster % 8)) == 0
+            {
+                result.lost_clusters += 1;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Set the free space, in bytes, below which the callback registered with `on_low_space`
+    /// is called, or `None` to disable the check.
+    ///
+    /// The free space is checked after each write to a file opened with `open`. The callback is
+    /// called once when the free space drops below the threshold, and is only called again after
+    /// the free space recovered to at least 10% above the threshold, so that it does not fire
+    /// repeatedly when the free space hovers around the threshold.
+    ///
+    /// The free space is checked immediately, which requires scanning the FAT once
+    /// if it was not done yet since the volume was mounted.
+    pub fn set_low_space_threshold(&self, bytes: Option<u64>) -> Result<(), EspError> {
+        {
+            let mut watch = self.low_space.lock();
+
+            watch.threshold = bytes;
+            watch.low = false;
+        }
+
+        if bytes.is_some() {
+            // Make FATFS count the free clusters, which it then keeps up to date
+            let path = self.fs.path("")?;
+
+            let mut free_clusters = 0;
+            let mut fs: *mut FATFS = core::ptr::null_mut();
+
+            check(unsafe { f_getfree(path.as_ptr(), &mut free_clusters, &mut fs) })?;
+
+            check_low_space(&self.low_space, &self.fatfs);
+        }
+
+        Ok(())
+    }
+
+    /// Register the callback called with the free space, in bytes, when it drops below the threshold
+    /// set with `set_low_space_threshold`, e.g. to rotate or stop logs.
+    ///
+    /// The callback is called from the task writing to the file, and must not call
+    /// `on_low_space` or `set_low_space_threshold`.
+    pub fn on_low_space<F>(&self, callback: F)
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.low_space.lock().callback = Some(Box::new(callback));
+    }
+
+    /// Make sure the volume is actually mounted, as `f_mount` mounts lazily.
+    fn ensure_mounted(&self) -> Result<(), EspError> {
+        let path = self.fs.path("/")?;
+
+        let mut dir: DIR = Default::default();
+
+        check(unsafe { f_opendir(&mut dir, path.as_ptr()) })?;
+        check(unsafe { f_closedir(&mut dir) })
+    }
+
+    fn stat(&self, path: &str) -> Result<FILINFO, EspError> {
+        let path = self.fs.path(path)?;
+
+        let mut info: FILINFO = Default::default();
+
+        check(unsafe { f_stat(path.as_ptr(), &mut info) })?;
+
+        Ok(info)
+    }
+
+    /// Remove a file or an empty directory from the filesystem.
+    pub fn remove(&self, path: &str) -> Result<(), EspError> {
+        self.check_writable()?;
+
+        let path = self.fs.path(path)?;
+
+        check(unsafe { f_unlink(path.as_ptr()) })
+    }
+
+    /// Delete the files of the directory `dir` whose modification time is older than `cutoff`,
+    /// e.g. to enforce a log retention policy. Subdirectories are not visited.
+    ///
+    /// With `dry_run`, nothing is deleted, and the report lists the files which would be deleted.
+    ///
+    /// The modification times are set by FATFS from `get_fattime`, i.e. from the system time,
+    /// so the system time should be set (e.g. with SNTP) when the files are written.
+    pub fn purge_older_than(
+        &self,
+        dir: &str,
+        cutoff: FatTime,
+        dry_run: bool,
+    ) -> Result<PurgeReport, EspError> {
+        let dir = dir.trim_end_matches('/');
+        let dir_path = self.fs.path(dir)?;
+
+        let mut report = PurgeReport {
+            files: Vec::new(),
+            bytes: 0,
+        };
+
+        let mut fdir: DIR = Default::default();
+        check(unsafe { f_opendir(&mut fdir, dir_path.as_ptr()) })?;
+
+        let result = loop {
+            let mut info: FILINFO = Default::default();
+
+            let res = unsafe { f_readdir(&mut fdir, &mut info) };
+            if res != FRESULT_FR_OK || info.fname[0] == 0 {
+                break check(res);
+            }
+
+            if info.fattrib as u32 & AM_DIR != 0 {
+                continue;
+            }
+
+            if FatTime::from_raw(info.fdate, info.ftime) < cutoff {
+                report
+                    .files
+                    .push(unsafe { from_cstr_ptr(info.fname.as_ptr()) }.into());
+                report.bytes += info.fsize as u64;
+            }
+        };
+
+        unsafe { f_closedir(&mut fdir) };
+
+        result?;
+
+        if !dry_run {
+            // Delete the files once the directory is closed, rather than while listing it
+            for name in &report.files {
+                self.remove(&format!("{dir}/{name}"))?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Truncate or extend a file to `size` bytes, and return the resulting size.
+    ///
+    /// When extending, the new part of the file is zero-filled.
+    pub fn truncate(&self, path: &str, size: u64) -> Result<u64, EspError> {
+        let mut file = self.open(path, FA_WRITE | FA_OPEN_EXISTING)?;
+
+        let current = file.fil.obj.objsize as u64;
+
+        if size < current {
+            file.seek(size)?;
+            check(unsafe { f_truncate(&mut *file.fil) })?;
+        } else if size > current {
+            file.seek(current)?;
+
+            let zeros = [0_u8; 512];
+            let mut remaining = size - current;
+
+            while remaining > 0 {
+                let len = remaining.min(zeros.len() as u64) as usize;
+
+                file.write_all(&zeros[..len])?;
+                remaining -= len as u64;
+            }
+        }
+
+        Ok(file.fil.obj.objsize as _)
+    }
+
+    /// Create a directory and all its missing parent directories.
+    ///
+    /// Directories which already exist are skipped. Both `/logs/2024` and `logs/2024/`
+    /// are accepted, and the root directory is never created.
+    pub fn create_dir_all(&self, path: &str) -> Result<(), EspError> {
+        self.check_writable()?;
+
+        let mut end = 0;
+
+        for component in path.split('/') {
+            end += component.len() + 1;
+
+            if component.is_empty() {
+                continue;
+            }
+
+            let dir_path = self.fs.path(&path[..end - 1])?;
+
+            let res = unsafe { f_mkdir(dir_path.as_ptr()) };
+
+            // `FR_EXIST` might also mean that a file with that name exists,
+            // in which case the next component fails with `ESP_ERR_NOT_FOUND`
+            if res != FRESULT_FR_EXIST {
+                check(res)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rename or move a file or a directory, possibly to another directory of the filesystem.
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` if `to` already exists, as FATFS does not replace
+    /// existing files when renaming (see `rename_replace`), and with `ESP_ERR_INVALID_ARG`
+    /// if a path has a drive prefix (e.g. `1:/file`), as FAT cannot move files across volumes.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), EspError> {
+        if from.contains(':') || to.contains(':') {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        self.check_writable()?;
+
+        let from = self.fs.path(from)?;
+        let to = self.fs.path(to)?;
+
+        check(unsafe { f_rename(from.as_ptr(), to.as_ptr()) })
+    }
+
+    /// Same as `rename`, but replaces the file `to` if it exists.
+    ///
+    /// As FATFS cannot rename over an existing file, `to` is removed first, so a power cut
+    /// between the two steps leaves only `from`. Directories are not replaced: if `to` is
+    /// a non-empty directory, `ESP_ERR_INVALID_STATE` is returned.
+    pub fn rename_replace(&self, from: &str, to: &str) -> Result<(), EspError> {
+        if from.contains(':') || to.contains(':') {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        if self.exists(to)? {
+            self.remove(to)?;
+        }
+
+        self.rename(from, to)
+    }
+
+    /// Read the bytes of the file at `path` starting at `offset` into `buf`, e.g. to serve
+    /// an HTTP range request. Returns the number of bytes read, which is less than `buf.len()`
+    /// only if the end of the file is reached, and 0 if `offset` is beyond the end of the file.
+    pub fn read_range(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, EspError> {
+        let mut file = self.open(path, FA_READ)?;
+
+        if offset >= file.fil.obj.objsize as u64 {
+            return Ok(0);
+        }
+
+        file.seek(offset)?;
+
+        let mut read = 0;
+
+        while read < buf.len() {
+            let len = file.read(&mut buf[read..])?;
+
+            if len == 0 {
+                break;
+            }
+
+            read += len;
+        }
+
+        Ok(read)
+    }
+
+    /// Stream the content of the file at `path` to `sink`, e.g. to serve it over HTTP, reading it
+    /// in chunks of up to `buf.len()` bytes. Returns the number of bytes transferred.
+    ///
+    /// Short writes of the sink are retried until each chunk is written completely.
+    /// A sink which accepts no data (i.e. whose `write` returns 0) fails with `FatStreamError::WriteZero`.
+    ///
+    /// Returns `FatStreamError::Read` with `ESP_ERR_INVALID_ARG` if `buf` is empty.
+    pub fn stream_to<W>(
+        &self,
+        path: &str,
+        sink: &mut W,
+        buf: &mut [u8],
+    ) -> Result<u64, FatStreamError<W::Error>>
+    where
+        W: io::Write,
+    {
+        if buf.is_empty() {
+            Err(FatStreamError::Read(EspError::from_infallible::<
+                ESP_ERR_INVALID_ARG,
+            >()))?;
+        }
+
+        let mut file = self.open(path, FA_READ).map_err(FatStreamError::Read)?;
+
+        let mut transferred = 0;
+
+        loop {
+            let read = file.read(buf).map_err(FatStreamError::Read)?;
+
+            if read == 0 {
+                break;
+            }
+
+            let mut data = &buf[..read];
+
+            while !data.is_empty() {
+                let written = sink.write(data).map_err(FatStreamError::Write)?;
+
+                if written == 0 {
+                    Err(FatStreamError::WriteZero)?;
+                }
+
+                data = &data[written..];
+            }
+
+            transferred += read as u64;
+        }
+
+        sink.flush().map_err(FatStreamError::Write)?;
+
+        Ok(transferred)
+    }
+
+    /// Replace the content of a file in a crash-safe way, by writing the data to a temporary file
+    /// (`<path>.tmp`), syncing it and then renaming it over the file.
+    ///
+    /// A power cut while the temporary file is written leaves the original file intact.
+    /// As FATFS cannot rename over an existing file, the original file is removed right before
+    /// the rename; a power cut exactly between these two steps leaves only the complete temporary file,
+    /// which `recover_atomic` renames back. Note that neither step is atomic at the sector level,
+    /// but this is still much safer than writing the file in place.
+    pub fn write_atomic(&self, path: &str, data: &[u8]) -> Result<(), EspError> {
+        let tmp_path = format!("{path}.tmp");
+
+        {
+            let mut file = self.open(&tmp_path, FA_WRITE | FA_CREATE_ALWAYS)?;
+
+            file.write_all(data)?;
+            file.sync()?;
+        }
+
+        self.rename_replace(&tmp_path, path)
+    }
+
+    /// Complete an interrupted `write_atomic`: if the file does not exist, but its temporary file does,
+    /// the temporary file is renamed to the file. Any other leftover temporary file is incomplete
+    /// and removed. Note that if the file never existed, an incomplete temporary file
+    /// of its very first `write_atomic` cannot be told apart from a complete one.
+    ///
+    /// Returns `true` if the file was recovered from its temporary file.
+    pub fn recover_atomic(&self, path: &str) -> Result<bool, EspError> {
+        let tmp_path = format!("{path}.tmp");
+
+        if !self.exists(&tmp_path)? {
+            return Ok(false);
+        }
+
+        if self.exists(path)? {
+            self.remove(&tmp_path)?;
+
+            Ok(false)
+        } else {
+            self.rename(&tmp_path, path)?;
+
+            Ok(true)
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, EspError> {
+        match self.stat(path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.code() == ESP_ERR_NOT_FOUND => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Measure the sequential write and read throughput of the underlying storage.
+    ///
+    /// The measurement is done by writing and then reading back `block_count` blocks of
+    /// `buf.len()` bytes to a scratch file in the root of the filesystem.
+    /// The scratch file is removed afterwards, so the filesystem is left intact.
+    ///
+    /// # Arguments
+    /// - Number of blocks to write and then read.
+    /// - Buffer to use for the transfers. Its size is the block size.
+    pub fn benchmark(&self, block_count: usize, buf: &mut [u8]) -> Result<BenchResult, EspError> {
+        const SCRATCH_FILE: &str = "/.bench.tmp";
+
+        if block_count == 0 || buf.is_empty() {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        let result = (|| {
+            let mut file = self.open(SCRATCH_FILE, FA_READ | FA_WRITE | FA_CREATE_ALWAYS)?;
+
+            let start = unsafe { esp_timer_get_time() };
+
+            for _ in 0..block_count {
+                file.write_all(buf)?;
+            }
+
+            file.sync()?;
+
+            let written = unsafe { esp_timer_get_time() };
+
+            file.seek(0)?;
+
+            for _ in 0..block_count {
+                file.read_exact(buf)?;
+            }
+
+            let read = unsafe { esp_timer_get_time() };
+
+            let bytes = (block_count * buf.len()) as u64;
+
+            Ok(BenchResult {
+                bytes,
+                write_mb_per_sec: BenchResult::mb_per_sec(bytes, written - start),
+                read_mb_per_sec: BenchResult::mb_per_sec(bytes, read - written),
+            })
+        })();
+
+        let removed = self.remove(SCRATCH_FILE);
+
+        let result = result?;
+        removed?;
+
+        Ok(result)
+    }
+}
+
+/// The sector cache configuration of a mounted FAT filesystem.
+///
+/// FATFS caches exactly one sector per volume (the window buffer), plus - with
+/// `CONFIG_FATFS_PER_FILE_CACHE` - one sector per open file. The cache sizes are fixed by the
+/// ESP IDF build configuration, and cannot be tuned at mount time:
+/// - `CONFIG_FATFS_SECTOR_*` selects the largest supported sector size (`FF_MAX_SS`),
+///   which is the size of each cache buffer unless `CONFIG_FATFS_USE_DYN_BUFFERS` is enabled,
+///   in which case the buffers are allocated with the actual sector size of the volume;
+/// - `CONFIG_FATFS_PER_FILE_CACHE` adds the per-file cache; without it, all files share the window buffer.
+///
+/// To trade more RAM for throughput, use a `BufferedFile`, which batches writes into multi-sector transfers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CacheConfig {
+    /// The sector size of the volume, in bytes.
+    pub sector_size: usize,
+    /// The largest sector size supported by the build configuration, in bytes.
+    pub max_sector_size: usize,
+    /// Whether each open file has its own sector cache.
+    pub per_file_cache: bool,
+    /// Whether the cache buffers are allocated with the sector size of the volume,
+    /// rather than with `max_sector_size`.
+    pub dynamic_buffers: bool,
+}
+
+impl CacheConfig {
+    /// The size of a single sector cache buffer, in bytes.
+    pub fn buffer_size(&self) -> usize {
+        if self.dynamic_buffers {
+            self.sector_size
+        } else {
+            self.max_sector_size
+        }
+    }
+}
+
+/// The fragmentation of a file, as returned by `MountedFatfs::fragmentation`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FragInfo {
+    /// The number of clusters allocated to the file.
+    pub clusters: u32,
+    /// The number of fragments, i.e. of runs of contiguous clusters. 1 for a contiguous file.
+    pub fragments: u32,
+    /// The number of runs by length: entry `i` counts the runs of `2^i` to `2^(i + 1) - 1`
+    /// clusters; the last entry counts all the longer runs too.
+    pub run_histogram: [u32; 16],
+}
+
+impl FragInfo {
+    /// Returns `true` if the file is stored in a single run of clusters.
+    pub fn is_contiguous(&self) -> bool {
+        self.fragments <= 1
+    }
+}
+
+/// A snapshot of the usage of a mounted FAT filesystem.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiskUsage {
+    /// Total size of the data area of the volume, in bytes.
+    pub total_bytes: u64,
+    /// Free space, in bytes.
+    pub free_bytes: u64,
+    /// Used space, in bytes.
+    pub used_bytes: u64,
+    /// Size of a cluster - the allocation unit - in bytes.
+    pub cluster_size: u32,
+    /// Volume label, as recorded in the boot sector.
+    pub label: heapless::String<11>,
+    /// Type of the FAT filesystem. Never `FatFsType::Auto`.
+    pub fat_type: FatFsType,
+}
+
+/// Get a snapshot of the usage of the mounted FAT filesystem with the given drive number.
+pub(crate) fn disk_usage(drive: u8) -> Result<DiskUsage, EspError> {
+    let path = Fatfs::<()>::path_from(drive, "")?;
+
+    let mut free_clusters = 0;
+    let mut fs: *mut FATFS = core::ptr::null_mut();
+
+    check(unsafe { f_getfree(path.as_ptr(), &mut free_clusters, &mut fs) })?;
+
+    let fs = unsafe { fs.as_ref() }.ok_or(EspError::from_infallible::<ESP_FAIL>())?;
+
+    let cluster_size = fs.csize as u32 * sector_size(fs) as u32;
+    let total_bytes = (fs.n_fatent as u64 - 2) * cluster_size as u64;
+    let free_bytes = free_clusters as u64 * cluster_size as u64;
+
+    let fat_type = match fs.fs_type as u32 {
+        FS_FAT12 | FS_FAT16 => FatFsType::Fat,
+        FS_FAT32 => FatFsType::Fat32,
+        _ => FatFsType::ExFat,
+    };
+
+    // The label lives in the extended BIOS parameter block of the boot sector,
+    // at an offset which depends on the FAT type
+    let mut boot_sector = vec![0_u8; sector_size(fs)];
+    read_sector(fs.pdrv, &mut boot_sector, fs.volbase as u64)?;
+
+    let label_offset = match fat_type {
+        FatFsType::Fat => Some(43),
+        FatFsType::Fat32 => Some(71),
+        _ => None,
+    };
+
+    let mut label = heapless::String::new();
+
+    if let Some(offset) = label_offset {
+        let raw_label = &boot_sector[offset..offset + 11];
+
+        if let Ok(raw_label) = core::str::from_utf8(raw_label) {
+            label.push_str(raw_label.trim_end()).unwrap();
+        }
+    }
+
+    Ok(DiskUsage {
+        total_bytes,
+        free_bytes,
+        used_bytes: total_bytes - free_bytes,
+        cluster_size,
+        label,
+        fat_type,
+    })
+}
+
+/// The result of a consistency check done with `Fatfs::check`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FsCheckResult {
+    /// The volume does not contain a recognizable FAT filesystem, e.g. because its boot sector
+    /// is damaged. The other fields are then 0, as nothing could be scanned.
+    pub unrecognized: bool,
+    /// Number of clusters marked as allocated in the FAT, but not belonging to any file or directory.
+    pub lost_clusters: u32,
+    /// Number of clusters belonging to more than one file or directory.
+    pub cross_linked_clusters: u32,
+    /// Number of sectors which differ between the copies of the FAT.
+    pub fat_mismatched_sectors: u32,
+}
+
+impl FsCheckResult {
+    const UNRECOGNIZED: Self = Self {
+        unrecognized: true,
+        lost_clusters: 0,
+        cross_linked_clusters: 0,
+        fat_mismatched_sectors: 0,
+    };
+
+    /// Returns `true` if no inconsistency was found.
+    pub fn is_clean(&self) -> bool {
+        !self.unrecognized
+            && self.lost_clusters == 0
+            && self.cross_linked_clusters == 0
+            && self.fat_mismatched_sectors == 0
+    }
+}
+
+/// A FAT timestamp, i.e. a local date and time with a resolution of 2 seconds,
+/// in the years 1980 to 2107.
+///
+/// Timestamps are ordered chronologically.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FatTime {
+    date: u16,
+    time: u16,
+}
+
+impl FatTime {
+    /// Create a timestamp from its components, or return `None` if they are out of range.
+    ///
+    /// The seconds are rounded down to an even number.
+    pub const fn new(
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Option<Self> {
+        if year < 1980
+            || year > 2107
+            || month < 1
+            || month > 12
+            || day < 1
+            || day > 31
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+
+        Some(Self {
+            date: ((year - 1980) << 9) | ((month as u16) << 5) | day as u16,
+            time: ((hour as u16) << 11) | ((minute as u16) << 5) | (second as u16 / 2),
+        })
+    }
+
+    /// Create a timestamp from the raw FAT date and time, as found in `FILINFO::fdate` and `FILINFO::ftime`.
+    pub const fn from_raw(date: u16, time: u16) -> Self {
+        Self { date, time }
+    }
+
+    /// Return the raw FAT date and time.
+    pub const fn raw(&self) -> (u16, u16) {
+        (self.date, self.time)
+    }
+
+    pub const fn year(&self) -> u16 {
+        (self.date >> 9) + 1980
+    }
+
+    pub const fn month(&self) -> u8 {
+        ((self.date >> 5) & 0x0f) as _
+    }
+
+    pub const fn day(&self) -> u8 {
+        (self.date & 0x1f) as _
+    }
+
+    pub const fn hour(&self) -> u8 {
+        (self.time >> 11) as _
+    }
+
+    pub const fn minute(&self) -> u8 {
+        ((self.time >> 5) & 0x3f) as _
+    }
+
+    pub const fn second(&self) -> u8 {
+        ((self.time & 0x1f) * 2) as _
+    }
+}
+
+/// The report of `MountedFatfs::purge_older_than`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PurgeReport {
+    /// The names of the files which were deleted - or would be, in a dry run.
+    pub files: Vec<String>,
+    /// The total size of these files, in bytes.
+    pub bytes: u64,
+}
+
+/// The error returned by `MountedFatfs::stream_to`.
+#[derive(Debug)]
+pub enum FatStreamError<E> {
+    /// The file could not be opened or read.
+    Read(EspError),
+    /// The sink failed.
+    Write(E),
+    /// The sink accepted no data.
+    WriteZero,
+}
+
+impl<E> core::fmt::Display for FatStreamError<E>
+where
+    E: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Read(err) => write!(f, "Reading the file failed: {err}"),
+            Self::Write(err) => write!(f, "Writing to the sink failed: {err:?}"),
+            Self::WriteZero => write!(f, "The sink accepted no data"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for FatStreamError<E> where E: core::fmt::Debug {}
+
+/// The size of the chunks in which `MountedFatfs::hash_file` reads the file.
+const HASH_CHUNK_LEN: usize = 4096;
+
+/// The algorithm of `MountedFatfs::hash_file`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    /// The CRC-32 of zlib and gzip (IEEE 802.3 polynomial)
+    Crc32,
+    /// SHA-256
+    #[cfg(esp_idf_comp_mbedtls_enabled)]
+    Sha256,
+}
+
+/// The digest of a file, computed with `MountedFatfs::hash_file`.
+///
+/// The `Display` implementation prints the digest in lowercase hex, as printed by
+/// e.g. `crc32` or `sha256sum`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FileDigest {
+    Crc32(u32),
+    #[cfg(esp_idf_comp_mbedtls_enabled)]
+    Sha256([u8; 32]),
+}
+
+impl core::fmt::Display for FileDigest {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Crc32(crc) => write!(f, "{crc:08x}"),
+            #[cfg(esp_idf_comp_mbedtls_enabled)]
+            Self::Sha256(hash) => hash.iter().try_for_each(|byte| write!(f, "{byte:02x}")),
+        }
+    }
+}
+
+/// An entry of a directory listed with `MountedFatfs::read_dir_paged`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FatDirEntry {
+    /// The name of the entry.
+    pub name: String,
+    /// The size of the entry in bytes, 0 for directories.
+    pub size: u64,
+    /// The attributes of the entry.
+    pub attributes: EnumSet<FatAttribute>,
+}
+
+/// Attribute of a file or a directory on a FAT filesystem.
+#[derive(Debug, EnumSetType)]
+#[enumset(repr = "u8")]
+pub enum FatAttribute {
+    /// Read only (`AM_RDO`)
+    ReadOnly = 0,
+    /// Hidden (`AM_HID`)
+    Hidden = 1,
+    /// System (`AM_SYS`)
+    System = 2,
+    /// Directory (`AM_DIR`)
+    Directory = 4,
+    /// Archive (`AM_ARC`)
+    Archive = 5,
+}
+
+/// The result of a throughput measurement done with `MountedFatfs::benchmark`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BenchResult {
+    /// Number of bytes written and then read.
+    pub bytes: u64,
+    /// Sequential write throughput, in MB/s.
+    pub write_mb_per_sec: f32,
+    /// Sequential read throughput, in MB/s.
+    pub read_mb_per_sec: f32,
+}
+
+impl BenchResult {
+    fn mb_per_sec(bytes: u64, elapsed_us: i64) -> f32 {
+        // Bytes per microsecond is the same as MB per second
+        bytes as f32 / elapsed_us.max(1) as f32
+    }
+}
+
+/// The free space threshold and callback of `MountedFatfs::set_low_space_threshold`.
+#[derive(Default)]
+struct LowSpaceWatch {
+    threshold: Option<u64>,
+    low: bool,
+    callback: Option<Box<dyn FnMut(u64) + Send>>,
+}
+
+fn check_low_space(watch: &Mutex<LowSpaceWatch>, fs: &FATFS) {
+    let mut watch = watch.lock();
+
+    let Some(threshold) = watch.threshold else {
+        return;
+    };
+
+    // `free_clst` is kept up to date by FATFS once known, and is out of range otherwise
+    if fs.free_clst > fs.n_fatent - 2 {
+        return;
+    }
+
+    let free_bytes = fs.free_clst as u64 * fs.csize as u64 * sector_size(fs) as u64;
+
+    if !watch.low && free_bytes < threshold {
+        warn!("Drive {}: free space low ({free_bytes} bytes)", fs.pdrv);
+
+        watch.low = true;
+
+        if let Some(callback) = watch.callback.as_mut() {
+            callback(free_bytes);
+        }
+    } else if watch.low && free_bytes >= threshold + threshold / 10 {
+        watch.low = false;
+    }
+}
+
+/// Represents a file opened on a mounted FAT filesystem.
+/// The file is automatically closed when the instance is dropped.
+pub struct FatFile<'a> {
+    fil: Box<FIL>,
+    low_space: &'a Mutex<LowSpaceWatch>,
+    #[cfg(feature = "fatfs-handle-tracking")]
+    open_handles: &'a Mutex<Vec<(usize, String)>>,
+}
+
+impl FatFile<'_> {
+    /// Read from the current position of the file into the buffer.
+    ///
+    /// Returns the number of bytes read, which is 0 at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        let mut read = 0;
+
+        check(unsafe {
+            f_read(
+                &mut *self.fil,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as _,
+                &mut read,
+            )
+        })?;
+
+        Ok(read as _)
+    }
+
+    /// Read exactly `buf.len()` bytes from the current position of the file.
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if the end of the file is reached before the buffer is filled.
+    pub fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), EspError> {
+        while !buf.is_empty() {
+            let read = self.read(buf)?;
+
+            if read == 0 {
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+            }
+
+            buf = &mut buf[read..];
+        }
+
+        Ok(())
+    }
+
+    /// Write the data at the current position of the file.
+    ///
+    /// Returns the number of bytes written, which might be less than `data.len()` if the volume is full.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, EspError> {
+        let mut written = 0;
+
+        check(unsafe {
+            f_write(
+                &mut *self.fil,
+                data.as_ptr() as *const _,
+                data.len() as _,
+                &mut written,
+            )
+        })?;
+
+        // SAFETY: The filesystem object outlives the file, as the file borrows the mount
+        check_low_space(self.low_space, unsafe { &*self.fil.obj.fs });
+
+        Ok(written as _)
+    }
+
+    /// Write all the data at the current position of the file.
+    ///
+    /// Returns `ESP_ERR_NO_MEM` if the volume gets full.
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<(), EspError> {
+        while !data.is_empty() {
+            let written = self.write(data)?;
+
+            if written == 0 {
+                Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+            }
+
+            data = &data[written..];
+        }
+
+        Ok(())
+    }
+
+    /// Move the current position of the file to the given offset from the start of the file.
+    pub fn seek(&mut self, offset: u64) -> Result<(), EspError> {
+        check(unsafe { f_lseek(&mut *self.fil, offset as _) })
+    }
+
+    /// Flush the cached data of the file to the storage.
+    pub fn sync(&mut self) -> Result<(), EspError> {
+        check(unsafe { f_sync(&mut *self.fil) })
+    }
+
+    /// Get the current position of the file.
+    pub fn position(&self) -> u64 {
+        self.fil.fptr as _
+    }
+
+    fn sector_size(&self) -> usize {
+        // SAFETY: The filesystem object outlives the file, as the file borrows the mount
+        sector_size(unsafe { &*self.fil.obj.fs })
+    }
+}
+
+/// A buffered writer on top of a `FatFile`, which accumulates small writes into a buffer
+/// spanning multiple sectors and writes them out as sector-aligned multi-sector transfers.
+///
+/// This is much faster than writing small records - e.g. log lines - one by one,
+/// as each small write results in a read-modify-write of a single sector.
+///
+/// Data in the buffer is lost on a power cut. Data is only guaranteed to be on the storage
+/// after `sync` returns; `flush` only hands the buffered data to FATFS, which might still cache
+/// the last partial sector. The buffer is flushed - but not synced - when the writer is dropped.
+pub struct BufferedFile<'a> {
+    file: FatFile<'a>,
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl<'a> BufferedFile<'a> {
+    /// Create a buffered writer with a buffer of `sectors` sectors.
+    pub fn new(file: FatFile<'a>, sectors: usize) -> Self {
+        let capacity = file.sector_size() * sectors.max(1);
+
+        Self {
+            file,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Buffer all the data, writing out the buffer each time it gets full.
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<(), EspError> {
+        while !data.is_empty() {
+            // Make sure that once the buffer is written out, the file position is sector-aligned
+            let misalignment = self.file.position() as usize % self.file.sector_size();
+            let limit = self.capacity - misalignment;
+
+            let len = data.len().min(limit - self.buf.len());
+
+            self.buf.extend_from_slice(&data[..len]);
+            data = &data[len..];
+
+            if self.buf.len() >= limit {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write out the buffered data to the file.
+    pub fn flush(&mut self) -> Result<(), EspError> {
+        if !self.buf.is_empty() {
+            self.file.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Write out the buffered data and flush the cached data of the file to the storage.
+    pub fn sync(&mut self) -> Result<(), EspError> {
+        self.flush()?;
+
+        self.file.sync()
+    }
+
+    /// Flush the buffered data and return the underlying file.
+    pub fn into_inner(mut self) -> Result<FatFile<'a>, EspError> {
+        self.flush()?;
+
+        let mut this = core::mem::ManuallyDrop::new(self);
+
+        // SAFETY: `this` is never used nor dropped afterwards
+        let file = unsafe { core::ptr::read(&this.file) };
+        let _buf = core::mem::take(&mut this.buf);
+
+        Ok(file)
+    }
+}
+
+impl Drop for BufferedFile<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Flush failed: {err}");
+        }
+    }
+}
+
+impl Drop for FatFile<'_> {
+    fn drop(&mut self) {
+        let res = unsafe { f_close(&mut *self.fil) };
+
+        if res != FRESULT_FR_OK {
+            warn!("Close failed: {res}");
+        }
+
+        #[cfg(feature = "fatfs-handle-tracking")]
+        {
+            let fil = &*self.fil as *const FIL as usize;
+
+            self.open_handles
+                .lock()
+                .retain(|(handle, _)| *handle != fil);
+        }
+    }
+}
+
+/// A reader of the FAT (File Allocation Table) of a mounted volume, which talks to the disk directly.
+struct FatTable<'a> {
+    fs: &'a FATFS,
+    sector_size: usize,
+    buf: Vec<u8>,
+    sector: Option<u64>,
+}
+
+impl<'a> FatTable<'a> {
+    fn new(fs: &'a FATFS) -> Result<Self, EspError> {
+        if fs.fs_type as u32 == FS_EXFAT {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?;
+        }
+
+        let sector_size = sector_size(fs);
+
+        Ok(Self {
+            fs,
+            sector_size,
+            buf: vec![0; sector_size],
+            sector: None,
+        })
+    }
+
+    /// Get the FAT entry of a cluster, i.e. 0 for a free cluster, or the next cluster in the chain.
+    fn entry(&mut self, cluster: u32) -> Result<u32, EspError> {
+        let cluster = cluster as u64;
+
+        Ok(match self.fs.fs_type as u32 {
+            FS_FAT12 => {
+                let offset = cluster + cluster / 2;
+                let value = self.byte(offset)? as u32 | (self.byte(offset + 1)? as u32) << 8;
+
+                if cluster & 1 != 0 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }
+            }
+            FS_FAT16 => self.byte(cluster * 2)? as u32 | (self.byte(cluster * 2 + 1)? as u32) << 8,
+            _ => {
+                let mut value = 0;
+
+                for index in (0..4).rev() {
+                    value = value << 8 | self.byte(cluster * 4 + index)? as u32;
+                }
+
+                value & 0x0fff_ffff
+            }
+        })
+    }
+
+    fn byte(&mut self, offset: u64) -> Result<u8, EspError> {
+        let sector = self.fs.fatbase as u64 + offset / self.sector_size as u64;
+
+        if self.sector != Some(sector) {
+            self.sector = None;
+            read_sector(self.fs.pdrv, &mut self.buf, sector)?;
+            self.sector = Some(sector);
+        }
+
+        Ok(self.buf[(offset % self.sector_size as u64) as usize])
+    }
+}
+
+/// Read one sector directly from the disk, bypassing the filesystem.
+///
+/// The buffer must be at least as large as the sector size of the volume.
+fn read_sector(drive: u8, buf: &mut [u8], sector: u64) -> Result<(), EspError> {
+    let res = unsafe { ff_disk_read(drive, buf.as_mut_ptr(), sector as _, 1) };
+
+    if res != DRESULT_RES_OK {
+        warn!("Reading sector {sector} failed: {res}");
+        Err(EspError::from_infallible::<ESP_FAIL>())?;
+    }
+
+    Ok(())
+}
+
+/// The sector size of a mounted volume.
+///
+/// `FATFS::ssize` only exists when `FF_MAX_SS != FF_MIN_SS`, so the size is asked to the disk,
+/// which - with a fixed sector size - is `FF_MAX_SS` anyway.
+fn sector_size(fs: &FATFS) -> usize {
+    let mut sector_size: WORD = 0;
+
+    let res = unsafe {
+        ff_disk_ioctl(
+            fs.pdrv,
+            GET_SECTOR_SIZE as _,
+            &mut sector_size as *mut WORD as *mut _,
+        )
+    };
+
+    if res != DRESULT_RES_OK || sector_size == 0 {
+        FF_MAX_SS as _
+    } else {
+        sector_size as _
+    }
+}
+
+/// The maximum size of a FAT12/FAT16 volume, with 65524 clusters of 64 KB.
+const MAX_FAT16_SIZE: u64 = 65524 * 64 * 1024;
+
+/// Convert the result code of `f_mkfs` into an `EspError`.
+fn check_mkfs(res: FRESULT) -> Result<(), EspError> {
+    if res == FRESULT_FR_MKFS_ABORTED {
+        warn!("Format aborted, the FAT type is not feasible with this volume or cluster size");
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+    }
+
+    check(res)
+}
+
+/// Convert a FATFS result code into an `EspError`.
+pub(crate) fn check(res: FRESULT) -> Result<(), EspError> {
+    #[allow(non_upper_case_globals)]
+    let err = match res {
+        FRESULT_FR_OK => return Ok(()),
+        FRESULT_FR_NO_FILE | FRESULT_FR_NO_PATH => ESP_ERR_NOT_FOUND,
+        FRESULT_FR_INVALID_NAME | FRESULT_FR_INVALID_PARAMETER | FRESULT_FR_INVALID_DRIVE => {
+            ESP_ERR_INVALID_ARG
+        }
+        FRESULT_FR_EXIST
+        | FRESULT_FR_DENIED
+        | FRESULT_FR_LOCKED
+        | FRESULT_FR_INVALID_OBJECT
+        | FRESULT_FR_NOT_ENABLED
+        | FRESULT_FR_NO_FILESYSTEM => ESP_ERR_INVALID_STATE,
+        FRESULT_FR_WRITE_PROTECTED => ESP_ERR_NOT_SUPPORTED,
+        FRESULT_FR_NOT_ENOUGH_CORE | FRESULT_FR_TOO_MANY_OPEN_FILES => ESP_ERR_NO_MEM,
+        FRESULT_FR_TIMEOUT => ESP_ERR_TIMEOUT,
+        _ => ESP_FAIL,
+    };
+
+    debug!("FATFS operation failed: {res}");
+
+    Err(EspError::from(err).unwrap())
+}
+
+/// A small configuration file (e.g. a JSON or TOML document), which is loaded into memory,
+/// modified by the caller and persisted atomically with `MountedFatfs::write_atomic`.
+///
+/// The file is locked for as long as the `ConfigFile` is alive: opening the same file again -
+/// e.g. from another task - blocks until the first `ConfigFile` is dropped. This serializes the
+/// read-modify-write cycles of the file.
+///
+/// The data is stored after a 16-byte header holding a magic number, a version - incremented on
+/// every `persist` - the length of the data and its CRC32, so that a corrupt file is detected on load.
+pub struct ConfigFile<'f, 'a, T> {
+    fs: &'f MountedFatfs<'a, T>,
+    path: String,
+    data: Vec<u8>,
+    version: u32,
+}
+
+impl<'f, 'a, T> ConfigFile<'f, 'a, T> {
+    const MAGIC: [u8; 4] = *b"ECFG";
+    const HEADER_LEN: usize = 16;
+
+    /// Lock and load the configuration file at `path`, completing an interrupted persist first.
+    ///
+    /// A missing file is loaded as empty data with version 0.
+    /// Fails with `ESP_ERR_INVALID_CRC` if the file is corrupt.
+    pub fn open(fs: &'f MountedFatfs<'a, T>, path: &str) -> Result<Self, EspError> {
+        {
+            let mut locks = fs.config_locks.lock();
+
+            while locks.iter().any(|locked| locked == path) {
+                locks = fs.config_unlocked.wait(locks);
+            }
+
+            locks.push(path.into());
+        }
+
+        // From here on, the lock is released by `Drop`
+        let mut config = Self {
+            fs,
+            path: path.into(),
+            data: Vec::new(),
+            version: 0,
+        };
+
+        config.load()?;
+
+        Ok(config)
+    }
+
+    /// The data of the file, without the header.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The data of the file, to be modified and then persisted with `persist`.
+    pub fn data_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+
+    /// The version of the loaded or last persisted data; 0 if the file does not exist yet.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Atomically replace the file with the current data, incrementing the version.
+    pub fn persist(&mut self) -> Result<(), EspError> {
+        let len: u32 = self
+            .data
+            .len()
+            .try_into()
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+        let version = self.version.wrapping_add(1);
+
+        let mut content = Vec::with_capacity(Self::HEADER_LEN + self.data.len());
+        content.extend_from_slice(&Self::MAGIC);
+        content.extend_from_slice(&version.to_le_bytes());
+        content.extend_from_slice(&len.to_le_bytes());
+        content.extend_from_slice(&Self::crc(&self.data).to_le_bytes());
+        content.extend_from_slice(&self.data);
+
+        self.fs.write_atomic(&self.path, &content)?;
+
+        self.version = version;
+
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<(), EspError> {
+        self.fs.recover_atomic(&self.path)?;
+
+        let mut file = match self.fs.open(&self.path, FA_READ) {
+            Err(err) if err.code() == ESP_ERR_NOT_FOUND => return Ok(()),
+            result => result?,
+        };
+
+        let size = file.fil.obj.objsize as usize;
+
+        if size < Self::HEADER_LEN {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())?;
+        }
+
+        let mut header = [0; Self::HEADER_LEN];
+        file.read_exact(&mut header)?;
+
+        let field = |offset: usize| {
+            u32::from_le_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+
+        let (version, len, crc) = (field(4), field(8), field(12));
+
+        if header[..4] != Self::MAGIC || len as usize != size - Self::HEADER_LEN {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())?;
+        }
+
+        let mut data = vec![0; len as usize];
+        file.read_exact(&mut data)?;
+
+        if Self::crc(&data) != crc {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())?;
+        }
+
+        self.data = data;
+        self.version = version;
+
+        Ok(())
+    }
+
+    fn crc(data: &[u8]) -> u32 {
+        unsafe { esp_rom_crc32_le(0, data.as_ptr(), data.len() as _) }
+    }
+}
+
+impl<T> Drop for ConfigFile<'_, '_, T> {
+    fn drop(&mut self) {
+        self.fs
+            .config_locks
+            .lock()
+            .retain(|locked| *locked != self.path);
+
+        self.fs.config_unlocked.notify_all();
+    }
+}
+
+/// The journal of the transaction in progress, see `MountedFatfs::transaction`.
+pub const FAT_JOURNAL_PATH: &str = "/fatfs.jnl";
+
+/// The suffix of the temporary files in which `FatTransaction::write` stages the new content of files.
+const FAT_TRANSACTION_SUFFIX: &str = ".txn";
+
+impl<'a, T> MountedFatfs<'a, T> {
+    /// Update several files all-or-nothing, e.g. a set of related configuration files.
+    ///
+    /// The operations of `f` are staged, and only applied once `f` returns successfully:
+    /// - `FatTransaction::write` writes the new content of a file to a temporary file
+    ///   (`<path>.txn`) right away, and syncs it;
+    /// - `FatTransaction::remove` and `FatTransaction::rename` are deferred.
+    ///
+    /// Each operation is first appended to a journal (`FAT_JOURNAL_PATH`) and synced.
+    /// The transaction is committed by appending a commit record to the journal, after which
+    /// the operations are applied in order - the temporary files being renamed over their files -
+    /// and the journal is removed.
+    ///
+    /// If `f` fails, the temporary files and the journal are removed, and the files are left untouched.
+    /// After a power cut, `recover_transaction` must be called once the filesystem is mounted on the next boot
+    /// (before reading the files): it completes the transaction if it was committed, and rolls it back otherwise.
+    /// The guarantees rely on the syncs and on the renames of FATFS; as with `write_atomic`, a file
+    /// which is replaced is removed right before the rename, which the recovery completes.
+    ///
+    /// Only one transaction can be in progress on the filesystem: this fails with
+    /// `ESP_ERR_INVALID_STATE` if the journal already exists, i.e. if another transaction is in
+    /// progress or if `recover_transaction` was not called.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R, EspError>
+    where
+        F: FnOnce(&mut FatTransaction<'_, 'a, T>) -> Result<R, EspError>,
+    {
+        let journal = self.open(FAT_JOURNAL_PATH, FA_WRITE | FA_CREATE_NEW)?;
+
+        let mut tx = FatTransaction {
+            fs: self,
+            journal,
+            ops: Vec::new(),
+        };
+
+        let value = match f(&mut tx).and_then(|value| tx.append("C").map(|_| value)) {
+            Ok(value) => value,
+            Err(err) => {
+                tx.rollback();
+
+                return Err(err);
+            }
+        };
+
+        // The transaction is committed: if applying it fails, `recover_transaction` completes it
+        let FatTransaction { ops, journal, .. } = tx;
+        drop(journal);
+
+        self.apply_transaction(&ops)?;
+        self.remove(FAT_JOURNAL_PATH)?;
+
+        Ok(value)
+    }
+
+    /// Complete or roll back a transaction interrupted by a power cut, see `transaction`.
+    ///
+    /// If the journal holds a commit record, the remaining operations of the transaction are applied;
+    /// otherwise, the temporary files of the transaction are removed. The journal is removed in both cases.
+    ///
+    /// Returns `true` if an interrupted transaction was completed.
+    pub fn recover_transaction(&self) -> Result<bool, EspError> {
+        let data = {
+            let mut journal = match self.open(FAT_JOURNAL_PATH, FA_READ) {
+                Err(err) if err.code() == ESP_ERR_NOT_FOUND => return Ok(false),
+                result => result?,
+            };
+
+            let mut data = vec![0; journal.fil.obj.objsize as usize];
+            journal.read_exact(&mut data)?;
+
+            data
+        };
+
+        let mut ops = Vec::new();
+        let mut committed = false;
+
+        // A line torn by the power cut has no newline, and is ignored
+        for line in data.split_inclusive(|byte| *byte == b'\n') {
+            let Some(line) = line
+                .strip_suffix(b"\n")
+                .and_then(|line| core::str::from_utf8(line).ok())
+            else {
+                break;
+            };
+
+            if line == "C" {
+                committed = true;
+                break;
+            }
+
+            if let Some(op) = FatTransactionOp::parse(line) {
+                ops.push(op);
+            }
+        }
+
+        if committed {
+            warn!("Completing an interrupted transaction");
+
+            self.apply_transaction(&ops)?;
+        } else {
+            warn!("Rolling back an interrupted transaction");
+
+            self.discard_transaction(&ops)?;
+        }
+
+        self.remove(FAT_JOURNAL_PATH)?;
+
+        Ok(committed)
+    }
+
+    /// Apply the operations of a committed transaction. Operations which were already applied
+    /// are skipped, so that an interrupted transaction can be applied again.
+    fn apply_transaction(&self, ops: &[FatTransactionOp]) -> Result<(), EspError> {
+        for op in ops {
+            match op {
+                FatTransactionOp::Write(path) => {
+                    let tmp_path = format!("{path}{FAT_TRANSACTION_SUFFIX}");
+
+                    if self.exists(&tmp_path)? {
+                        self.rename_replace(&tmp_path, path)?;
+                    }
+                }
+                FatTransactionOp::Remove(path) => {
+                    if self.exists(path)? {
+                        self.remove(path)?;
+                    }
+                }
+                FatTransactionOp::Rename(from, to) => {
+                    if self.exists(from)? {
+                        self.rename_replace(from, to)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the temporary files of a transaction which was not committed.
+    fn discard_transaction(&self, ops: &[FatTransactionOp]) -> Result<(), EspError> {
+        for op in ops {
+            if let FatTransactionOp::Write(path) = op {
+                let tmp_path = format!("{path}{FAT_TRANSACTION_SUFFIX}");
+
+                if self.exists(&tmp_path)? {
+                    self.remove(&tmp_path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A transaction in progress, see `MountedFatfs::transaction`.
+///
+/// Paths are compared as given, so a file should always be referred to with the same path
+/// within a transaction. A path which is removed or renamed cannot be used again in the same
+/// transaction, as the transaction could then not be applied again after a power cut.
+pub struct FatTransaction<'f, 'a, T> {
+    fs: &'f MountedFatfs<'a, T>,
+    journal: FatFile<'f>,
+    ops: Vec<FatTransactionOp>,
+}
+
+impl<T> FatTransaction<'_, '_, T> {
+    /// Stage the new content of the file at `path`, which is created if it does not exist.
+    ///
+    /// Writing the same file again replaces the staged content.
+    pub fn write(&mut self, path: &str, data: &[u8]) -> Result<(), EspError> {
+        self.check_path(path)?;
+
+        let op = FatTransactionOp::Write(path.into());
+
+        if !self.ops.contains(&op) {
+            self.append(&format!("{op}"))?;
+            self.ops.push(op);
+        }
+
+        let mut file = self.fs.open(
+            &format!("{path}{FAT_TRANSACTION_SUFFIX}"),
+            FA_WRITE | FA_CREATE_ALWAYS,
+        )?;
+
+        file.write_all(data)?;
+        file.sync()
+    }
+
+    /// Remove the file at `path` when the transaction is committed. A missing file is ignored.
+    pub fn remove(&mut self, path: &str) -> Result<(), EspError> {
+        self.check_path(path)?;
+
+        self.push(FatTransactionOp::Remove(path.into()))
+    }
+
+    /// Rename the file `from` to `to` when the transaction is committed, replacing `to` if it exists.
+    /// Nothing is done if `from` does not exist anymore at that point.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), EspError> {
+        if from.contains(':') || to.contains(':') {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        self.check_path(from)?;
+        self.check_path(to)?;
+
+        self.push(FatTransactionOp::Rename(from.into(), to.into()))
+    }
+
+    fn check_path(&self, path: &str) -> Result<(), EspError> {
+        let freed = self.ops.iter().any(|op| match op {
+            FatTransactionOp::Remove(removed) => removed == path,
+            FatTransactionOp::Rename(from, _) => from == path,
+            FatTransactionOp::Write(_) => false,
+        });
+
+        if freed || path.is_empty() || path.contains(['\n', '\t']) {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, op: FatTransactionOp) -> Result<(), EspError> {
+        self.append(&format!("{op}"))?;
+        self.ops.push(op);
+
+        Ok(())
+    }
+
+    /// Append a record to the journal and sync it.
+    fn append(&mut self, record: &str) -> Result<(), EspError> {
+        self.journal.write_all(record.as_bytes())?;
+        self.journal.write_all(b"\n")?;
+        self.journal.sync()
+    }
+
+    /// Remove the temporary files and the journal of a transaction which failed before being committed.
+    fn rollback(self) {
+        let FatTransaction { fs, journal, ops } = self;
+        drop(journal);
+
+        if let Err(err) = fs
+            .discard_transaction(&ops)
+            .and_then(|_| fs.remove(FAT_JOURNAL_PATH))
+        {
+            warn!("Rolling back the transaction failed: {err}");
+        }
+    }
 }
 
-impl<T> MountedFatfs<'_, T> {
-    /// Get the underlying FATFS instance.
-    pub fn fatfs(&self) -> &FATFS {
-        &self.fatfs
+/// An operation of a `FatTransaction`, as recorded in the journal.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum FatTransactionOp {
+    Write(String),
+    Remove(String),
+    Rename(String, String),
+}
+
+impl FatTransactionOp {
+    fn parse(record: &str) -> Option<Self> {
+        let (kind, args) = record.split_once(' ')?;
+
+        match kind {
+            "W" => Some(Self::Write(args.into())),
+            "D" => Some(Self::Remove(args.into())),
+            "R" => args
+                .split_once('\t')
+                .map(|(from, to)| Self::Rename(from.into(), to.into())),
+            _ => None,
+        }
     }
+}
 
-    // TODO: Add safe methods to interact with the filesystem
+impl core::fmt::Display for FatTransactionOp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Write(path) => write!(f, "W {path}"),
+            Self::Remove(path) => write!(f, "D {path}"),
+            Self::Rename(from, to) => write!(f, "R {from}\t{to}"),
+        }
+    }
 }
 
 impl<T> Drop for MountedFatfs<'_, T> {
@@ -101,6 +2278,25 @@ impl<T> Drop for MountedFatfs<'_, T> {
     }
 }
 
+/// Flush the data written to a file on a FAT filesystem mounted in the VFS to the storage.
+///
+/// Until this function (or `File::sync_all`, which is equivalent) is called or the file is closed,
+/// written data might only live in the FATFS cache, and be lost on a power cut.
+/// Loggers should therefore call it at checkpoints.
+#[cfg(feature = "std")]
+pub fn sync_file(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { fsync(file.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// The thread-safety of file operations relies on the FATFS component being reentrant
+const _: () = assert!(FF_FS_REENTRANT != 0);
+
 /// Represents a FAT filesystem.
 pub struct Fatfs<T> {
     drive: u8,
@@ -126,10 +2322,80 @@ impl<T> Fatfs<T> {
 
         Ok(Self {
             drive,
-            _partition: Partition::SdCard(sd_card_driver),
+            _partition: Partition::SdCard {
+                driver: sd_card_driver,
+                safe_mode: false,
+            },
         })
     }
 
+    /// Switch the SD card of the filesystem to - or back from - a safe mode, in which the card clock
+    /// is lowered to `SDCARD_SAFE_MODE_FREQ_KHZ`.
+    ///
+    /// This trades throughput for reliability on marginal hardware (i.e. long wiring), where CRC errors
+    /// at the default clock make reads and writes fail intermittently. As the SD card driver retries
+    /// and then reports such errors to FATFS as generic disk errors, the switch is not automatic: the
+    /// application should enable the safe mode when file operations keep failing with `FR_DISK_ERR`.
+    ///
+    /// Leaving the safe mode restores the highest clock supported by both the host and the card.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_SUPPORTED` if the filesystem is not on an SD card,
+    /// or if the SD host does not support changing the card clock.
+    pub fn set_sdcard_safe_mode<H>(&mut self, safe_mode: bool) -> Result<(), EspError>
+    where
+        T: BorrowMut<SdCardDriver<H>>,
+    {
+        let Partition::SdCard {
+            driver,
+            safe_mode: current,
+        } = &mut self._partition
+        else {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        };
+
+        let card = driver.borrow_mut().card();
+
+        let max_freq_khz = card.max_freq_khz.min(card.host.max_freq_khz as _);
+        let freq_khz = if safe_mode {
+            SDCARD_SAFE_MODE_FREQ_KHZ.min(max_freq_khz)
+        } else {
+            max_freq_khz
+        };
+
+        let set_card_clk = card
+            .host
+            .set_card_clk
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?;
+
+        esp!(unsafe { set_card_clk(card.host.slot, freq_khz) })?;
+
+        if *current != safe_mode {
+            warn!(
+                "Drive {}: SD card safe mode {}, clock set to {freq_khz} kHz",
+                self.drive,
+                if safe_mode { "enabled" } else { "disabled" }
+            );
+        }
+
+        *current = safe_mode;
+
+        Ok(())
+    }
+
+    /// Return `true` if the SD card of the filesystem is in the safe mode,
+    /// see `set_sdcard_safe_mode`.
+    pub fn is_sdcard_safe_mode(&self) -> bool {
+        matches!(
+            self._partition,
+            Partition::SdCard {
+                safe_mode: true,
+                ..
+            }
+        )
+    }
+
     /// Get the drive number of the filesystem.
     pub fn drive(&self) -> u8 {
         self.drive
@@ -137,6 +2403,11 @@ impl<T> Fatfs<T> {
 
     /// Format the partition with the given configuration.
     ///
+    /// The FAT type of `configuration` is validated against the size of the partition:
+    /// FAT12/FAT16 volumes cannot be larger than 4 GB, so formatting a larger partition
+    /// with `FatFsType::Fat` fails with `ESP_ERR_INVALID_ARG`. If FATFS cannot create
+    /// a volume of the requested type with the requested cluster size, `ESP_ERR_INVALID_SIZE` is returned.
+    ///
     /// # Arguments
     /// - Formatting configuration.
     /// - Buffer to use when formatting.
@@ -145,6 +2416,11 @@ impl<T> Fatfs<T> {
         configuration: &FormatConfiguration,
         buf: &mut [u8],
     ) -> Result<(), EspError> {
+        if configuration.fs_type == FatFsType::Fat && self.size()? > MAX_FAT16_SIZE {
+            warn!("Drive {} is too large for FAT12/FAT16", self.drive);
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
         let drive_path = self.drive_path();
 
         #[cfg(not(esp_idf_version_major = "4"))]
@@ -162,19 +2438,19 @@ impl<T> Fatfs<T> {
                 align: configuration.volume_data_alignment.get() as _,
             };
 
-            unsafe {
+            check_mkfs(unsafe {
                 f_mkfs(
                     drive_path.as_ptr(),
                     &opt,
                     buf.as_mut_ptr() as *mut _,
                     buf.len() as _,
-                );
-            }
+                )
+            })?;
         }
 
         #[cfg(esp_idf_version_major = "4")]
         {
-            unsafe {
+            check_mkfs(unsafe {
                 f_mkfs(
                     drive_path.as_ptr(),
                     match configuration.fs_type {
@@ -186,15 +2462,129 @@ impl<T> Fatfs<T> {
                     configuration.cluster_size,
                     buf.as_mut_ptr() as *mut _,
                     buf.len() as _,
-                );
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the consistency of the filesystem, without modifying it.
+    ///
+    /// The FAT tables and the directory tree are scanned for lost and cross-linked clusters,
+    /// and the copies of the FAT are compared with each other. This allows to make an informed
+    /// decision on whether the filesystem needs to be formatted.
+    ///
+    /// A volume which cannot be mounted because it does not contain a recognizable FAT filesystem
+    /// (see `probe`) - e.g. because its boot sector is damaged - is reported as
+    /// `FsCheckResult::unrecognized` rather than as an error. Errors are only returned when
+    /// the volume cannot be read at all.
+    ///
+    /// Note that the scan needs a bitmap of all clusters, i.e. one byte of RAM per 8 clusters.
+    /// Checking exFAT volumes is not supported.
+    pub fn check(&mut self) -> Result<FsCheckResult, EspError> {
+        if self.probe()?.is_none() {
+            return Ok(FsCheckResult::UNRECOGNIZED);
+        }
+
+        self.mount()?.check_consistency()
+    }
+
+    /// Get the size of the partition in bytes.
+    fn size(&mut self) -> Result<u64, EspError> {
+        if unsafe { ff_disk_initialize(self.drive) } as u32 & STA_NOINIT != 0 {
+            warn!("Initializing drive {} failed", self.drive);
+            Err(EspError::from_infallible::<ESP_FAIL>())?;
+        }
+
+        let mut sector_count: LBA_t = 0;
+        let mut sector_size: WORD = 0;
+
+        for (cmd, buf) in [
+            (GET_SECTOR_COUNT, &mut sector_count as *mut LBA_t as *mut _),
+            (GET_SECTOR_SIZE, &mut sector_size as *mut WORD as *mut _),
+        ] {
+            if unsafe { ff_disk_ioctl(self.drive, cmd as _, buf) } != DRESULT_RES_OK {
+                Err(EspError::from_infallible::<ESP_FAIL>())?;
             }
         }
 
+        Ok(sector_count as u64 * sector_size as u64)
+    }
+
+    /// Detect the type of the FAT filesystem on the partition by inspecting its boot sector,
+    /// without mounting it.
+    ///
+    /// If the partition is an SD card with an MBR partition table, the first partition is inspected.
+    ///
+    /// Returns `None` if the partition does not contain a recognizable FAT filesystem.
+    pub fn probe(&mut self) -> Result<Option<FatFsType>, EspError> {
+        if unsafe { ff_disk_initialize(self.drive) } as u32 & STA_NOINIT != 0 {
+            warn!("Initializing drive {} failed", self.drive);
+            Err(EspError::from_infallible::<ESP_FAIL>())?;
+        }
+
+        // FATFS does not support sectors larger than 4096 bytes
+        let mut buf = vec![0_u8; 4096];
+
+        read_sector(self.drive, &mut buf, 0)?;
+
+        if buf[510..512] != [0x55, 0xaa] {
+            return Ok(None);
+        }
+
+        let fs_type = Self::boot_sector_fs_type(&buf);
+        if fs_type.is_some() {
+            return Ok(fs_type);
+        }
+
+        // Not a boot sector, so maybe an MBR: look at the first partition
+        let start = u32::from_le_bytes(buf[454..458].try_into().unwrap());
+        if buf[450] == 0 || start == 0 {
+            return Ok(None);
+        }
+
+        read_sector(self.drive, &mut buf, start as _)?;
+
+        Ok(Self::boot_sector_fs_type(&buf))
+    }
+
+    fn boot_sector_fs_type(sector: &[u8]) -> Option<FatFsType> {
+        if &sector[3..11] == b"EXFAT   " {
+            Some(FatFsType::ExFat)
+        } else if &sector[82..87] == b"FAT32" {
+            Some(FatFsType::Fat32)
+        } else if &sector[54..57] == b"FAT" {
+            Some(FatFsType::Fat)
+        } else {
+            None
+        }
+    }
+
+    /// Fail with `ESP_ERR_NOT_SUPPORTED` if the partition contains an exFAT filesystem,
+    /// but the FATFS component is built without exFAT support.
+    pub(crate) fn check_supported(&mut self) -> Result<(), EspError> {
+        if FF_FS_EXFAT == 0 && self.probe()? == Some(FatFsType::ExFat) {
+            warn!(
+                "Drive {} contains an exFAT filesystem, but exFAT support is not enabled",
+                self.drive
+            );
+            Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?;
+        }
+
         Ok(())
     }
 
     /// Mount the filesystem and return a handle to it.
+    ///
+    /// exFAT volumes - common on SD cards larger than 32 GB - can only be mounted if the FATFS
+    /// component is built with exFAT support (`FF_FS_EXFAT`), which in turn requires long file names
+    /// to be enabled (`CONFIG_FATFS_LFN_HEAP` or `CONFIG_FATFS_LFN_STACK`).
+    /// Otherwise `ESP_ERR_NOT_SUPPORTED` is returned, so that the volume is not mistaken for
+    /// an unformatted one (and formatted).
     pub fn mount(&mut self) -> Result<MountedFatfs<'_, T>, EspError> {
+        self.check_supported()?;
+
         let mut fatfs: Box<FATFS> = Box::default(); // TODO: Large stack size
 
         let drive_path = self.drive_path();
@@ -206,7 +2596,17 @@ impl<T> Fatfs<T> {
             Err(EspError::from_infallible::<ESP_FAIL>())?
         }
 
-        Ok(MountedFatfs { fs: self, fatfs })
+        Ok(MountedFatfs {
+            fs: self,
+            fatfs,
+            config_locks: Mutex::new(Vec::new()),
+            config_unlocked: Condvar::new(),
+            low_space: Mutex::new(LowSpaceWatch::default()),
+            yield_interval: Some(DEFAULT_YIELD_INTERVAL),
+            read_only: false,
+            #[cfg(feature = "fatfs-handle-tracking")]
+            open_handles: Mutex::new(Vec::new()),
+        })
     }
 
     pub(crate) fn drive_path_from(drive: u8) -> [core::ffi::c_char; 2] {
@@ -216,6 +2616,15 @@ impl<T> Fatfs<T> {
     pub(crate) fn drive_path(&self) -> [core::ffi::c_char; 2] {
         Self::drive_path_from(self.drive)
     }
+
+    pub(crate) fn path_from(drive: u8, path: &str) -> Result<CString, EspError> {
+        to_cstring_arg(&format!("{}:{}", drive, path))
+    }
+
+    /// Build a FATFS path, i.e. a path prefixed with the drive number of the filesystem.
+    pub(crate) fn path(&self, path: &str) -> Result<CString, EspError> {
+        Self::path_from(self.drive, path)
+    }
 }
 
 impl Fatfs<()> {
@@ -267,10 +2676,445 @@ impl Fatfs<()> {
     }
 }
 
+impl Fatfs<()> {
+    /// Create a new FAT filesystem instance on top of a custom block device, e.g. an encrypted
+    /// partition or a network block store. The device is registered as the FATFS disk I/O driver
+    /// of the drive, and dropped when the filesystem instance is dropped.
+    ///
+    /// Fails with `ESP_ERR_INVALID_ARG` if the block size of the device is not supported
+    /// by FATFS (see `BlockDevice::block_size`), and with `ESP_ERR_INVALID_STATE` if the drive
+    /// is already in use by another block device.
+    ///
+    /// # Arguments
+    /// - Drive number to assign to the filesystem.
+    /// - Block device.
+    pub fn new_with_diskio<D>(drive: u8, device: D) -> Result<Self, EspError>
+    where
+        D: BlockDevice + 'static,
+    {
+        let block_size = device.block_size();
+
+        if !block_size.is_power_of_two()
+            || block_size < FF_MIN_SS as usize
+            || block_size > FF_MAX_SS as usize
+        {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        {
+            let mut devices = BLOCK_DEVICES.lock();
+
+            let slot = devices
+                .get_mut(drive as usize)
+                .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+            if slot.is_some() {
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+            }
+
+            *slot = Some(Box::new(device));
+        }
+
+        static BLOCK_DEVICE_DISKIO: ff_diskio_impl_t = ff_diskio_impl_t {
+            init: Some(diskio::init),
+            status: Some(diskio::status),
+            read: Some(diskio::read),
+            write: Some(diskio::write),
+            ioctl: Some(diskio::ioctl),
+        };
+
+        unsafe {
+            ff_diskio_register(drive, &BLOCK_DEVICE_DISKIO);
+        }
+
+        Ok(Self {
+            drive,
+            _partition: Partition::BlockDevice,
+        })
+    }
+
+    /// Create a new FAT filesystem instance backed by a RAM buffer of `size` bytes, and format it.
+    ///
+    /// This is meant for tests and examples which should not depend on an SD card or on a
+    /// flash partition: the filesystem can be mounted right away, and its content is lost
+    /// when the instance is dropped.
+    ///
+    /// The size must be a multiple of the 512-byte sector size, and large enough for a FAT volume
+    /// (at least 64 KB); otherwise `ESP_ERR_INVALID_SIZE` is returned.
+    ///
+    /// # Arguments
+    /// - Drive number to assign to the filesystem.
+    /// - Size of the RAM buffer in bytes.
+    #[cfg(feature = "experimental")]
+    pub fn new_ram(drive: u8, size: usize) -> Result<Self, EspError> {
+        if size == 0 || size % RamDisk::BLOCK_SIZE != 0 {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+        }
+
+        let mut fs = Self::new_with_diskio(drive, RamDisk(vec![0; size].into_boxed_slice()))?;
+
+        let mut buf = vec![0; RamDisk::BLOCK_SIZE * 8];
+        fs.format(&FormatConfiguration::default(), &mut buf)?;
+
+        Ok(fs)
+    }
+}
+
+/// A block device on which a FAT filesystem can be created with `Fatfs::new_with_diskio`.
+///
+/// The methods are called by FATFS from the tasks accessing the filesystem. FATFS serializes the
+/// accesses to a volume, and the calls to all block devices are additionally serialized by a lock
+/// shared by all drives: the implementation therefore does not need to be reentrant, but it must
+/// not access a FAT filesystem itself, as that would deadlock.
+pub trait BlockDevice: Send {
+    /// The size of a block in bytes, i.e. the sector size of the filesystem.
+    ///
+    /// Must be a power of two between `FF_MIN_SS` and `FF_MAX_SS`, i.e. 512 bytes unless
+    /// a larger sector size is enabled with `CONFIG_FATFS_SECTOR_4096`.
+    fn block_size(&self) -> usize;
+
+    /// The number of blocks of the device.
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks starting at block `start` into `buf`, whose length is a multiple of the block size.
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> Result<(), EspError>;
+
+    /// Write `data`, whose length is a multiple of the block size, to the blocks starting at block `start`.
+    fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), EspError>;
+
+    /// Flush any data cached by the device to the storage.
+    fn sync(&mut self) -> Result<(), EspError> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "experimental")]
+struct RamDisk(Box<[u8]>);
+
+#[cfg(feature = "experimental")]
+impl RamDisk {
+    const BLOCK_SIZE: usize = 512;
+
+    fn blocks(&mut self, start: u64, len: usize) -> Result<&mut [u8], EspError> {
+        let start = start as usize * Self::BLOCK_SIZE;
+
+        self.0
+            .get_mut(start..start + len)
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.0.len() / Self::BLOCK_SIZE) as _
+    }
+
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> Result<(), EspError> {
+        buf.copy_from_slice(self.blocks(start, buf.len())?);
+
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), EspError> {
+        self.blocks(start, data.len())?.copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+const NO_BLOCK_DEVICE: Option<Box<dyn BlockDevice>> = None;
+
+/// The block devices of the filesystems created with `Fatfs::new_with_diskio`, by drive number.
+static BLOCK_DEVICES: Mutex<[Option<Box<dyn BlockDevice>>; FF_VOLUMES as usize]> =
+    Mutex::new([NO_BLOCK_DEVICE; FF_VOLUMES as usize]);
+
+/// The FATFS disk I/O driver of the filesystems created with `Fatfs::new_with_diskio`.
+mod diskio {
+    use ::log::warn;
+
+    use crate::sys::*;
+
+    use super::{BlockDevice, BLOCK_DEVICES};
+
+    pub(super) unsafe extern "C" fn init(pdrv: BYTE) -> DSTATUS {
+        status(pdrv)
+    }
+
+    pub(super) unsafe extern "C" fn status(pdrv: BYTE) -> DSTATUS {
+        match BLOCK_DEVICES.lock().get(pdrv as usize) {
+            Some(Some(_)) => 0,
+            _ => STA_NOINIT as _,
+        }
+    }
+
+    pub(super) unsafe extern "C" fn read(
+        pdrv: BYTE,
+        buff: *mut BYTE,
+        sector: u32,
+        count: UINT,
+    ) -> DRESULT {
+        with_device(pdrv, |device| {
+            let len = count as usize * device.block_size();
+
+            device.read_blocks(sector as _, core::slice::from_raw_parts_mut(buff, len))
+        })
+    }
+
+    pub(super) unsafe extern "C" fn write(
+        pdrv: BYTE,
+        buff: *const BYTE,
+        sector: u32,
+        count: UINT,
+    ) -> DRESULT {
+        with_device(pdrv, |device| {
+            let len = count as usize * device.block_size();
+
+            device.write_blocks(sector as _, core::slice::from_raw_parts(buff, len))
+        })
+    }
+
+    pub(super) unsafe extern "C" fn ioctl(
+        pdrv: BYTE,
+        cmd: BYTE,
+        buff: *mut core::ffi::c_void,
+    ) -> DRESULT {
+        with_device(pdrv, |device| {
+            match cmd as u32 {
+                CTRL_SYNC => device.sync()?,
+                GET_SECTOR_COUNT => *(buff as *mut LBA_t) = device.block_count() as _,
+                GET_SECTOR_SIZE => *(buff as *mut WORD) = device.block_size() as _,
+                GET_BLOCK_SIZE => *(buff as *mut DWORD) = 1,
+                _ => Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?,
+            }
+
+            Ok(())
+        })
+    }
+
+    fn with_device<F>(pdrv: BYTE, f: F) -> DRESULT
+    where
+        F: FnOnce(&mut dyn BlockDevice) -> Result<(), EspError>,
+    {
+        let mut devices = BLOCK_DEVICES.lock();
+
+        let Some(Some(device)) = devices.get_mut(pdrv as usize) else {
+            return DRESULT_RES_NOTRDY;
+        };
+
+        match f(device.as_mut()) {
+            Ok(()) => DRESULT_RES_OK,
+            Err(err)
+                if err.code() == ESP_ERR_NOT_SUPPORTED || err.code() == ESP_ERR_INVALID_ARG =>
+            {
+                DRESULT_RES_PARERR
+            }
+            Err(err) => {
+                warn!("Block device of drive {pdrv} failed: {err}");
+
+                DRESULT_RES_ERROR
+            }
+        }
+    }
+}
+
 impl<T> Drop for Fatfs<T> {
     fn drop(&mut self) {
         unsafe {
             ff_diskio_register(self.drive, core::ptr::null_mut());
         }
+
+        if matches!(self._partition, Partition::BlockDevice) {
+            BLOCK_DEVICES.lock()[self.drive as usize] = None;
+        }
+    }
+}
+
+/// Async access to files on FAT filesystems mounted in the VFS (see `crate::io::vfs::MountedFatfs`).
+///
+/// As file I/O on FAT filesystems is blocking, the file operations are offloaded to a dedicated
+/// FreeRTOS task per file, so that the executor is not stalled while the storage is being accessed.
+/// Data is copied between the caller's buffers and the task's buffer.
+#[cfg(feature = "std")]
+pub mod asynch {
+    use core::ffi::CStr;
+
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use std::io::{Read, Seek, Write};
+
+    use embedded_svc::io;
+
+    use ::log::warn;
+
+    use crate::io::EspIOError;
+    use crate::private::unblocker::Unblocker;
+    use crate::private::zerocopy::Channel;
+    use crate::sys::*;
+
+    #[derive(Copy, Clone, Debug)]
+    enum AsyncCommand {
+        None,
+        Read(usize),
+        Write,
+        Sync,
+        Seek(std::io::SeekFrom),
+    }
+
+    #[derive(Debug)]
+    struct AsyncWork {
+        command: AsyncCommand,
+        buf: Vec<u8>,
+        result: Result<u64, EspError>,
+    }
+
+    /// An async wrapper around a `std::fs::File` on a FAT filesystem mounted in the VFS.
+    pub struct EspAsyncFile(Unblocker<AsyncWork>);
+
+    impl EspAsyncFile {
+        /// Wrap a file, offloading its operations to a dedicated task with the given stack size.
+        pub fn new(file: std::fs::File, stack_size: usize) -> Result<Self, EspError> {
+            let unblocker = Unblocker::new(
+                CStr::from_bytes_until_nul(b"Async file task\0").unwrap(),
+                stack_size,
+                None,
+                None,
+                move |channel| Self::work(channel, file),
+            )?;
+
+            Ok(Self(unblocker))
+        }
+
+        /// Read from the current position of the file into the buffer.
+        ///
+        /// Returns the number of bytes read, which is 0 at the end of the file.
+        pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+            self.execute(AsyncCommand::Read(buf.len()), &[], buf)
+                .await
+                .map(|read| read as _)
+        }
+
+        /// Write the data at the current position of the file.
+        ///
+        /// Returns the number of bytes written.
+        pub async fn write(&mut self, data: &[u8]) -> Result<usize, EspError> {
+            self.execute(AsyncCommand::Write, data, &mut [])
+                .await
+                .map(|written| written as _)
+        }
+
+        /// Flush the written data to the storage, see `super::sync_file`.
+        pub async fn sync(&mut self) -> Result<(), EspError> {
+            self.execute(AsyncCommand::Sync, &[], &mut [])
+                .await
+                .map(|_| ())
+        }
+
+        /// Move the current position of the file, and return the new position.
+        pub async fn seek(&mut self, pos: std::io::SeekFrom) -> Result<u64, EspError> {
+            self.execute(AsyncCommand::Seek(pos), &[], &mut []).await
+        }
+
+        async fn execute(
+            &mut self,
+            command: AsyncCommand,
+            data: &[u8],
+            out: &mut [u8],
+        ) -> Result<u64, EspError> {
+            // Get the shared reference to the work item (as processed by the Self::work thread),
+            // and replace it with the next work item we want to process.
+            let work = self.0.exec_in_out().await.unwrap();
+
+            work.command = command;
+            work.buf.clear();
+            work.buf.extend_from_slice(data);
+
+            // Signal the worker thread that it can process the work item.
+            self.0.do_exec().await;
+
+            // Wait for the worker thread to finish and return the result.
+            let work = self.0.exec_in_out().await.unwrap();
+
+            let result = work.result?;
+
+            if let AsyncCommand::Read(_) = command {
+                out[..result as usize].copy_from_slice(&work.buf[..result as usize]);
+            }
+
+            Ok(result)
+        }
+
+        fn work(channel: Arc<Channel<AsyncWork>>, mut file: std::fs::File) {
+            // Placeholder work item. This will be replaced by the first actual work item.
+            let mut work = AsyncWork {
+                command: AsyncCommand::None,
+                buf: Vec::new(),
+                result: Ok(0),
+            };
+
+            // Repeatedly share a reference to the work until the channel is closed.
+            while channel.share(&mut work) {
+                let result = match work.command {
+                    AsyncCommand::None => Ok(0),
+                    AsyncCommand::Read(len) => {
+                        work.buf.resize(len, 0);
+                        file.read(&mut work.buf).map(|read| read as _)
+                    }
+                    AsyncCommand::Write => file.write(&work.buf).map(|written| written as _),
+                    AsyncCommand::Sync => file.sync_all().map(|_| 0),
+                    AsyncCommand::Seek(pos) => file.seek(pos),
+                };
+
+                work.result = result.map_err(to_esp_error);
+            }
+        }
+    }
+
+    fn to_esp_error(err: std::io::Error) -> EspError {
+        warn!("File operation failed: {err}");
+
+        match err.kind() {
+            std::io::ErrorKind::NotFound => EspError::from_infallible::<ESP_ERR_NOT_FOUND>(),
+            std::io::ErrorKind::InvalidInput => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+            std::io::ErrorKind::OutOfMemory => EspError::from_infallible::<ESP_ERR_NO_MEM>(),
+            _ => EspError::from_infallible::<ESP_FAIL>(),
+        }
+    }
+
+    impl io::ErrorType for EspAsyncFile {
+        type Error = EspIOError;
+    }
+
+    impl io::asynch::Read for EspAsyncFile {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            EspAsyncFile::read(self, buf).await.map_err(EspIOError)
+        }
+    }
+
+    impl io::asynch::Write for EspAsyncFile {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            EspAsyncFile::write(self, buf).await.map_err(EspIOError)
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            EspAsyncFile::sync(self).await.map_err(EspIOError)
+        }
+    }
+
+    impl io::asynch::Seek for EspAsyncFile {
+        async fn seek(&mut self, pos: io::SeekFrom) -> Result<u64, Self::Error> {
+            let pos = match pos {
+                io::SeekFrom::Start(offset) => std::io::SeekFrom::Start(offset),
+                io::SeekFrom::End(offset) => std::io::SeekFrom::End(offset),
+                io::SeekFrom::Current(offset) => std::io::SeekFrom::Current(offset),
+            };
+
+            EspAsyncFile::seek(self, pos).await.map_err(EspIOError)
+        }
     }
 }
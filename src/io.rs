@@ -92,12 +92,18 @@ pub mod vfs {
     }
 
     /// Represents a mounted FAT filesystem.
+    ///
+    /// Individual file operations are thread-safe, as the FATFS component is built with
+    /// reentrancy enabled (`FF_FS_REENTRANT`). Sequences of operations which need to be atomic
+    /// with regards to other tasks (e.g. a read-modify-write of a configuration file)
+    /// should be wrapped with `with_lock`.
     #[cfg(all(feature = "experimental", feature = "alloc"))]
     pub struct MountedFatfs<T> {
         _handle: *mut sys::FATFS,
         _fatfs: T,
         path: alloc::ffi::CString,
         drive: u8,
+        lock: crate::private::mutex::Mutex<()>,
     }
 
     #[cfg(all(feature = "experimental", feature = "alloc"))]
@@ -108,10 +114,15 @@ pub mod vfs {
         /// - `fatfs`: The FAT filesystem instance to mount.
         /// - `path`: The path to mount the filesystem at.
         /// - `max_fds`: The maximum number of file descriptors to allocate.
+        ///
+        /// Returns `ESP_ERR_NOT_SUPPORTED` for exFAT volumes if the FATFS component
+        /// is built without exFAT support, see `Fatfs::mount`.
         pub fn mount<H>(mut fatfs: T, path: &str, max_fds: usize) -> Result<Self, sys::EspError>
         where
             T: core::borrow::BorrowMut<crate::fs::fatfs::Fatfs<H>>,
         {
+            fatfs.borrow_mut().check_supported()?;
+
             let path = crate::private::cstr::to_cstring_arg(path)?;
             let drive_path = fatfs.borrow_mut().drive_path();
 
@@ -137,8 +148,161 @@ pub mod vfs {
                 _fatfs: fatfs,
                 path,
                 drive,
+                lock: crate::private::mutex::Mutex::new(()),
             })
         }
+
+        /// Same as `mount`, but retries up to `attempts` times when the storage cannot be read,
+        /// e.g. when a cold SD card needs a moment to stabilize after power-up.
+        ///
+        /// The first retry happens after `delay`, and the delay doubles after each retry.
+        /// As FATFS mounts lazily, the storage is probed by reading its boot sector before mounting.
+        /// Returns the error of the last attempt if all attempts fail, and `ESP_ERR_INVALID_ARG`
+        /// if `attempts` is 0.
+        pub fn mount_with_retry<H>(
+            mut fatfs: T,
+            path: &str,
+            max_fds: usize,
+            attempts: u32,
+            delay: core::time::Duration,
+        ) -> Result<Self, sys::EspError>
+        where
+            T: core::borrow::BorrowMut<crate::fs::fatfs::Fatfs<H>>,
+        {
+            let mut delay = delay;
+            let mut result = Err(sys::EspError::from_infallible::<{ sys::ESP_ERR_INVALID_ARG }>());
+
+            for attempt in 0..attempts {
+                if attempt > 0 {
+                    ::log::warn!("Mounting {path} failed, retrying in {delay:?}");
+
+                    crate::hal::delay::FreeRtos::delay_ms(
+                        delay.as_millis().try_into().unwrap_or(u32::MAX),
+                    );
+
+                    delay = delay.saturating_mul(2);
+                }
+
+                result = fatfs.borrow_mut().probe().map(|_| ());
+
+                if result.is_ok() {
+                    break;
+                }
+            }
+
+            result?;
+
+            Self::mount(fatfs, path, max_fds)
+        }
+
+        /// Run a sequence of file operations while holding the lock of this mount.
+        ///
+        /// All tasks which perform multi-step operations on the same files should do so
+        /// within `with_lock`, so that these operations are serialized.
+        /// The closure receives the path at which the filesystem is mounted.
+        ///
+        /// Note that the lock is not re-entrant: calling `with_lock` from within the closure deadlocks.
+        pub fn with_lock<F, R>(&self, f: F) -> R
+        where
+            F: FnOnce(&str) -> R,
+        {
+            let _guard = self.lock.lock();
+
+            f(self.base_path())
+        }
+
+        /// Get the path at which the filesystem is mounted in the VFS.
+        pub fn base_path(&self) -> &str {
+            // Safe to unwrap, as the path was created from a `&str`
+            self.path.to_str().unwrap()
+        }
+
+        /// Get a snapshot of the usage of the filesystem.
+        pub fn usage(&self) -> Result<crate::fs::fatfs::DiskUsage, sys::EspError> {
+            crate::fs::fatfs::disk_usage(self.drive)
+        }
+
+        /// Get a handle to the root of the filesystem, whose file operations take paths
+        /// relative to `base_path`, so that the base path does not have to be prepended manually
+        /// to every path.
+        ///
+        /// The handle borrows the mount, so it cannot outlive it. (As the handle borrows the mount,
+        /// `mount` cannot return both: mount first, then get the root.)
+        #[cfg(feature = "std")]
+        pub fn root(&self) -> FatfsRoot<'_> {
+            FatfsRoot {
+                base_path: self.base_path(),
+            }
+        }
+    }
+
+    /// The root of a FAT filesystem mounted in the VFS, see `MountedFatfs::root`.
+    ///
+    /// The operations are the ones of `std::fs`, with paths relative to the base path of the mount
+    /// (e.g. `logs/log.txt` or `/logs/log.txt`). Paths with `..` components are rejected with
+    /// `std::io::ErrorKind::InvalidInput`, so that the operations cannot escape the mount.
+    #[cfg(all(feature = "experimental", feature = "std"))]
+    #[derive(Debug, Copy, Clone)]
+    pub struct FatfsRoot<'a> {
+        base_path: &'a str,
+    }
+
+    #[cfg(all(feature = "experimental", feature = "std"))]
+    impl FatfsRoot<'_> {
+        /// Get the absolute VFS path of `path`, relative to the base path of the mount.
+        pub fn path(&self, path: &str) -> std::io::Result<std::path::PathBuf> {
+            let path = std::path::Path::new(path.trim_start_matches('/'));
+
+            if path
+                .components()
+                .any(|component| matches!(component, std::path::Component::ParentDir))
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "path escapes the mount",
+                ));
+            }
+
+            Ok(std::path::Path::new(self.base_path).join(path))
+        }
+
+        /// Open an existing file for reading, see `std::fs::File::open`.
+        pub fn open(&self, path: &str) -> std::io::Result<std::fs::File> {
+            std::fs::File::open(self.path(path)?)
+        }
+
+        /// Create a file for writing, truncating it if it exists, see `std::fs::File::create`.
+        pub fn create(&self, path: &str) -> std::io::Result<std::fs::File> {
+            std::fs::File::create(self.path(path)?)
+        }
+
+        /// List the entries of a directory, see `std::fs::read_dir`.
+        pub fn read_dir(&self, path: &str) -> std::io::Result<std::fs::ReadDir> {
+            std::fs::read_dir(self.path(path)?)
+        }
+
+        /// Remove a file, see `std::fs::remove_file`.
+        pub fn remove(&self, path: &str) -> std::io::Result<()> {
+            std::fs::remove_file(self.path(path)?)
+        }
+    }
+
+    // Safe because the FATFS handle is never accessed after mounting,
+    // and the FATFS component is reentrant
+    #[cfg(all(feature = "experimental", feature = "alloc"))]
+    unsafe impl<T> Send for MountedFatfs<T> where T: Send {}
+
+    #[cfg(all(feature = "experimental", feature = "alloc"))]
+    unsafe impl<T> Sync for MountedFatfs<T> where T: Sync {}
+
+    #[cfg(all(feature = "experimental", feature = "alloc"))]
+    impl<T> core::fmt::Debug for MountedFatfs<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("MountedFatfs")
+                .field("base_path", &self.base_path())
+                .field("drive", &self.drive)
+                .finish()
+        }
     }
 
     #[cfg(all(feature = "experimental", feature = "alloc"))]
@@ -37,11 +37,12 @@
 //! }
 //! ```
 
-use core::cmp::min;
+use core::cmp::{min, Ordering};
 use core::fmt::Write;
 use core::marker::PhantomData;
 use core::mem;
 use core::ptr;
+use core::time::Duration;
 
 use ::log::*;
 use embedded_svc::ota::OtaUpdateFinished;
@@ -53,13 +54,81 @@ pub use embedded_svc::ota::{FirmwareInfo, LoadResult, Slot, SlotState, UpdatePro
 
 use crate::sys::*;
 
+use crate::hal::delay::FreeRtos;
+use crate::hal::reset::ResetReason;
+
 use crate::io::EspIOError;
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+use crate::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+use crate::private::sha256::{sha256, Sha256};
 use crate::private::{cstr::*, mutex};
 
 static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
 
+/// The default number of bytes after which the flash readback loops yield, see `EspOta::set_yield_interval`.
+pub const DEFAULT_YIELD_INTERVAL: usize = 64 * 1024;
+
+/// Yield for one tick if an interval boundary was crossed when processing the bytes `from..to`.
+fn cooperative_yield(interval: Option<usize>, from: usize, to: usize) {
+    if let Some(interval) = interval.filter(|interval| *interval > 0) {
+        if from / interval != to / interval {
+            unsafe { vTaskDelay(1) };
+        }
+    }
+}
+
+/// Returns the length of the app image in `partition`, as declared by its headers.
+fn image_len(partition: &esp_partition_t) -> Result<usize, EspError> {
+    let mut metadata: esp_image_metadata_t = Default::default();
+
+    esp!(unsafe {
+        esp_image_get_metadata(
+            &esp_partition_pos_t {
+                offset: partition.address,
+                size: partition.size,
+            },
+            &mut metadata,
+        )
+    })?;
+
+    Ok(metadata.image_len as _)
+}
+
+/// Whether an `EspOtaUpdate` is currently alive.
+///
+/// The borrow on `EspOta` already prevents a second update while one is alive; this flag only
+/// matters for an update leaked with `core::mem::forget`. Such an update still owns an
+/// `esp_ota_begin` handle, so the flag deliberately stays set (even across `EspOta` re-creation),
+/// and no other update can be started until reboot.
+static UPDATING: mutex::Mutex<bool> = mutex::Mutex::new(false);
+
+fn check_not_updating() -> Result<(), EspError> {
+    if *UPDATING.lock() {
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the minimum number of bytes from the start of a firmware image which are necessary
+/// to load the firmware information, i.e. the image header, the first segment header
+/// and the application description.
+///
+/// This size depends on the target and on the ESP IDF version.
+pub const fn min_loader_capacity() -> usize {
+    mem::size_of::<esp_image_header_t>()
+        + mem::size_of::<esp_image_segment_header_t>()
+        + mem::size_of::<esp_app_desc_t>()
+}
+
+const LOADER_CAPACITY: usize = 512;
+
+// Fail the build rather than silently never loading the firmware info
+const _: () = assert!(LOADER_CAPACITY >= min_loader_capacity());
+
 #[deprecated(note = "Use `EspFirmwareInfoLoad` instead")]
-pub struct EspFirmwareInfoLoader(heapless::Vec<u8, 512>);
+pub struct EspFirmwareInfoLoader(heapless::Vec<u8, LOADER_CAPACITY>);
 
 #[allow(deprecated)]
 impl EspFirmwareInfoLoader {
@@ -85,19 +154,14 @@ impl EspFirmwareInfoLoader {
     }
 
     pub fn is_loaded(&self) -> bool {
-        self.0.len()
-            >= mem::size_of::<esp_image_header_t>()
-                + mem::size_of::<esp_image_segment_header_t>()
-                + mem::size_of::<esp_app_desc_t>()
+        self.0.len() >= min_loader_capacity()
     }
 
     pub fn get_info(&self) -> Result<FirmwareInfo, EspError> {
         if self.is_loaded() {
             let app_desc_slice = &self.0[mem::size_of::<esp_image_header_t>()
                 + mem::size_of::<esp_image_segment_header_t>()
-                ..mem::size_of::<esp_image_header_t>()
-                    + mem::size_of::<esp_image_segment_header_t>()
-                    + mem::size_of::<esp_app_desc_t>()];
+                ..min_loader_capacity()];
 
             let app_desc = unsafe {
                 (app_desc_slice.as_ptr() as *const esp_app_desc_t)
@@ -149,6 +213,91 @@ impl FirmwareInfoLoader for EspFirmwareInfoLoader {
     }
 }
 
+/// A firmware info loader which accumulates the start of the firmware image in a buffer
+/// provided by the caller, so that the buffer can live wherever the caller wants
+/// (in a static, on the stack, or be reused afterwards).
+///
+/// This has the same API as the deprecated `EspFirmwareInfoLoader`, which owns its buffer.
+pub struct EspFirmwareInfoSliceLoader<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> EspFirmwareInfoSliceLoader<'b> {
+    /// Creates a loader using `buf` as its buffer. Only the first `min_loader_capacity()` bytes
+    /// of `buf` are used.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if `buf` is shorter than `min_loader_capacity()`.
+    pub fn new(buf: &'b mut [u8]) -> Result<Self, EspError> {
+        if buf.len() < min_loader_capacity() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        Ok(Self { buf, len: 0 })
+    }
+
+    pub fn load(&mut self, buf: &[u8]) -> Result<LoadResult, EspError> {
+        let remaining = min_loader_capacity() - self.len;
+        let len = min(buf.len(), remaining);
+
+        self.buf[self.len..self.len + len].copy_from_slice(&buf[..len]);
+        self.len += len;
+
+        Ok(if self.is_loaded() {
+            LoadResult::Loaded
+        } else {
+            LoadResult::LoadMore
+        })
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.len >= min_loader_capacity()
+    }
+
+    pub fn get_info(&self) -> Result<FirmwareInfo, EspError> {
+        let native_info = EspFirmwareInfoLoad
+            .fetch_native(&self.buf[..self.len])
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+        let mut info = FirmwareInfo {
+            version: heapless::String::new(),
+            released: heapless::String::new(),
+            description: None,
+            signature: None,
+            download_id: None,
+        };
+
+        EspFirmwareInfoLoad::load_firmware_info(&mut info, native_info.app_desc)?;
+
+        Ok(info)
+    }
+
+    /// Releases the buffer, e.g. to reuse it for the update itself.
+    pub fn into_inner(self) -> &'b mut [u8] {
+        self.buf
+    }
+}
+
+impl io::ErrorType for EspFirmwareInfoSliceLoader<'_> {
+    type Error = EspIOError;
+}
+
+impl FirmwareInfoLoader for EspFirmwareInfoSliceLoader<'_> {
+    fn load(&mut self, buf: &[u8]) -> Result<LoadResult, Self::Error> {
+        Ok(EspFirmwareInfoSliceLoader::load(self, buf)?)
+    }
+
+    fn is_loaded(&self) -> bool {
+        EspFirmwareInfoSliceLoader::is_loaded(self)
+    }
+
+    fn get_info(&self) -> Result<FirmwareInfo, Self::Error> {
+        Ok(EspFirmwareInfoSliceLoader::get_info(self)?)
+    }
+}
+
 /// Native ESP-IDF firmware information
 #[derive(Debug, Clone)]
 pub struct EspNativeFirmwareInfo<'a> {
@@ -172,10 +321,7 @@ impl EspFirmwareInfoLoad {
     /// Returns `Some(EspNativeFirmwareInfo)` if the information was successfully fetched.
     /// Returns `None` if the firmware data has not been loaded completely yet.
     pub fn fetch_native<'a>(&self, data: &'a [u8]) -> Option<EspNativeFirmwareInfo<'a>> {
-        let loaded = data.len()
-            >= mem::size_of::<esp_image_header_t>()
-                + mem::size_of::<esp_image_segment_header_t>()
-                + mem::size_of::<esp_app_desc_t>();
+        let loaded = data.len() >= min_loader_capacity();
 
         if loaded {
             let image_header_slice = &data[..mem::size_of::<esp_image_header_t>()];
@@ -184,9 +330,7 @@ impl EspFirmwareInfoLoad {
                     + mem::size_of::<esp_image_segment_header_t>()];
             let app_desc_slice = &data[mem::size_of::<esp_image_header_t>()
                 + mem::size_of::<esp_image_segment_header_t>()
-                ..mem::size_of::<esp_image_header_t>()
-                    + mem::size_of::<esp_image_segment_header_t>()
-                    + mem::size_of::<esp_app_desc_t>()];
+                ..min_loader_capacity()];
 
             let image_header = unsafe {
                 (image_header_slice.as_ptr() as *const esp_image_header_t)
@@ -216,6 +360,124 @@ impl EspFirmwareInfoLoad {
         }
     }
 
+    /// Checks that the flash size the firmware image was built for (as encoded in its image header)
+    /// does not exceed the actual size of the flash chip, as such an image might fail to boot.
+    ///
+    /// This check should be done before completing an update, together with the other checks
+    /// of the firmware information.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if the image header has not been loaded completely yet,
+    /// and `ESP_ERR_NOT_SUPPORTED` if the image was built for a larger flash chip.
+    pub fn check_flash_size(&self, data: &[u8]) -> Result<(), EspError> {
+        let native_info = self
+            .fetch_native(data)
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+        // `esp_image_flash_size_t`: 0 is 1 MB, 1 is 2 MB, etc.
+        let image_flash_size = (1024 * 1024_u64) << native_info.image_header.spi_size();
+
+        let mut flash_size = 0_u32;
+        esp!(unsafe { esp_flash_get_size(esp_flash_default_chip, &mut flash_size) })?;
+
+        if image_flash_size > flash_size as u64 {
+            warn!(
+                "Image built for a {image_flash_size} bytes flash, but the flash size is {flash_size} bytes"
+            );
+            Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?;
+        }
+
+        Ok(())
+    }
+
+    /// Estimates the total size of the firmware image by walking the segment headers
+    /// declared in its image header, e.g. to call `EspOta::initiate_update_with_size` when the
+    /// server does not report the size of the image.
+    ///
+    /// As the segment headers are interleaved with the segment data, `data` must extend up to
+    /// the header of the last segment; otherwise `None` is returned. The estimate includes the
+    /// checksum and the appended SHA-256, but not a secure boot signature block, which might
+    /// follow the image. Writing beyond the estimate is allowed, up to the size of the partition.
+    ///
+    /// `None` is also returned if the segment headers are malformed, i.e. if the estimate
+    /// overflows or exceeds the size of the next update partition.
+    pub fn estimated_image_size(&self, data: &[u8]) -> Option<usize> {
+        let native_info = self.fetch_native(data)?;
+
+        // The segment lengths come from the image, which is not validated yet
+        let partition_size =
+            unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() }?.size;
+
+        let mut offset = mem::size_of::<esp_image_header_t>();
+
+        for _ in 0..native_info.image_header.segment_count {
+            let segment_header = data
+                .get(offset..offset.checked_add(mem::size_of::<esp_image_segment_header_t>())?)?;
+
+            // `esp_image_segment_header_t`: `load_addr`, then `data_len`
+            let data_len = u32::from_le_bytes(segment_header[4..8].try_into().unwrap());
+
+            offset = offset
+                .checked_add(segment_header.len())?
+                .checked_add(data_len as usize)?;
+        }
+
+        // The checksum byte, padded to 16 bytes
+        let mut size = offset.checked_add(1 + 15)? & !15;
+
+        if native_info.image_header.hash_appended != 0 {
+            size = size.checked_add(32)?;
+        }
+
+        (size <= partition_size as usize).then_some(size)
+    }
+
+    /// Returns the version of ESP IDF the firmware image was built with (e.g. `v5.2.1`),
+    /// or `None` if the firmware data has not been loaded completely yet.
+    pub fn idf_version<'a>(&self, data: &'a [u8]) -> Option<&'a str> {
+        self.fetch_native(data)
+            .map(|native_info| unsafe { from_cstr_ptr(&native_info.app_desc.idf_ver as *const _) })
+    }
+
+    /// Checks the firmware image against an update policy, before the update is completed:
+    /// - its secure version must be at least `min_secure_version`, which rejects downgrades
+    ///   before they are written (the bootloader only enforces the secure version
+    ///   with anti-rollback enabled, and only on boot);
+    /// - it must have been built with ESP IDF `min_idf_version` (as `(major, minor, patch)`) or later.
+    ///
+    /// Each failed criterion is reported with a distinct `OtaPolicyError`.
+    pub fn check_policy(
+        &self,
+        data: &[u8],
+        min_secure_version: u32,
+        min_idf_version: (u32, u32, u32),
+    ) -> Result<(), OtaPolicyError> {
+        let native_info = self.fetch_native(data).ok_or(OtaPolicyError::NotLoaded)?;
+
+        let secure_version = native_info.app_desc.secure_version;
+
+        if secure_version < min_secure_version {
+            Err(OtaPolicyError::SecureVersion {
+                image: secure_version,
+                min: min_secure_version,
+            })?;
+        }
+
+        let idf_version = unsafe { from_cstr_ptr(&native_info.app_desc.idf_ver as *const _) };
+        let idf_version =
+            parse_idf_version(idf_version).ok_or(OtaPolicyError::UnknownIdfVersion)?;
+
+        if idf_version < min_idf_version {
+            Err(OtaPolicyError::IdfVersion {
+                image: idf_version,
+                min: min_idf_version,
+            })?;
+        }
+
+        Ok(())
+    }
+
     /// Fetches firmware information from the firmware binary data chunk loaded so far.
     ///
     /// Returns `true` if the information was successfully fetched.
@@ -270,246 +532,2635 @@ impl EspFirmwareInfoLoad {
     }
 }
 
+/// Parses an ESP IDF version string as embedded in the app description, e.g. `v5.2.1`,
+/// `v5.1-dirty` or `v5.3-dev-1234-gabcdef`, into `(major, minor, patch)`.
+fn parse_idf_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let version = version
+        .split(|c: char| c != '.' && !c.is_ascii_digit())
+        .next()?;
+
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+
+    Some((major, minor, patch))
+}
+
+/// Compares two firmware versions, parsed with `parse_idf_version`, so that suffixes like
+/// `-dirty` or `-rc1` are ignored. A missing or unparseable version is older than any parseable one.
+fn compare_versions(a: Option<&str>, b: Option<&str>) -> Ordering {
+    a.and_then(parse_idf_version)
+        .cmp(&b.and_then(parse_idf_version))
+}
+
+/// The error returned by `EspFirmwareInfoLoad::check_policy`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OtaPolicyError {
+    /// The firmware data has not been loaded completely yet.
+    NotLoaded,
+    /// The secure version of the image is lower than the policy minimum.
+    SecureVersion { image: u32, min: u32 },
+    /// The image was built with an ESP IDF version older than the policy minimum.
+    IdfVersion {
+        image: (u32, u32, u32),
+        min: (u32, u32, u32),
+    },
+    /// The ESP IDF version of the image could not be parsed.
+    UnknownIdfVersion,
+}
+
+impl core::fmt::Display for OtaPolicyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NotLoaded => write!(f, "The firmware information is not loaded yet"),
+            Self::SecureVersion { image, min } => {
+                write!(f, "Secure version {image} is lower than {min}")
+            }
+            Self::IdfVersion { image, min } => write!(
+                f,
+                "ESP IDF version {}.{}.{} is older than {}.{}.{}",
+                image.0, image.1, image.2, min.0, min.1, min.2
+            ),
+            Self::UnknownIdfVersion => write!(f, "Unknown ESP IDF version"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for OtaPolicyError {}
+
 impl io::ErrorType for EspFirmwareInfoLoad {
     type Error = EspIOError;
 }
 
+/// The minimum duration over which the write throughput is sampled, see `EspOtaUpdate::throughput`.
+///
+/// Chunks arriving in a burst are accumulated into one sample, so that their rate does not
+/// look like an infinite one.
+pub const THROUGHPUT_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The weight of a new throughput sample in `EspOtaUpdate::smoothed_throughput`.
+pub const THROUGHPUT_SMOOTHING: f32 = 0.2;
+
+#[derive(Debug)]
+struct Throughput {
+    sample_start: i64,
+    sample_len: usize,
+    last: Option<f32>,
+    smoothed: Option<f32>,
+}
+
+impl Throughput {
+    fn new() -> Self {
+        Self {
+            sample_start: unsafe { esp_timer_get_time() },
+            sample_len: 0,
+            last: None,
+            smoothed: None,
+        }
+    }
+
+    fn update(&mut self, len: usize) {
+        self.sample_len += len;
+
+        let now = unsafe { esp_timer_get_time() };
+        let elapsed = now - self.sample_start;
+
+        if elapsed >= THROUGHPUT_SAMPLE_INTERVAL.as_micros() as i64 {
+            let rate = self.sample_len as f32 * 1_000_000.0 / elapsed as f32;
+
+            self.last = Some(rate);
+            self.smoothed = Some(self.smoothed.map_or(rate, |smoothed| {
+                smoothed + THROUGHPUT_SMOOTHING * (rate - smoothed)
+            }));
+
+            self.sample_start = now;
+            self.sample_len = 0;
+        }
+    }
+
+    fn eta(rate: Option<f32>, remaining: usize) -> Option<Duration> {
+        rate.filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f32(remaining as f32 / rate))
+    }
+}
+
 #[derive(Debug)]
 pub struct EspOtaUpdate<'a> {
     update_partition: *const esp_partition_t,
     update_handle: esp_ota_handle_t,
+    write_retries: u32,
+    write_retry_delay: Duration,
+    last_write_retries: u32,
+    written: usize,
+    last_write_len: usize,
+    warn_unaligned: bool,
+    erase_on_failed_validation: bool,
+    yield_interval: Option<usize>,
+    throughput: Throughput,
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    checkpoint: Option<OtaCheckpoint>,
     _data: PhantomData<&'a mut ()>,
 }
 
+/// The recommended size of the buffers passed to `EspOtaUpdate::write`, i.e. the flash sector size.
+///
+/// Writing in multiples of this size keeps the writes aligned to flash sectors,
+/// which avoids unnecessary work in the flash driver.
+pub const RECOMMENDED_WRITE_SIZE: usize = SPI_FLASH_SEC_SIZE as usize;
+
 impl<'a> EspOtaUpdate<'a> {
-    /// Writes OTA update data to partition.
-    /// This function can be called multiple times as data is received during the OTA operation.
-    /// Data is written sequentially to the partition.
+    fn new(
+        update_partition: *const esp_partition_t,
+        update_handle: esp_ota_handle_t,
+        yield_interval: Option<usize>,
+    ) -> Self {
+        *UPDATING.lock() = true;
+
+        Self {
+            update_partition,
+            update_handle,
+            write_retries: 0,
+            write_retry_delay: Duration::ZERO,
+            last_write_retries: 0,
+            written: 0,
+            last_write_len: 0,
+            warn_unaligned: false,
+            erase_on_failed_validation: false,
+            yield_interval,
+            throughput: Throughput::new(),
+            #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+            checkpoint: None,
+            _data: PhantomData,
+        }
+    }
+
+    /// Configures how many times a write which failed due to a - possibly transient - flash error
+    /// should be retried, and how long to wait before each retry.
     ///
-    /// # Errors
+    /// By default, writes are not retried. Only flash operation failures and timeouts
+    /// are retried, and never when flash encryption is enabled: `esp_ota_write` then buffers
+    /// the data which is not aligned to 16 bytes, and a failed write might have buffered it already.
     ///
-    /// Returns an error if data could not be written to flash.
-    pub fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+    /// If a write still fails after all retries are exhausted, the error is returned and the update
+    /// should be aborted (i.e. by dropping it).
+    pub fn set_write_retries(&mut self, retries: u32, delay: Duration) {
+        self.write_retries = retries;
+        self.write_retry_delay = delay;
+    }
+
+    /// Returns the label of the partition being updated, e.g. `ota_1`.
+    pub fn target_label(&self) -> Result<heapless::String<16>, EspError> {
         self.check_write()?;
 
-        if !buf.is_empty() {
-            esp!(unsafe { esp_ota_write(self.update_handle, buf.as_ptr() as _, buf.len() as _) })?;
-        }
+        let partition = unsafe { self.update_partition.as_ref() }.unwrap();
 
-        Ok(())
+        partition_label(partition)
+            .try_into()
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())
     }
 
-    /// This function does not perform any flash operations, as flash writes are not cached and,
-    /// therefore, do not need to be flushed.
+    /// Writes an application-specific trailer - e.g. a signed manifest - right after the image,
+    /// within the update partition. It can be read back with `EspOta::read_trailer`.
+    ///
+    /// Must be called once the whole image is written, and before `finish` or `complete`.
     ///
     /// # Errors
     ///
-    /// Returns an error update partition is not valid.
-    pub fn flush(&mut self) -> Result<(), EspError> {
+    /// Returns `ESP_ERR_INVALID_STATE` if the data written so far is not exactly the image
+    /// (as declared by its headers), and `ESP_ERR_INVALID_SIZE` if the trailer does not fit
+    /// in the partition.
+    pub fn write_trailer(&mut self, data: &[u8]) -> Result<(), EspError> {
         self.check_write()?;
 
-        Ok(())
+        let partition = unsafe { self.update_partition.as_ref() }.unwrap();
+
+        if image_len(partition)? != self.written {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        if self.written + data.len() > partition.size as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        self.write(data)
     }
 
-    /// Finishes the OTA update and validates the new app image. Returns an instance of `EspOtaUpdateFinished`.
+    /// Returns the size of the partition being updated, i.e. an upper bound of the image size,
+    /// e.g. for progress reports when the size of the image is not known.
+    pub fn partition_size(&self) -> usize {
+        unsafe { self.update_partition.as_ref() }.map_or(0, |partition| partition.size as _)
+    }
+
+    /// Returns how many bytes can still be written to the partition being updated,
+    /// i.e. its size minus the bytes written so far.
     ///
-    /// <div class="warning">
-    /// This function does not update the boot partition. The user must call activate()
-    /// on the returned instance of EspOtaUpdateFinished.
-    /// </div>
+    /// When resuming an update, this tells whether the remaining part of the image fits.
+    pub fn remaining_space(&self) -> usize {
+        self.partition_size().saturating_sub(self.written)
+    }
+
+    /// Returns the offset in the image at which the next write goes, i.e. the number of bytes
+    /// of the image written so far.
     ///
-    /// See also: [`complete`](Self::complete)
-    pub fn finish(self) -> Result<EspOtaUpdateFinished<'a>, EspError> {
-        self.check_write()?;
+    /// For an update resumed with `EspOta::resume_from_checkpoint`, this is where the download
+    /// should continue from, e.g. with an HTTP range request.
+    pub fn offset(&self) -> usize {
+        self.written
+    }
 
-        esp!(unsafe { esp_ota_end(self.update_handle) })?;
-        let update_partition = self.update_partition;
+    /// Returns the write throughput in bytes per second, as measured over the most recent
+    /// sample of at least `THROUGHPUT_SAMPLE_INTERVAL`, or `None` until the first sample is complete.
+    ///
+    /// The time between writes is included, so this is the end-to-end rate of the update,
+    /// e.g. including the download of the image.
+    pub fn throughput(&self) -> Option<u32> {
+        self.throughput.last.map(|rate| rate as _)
+    }
 
-        // `Drop::drop` must not be called on `EspOtaUpdate` after the OTA handle has been
-        // invalidated.
-        mem::forget(self);
+    /// Returns the exponentially-weighted moving average of the write throughput samples
+    /// (see `throughput` and `THROUGHPUT_SMOOTHING`) in bytes per second, which is steadier
+    /// than the throughput of the most recent sample when the data is delivered in bursts.
+    pub fn smoothed_throughput(&self) -> Option<u32> {
+        self.throughput.smoothed.map(|rate| rate as _)
+    }
 
-        Ok(EspOtaUpdateFinished {
-            update_partition,
-            _data: PhantomData,
-        })
+    /// Returns the estimated time to write the rest of an image of `image_len` bytes,
+    /// based on the throughput of the most recent sample (see `throughput`).
+    ///
+    /// Returns `None` until the first sample is complete, or if nothing was written during it.
+    pub fn eta(&self, image_len: usize) -> Option<Duration> {
+        Throughput::eta(self.throughput.last, image_len.saturating_sub(self.written))
     }
 
-    /// Completes the OTA process by validating the new app image and updating the boot partition.
-    pub fn complete(self) -> Result<(), EspError> {
-        self.check_write()?;
+    /// Same as `eta`, but based on the smoothed throughput (see `smoothed_throughput`),
+    /// so that a displayed estimate does not jump with each chunk.
+    pub fn smoothed_eta(&self, image_len: usize) -> Option<Duration> {
+        Throughput::eta(
+            self.throughput.smoothed,
+            image_len.saturating_sub(self.written),
+        )
+    }
 
-        esp!(unsafe { esp_ota_end(self.update_handle) })?;
-        esp!(unsafe { esp_ota_set_boot_partition(self.update_partition) })?;
+    /// Periodically checkpoints the progress of this update in NVS, so that an update interrupted
+    /// e.g. by a power loss can be continued after a reboot with `EspOta::resume_from_checkpoint`,
+    /// instead of downloading the whole image again.
+    ///
+    /// The progress is saved every `interval` bytes in the `OTA_CHECKPOINT_NAMESPACE` namespace
+    /// of the default NVS partition, together with `image_id`, which identifies the image being
+    /// written (e.g. its version, or the ETag of the download), so that a checkpoint is never
+    /// resumed with a different image. As NVS writes wear the flash too, `interval` should be large,
+    /// e.g. 64 KB or more.
+    ///
+    /// The consistency guarantees are as follows:
+    /// - The saved offset is rounded down to a flash sector (`RECOMMENDED_WRITE_SIZE`), and it is
+    ///   saved only once all the data before it is written to flash, so the checkpoint never points
+    ///   beyond the data actually in flash. The data after the checkpoint is written again on resume.
+    /// - Failing to save a checkpoint does not fail the write: the error is logged and the previous
+    ///   checkpoint is kept.
+    /// - The checkpoint is removed when the update is finished, completed or cancelled with `abort`.
+    ///   It is kept when the update is just dropped (e.g. on a download error), so that it can be resumed.
+    /// - The checkpoint only records the progress, it does not protect the update slot. A checkpoint
+    ///   whose slot is no longer the next update slot, or no longer starts with an image header,
+    ///   is discarded on resume, but a slot overwritten by another update which was not checkpointed
+    ///   cannot always be detected, so `EspOta::clear_checkpoint` should be called before such an update.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if `interval` is 0, and `ESP_ERR_INVALID_SIZE` if `image_id`
+    /// is longer than `OTA_CHECKPOINT_MAX_ID_LEN` bytes.
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    pub fn enable_checkpoints(
+        &mut self,
+        nvs: EspDefaultNvsPartition,
+        image_id: &[u8],
+        interval: usize,
+    ) -> Result<(), EspError> {
+        self.check_write()?;
 
-        // `Drop::drop` must not be called on `EspOtaUpdate` after the OTA handle has been
-        // invalidated.
-        mem::forget(self);
+        self.checkpoint = Some(OtaCheckpoint::new(
+            OtaCheckpoint::open(nvs)?,
+            image_id,
+            interval,
+            self.written,
+        )?);
 
         Ok(())
     }
 
-    /// Cancels the update.
-    pub fn abort(self) -> Result<(), EspError> {
-        // The OTA update is aborted when `EspOtaUpdate` is dropped.
+    fn clear_checkpoint(&mut self) -> Result<(), EspError> {
+        #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+        if let Some(mut checkpoint) = self.checkpoint.take() {
+            checkpoint.clear()?;
+        }
+
         Ok(())
     }
 
-    fn check_write(&self) -> Result<(), EspError> {
-        if !self.update_partition.is_null() {
-            Ok(())
-        } else {
-            Err(EspError::from_infallible::<ESP_FAIL>())
-        }
+    /// Configures whether a warning should be logged when a write does not start
+    /// at a multiple of `RECOMMENDED_WRITE_SIZE`, i.e. when a previous write was not the last one,
+    /// but its size was not a multiple of `RECOMMENDED_WRITE_SIZE`.
+    ///
+    /// Unaligned writes are correct, but slower. By default, no warning is logged.
+    pub fn set_warn_unaligned(&mut self, warn_unaligned: bool) {
+        self.warn_unaligned = warn_unaligned;
     }
-}
 
-impl Drop for EspOtaUpdate<'_> {
-    fn drop(&mut self) {
-        // SAFETY: `esp_ota_abort` can only fail if the provided OTA handle is invalid.
-        //
-        // 1) The only safe way to acquire an `EspOtaUpdate` is through `EspOta::initiate_update`
-        //    which constructs the new instance using an OTA handle returned by `esp_ota_begin`.
-        // 2) The methods which invalidate the OTA handle all call `mem::forget(self)`.
-        //
-        // This means that our API guarantees that the OTA handle contained in this struct is valid
-        // and so calling this function will always be safe.
-        unsafe { esp_ota_abort(self.update_handle) };
+    /// Returns how many times the last write had to be retried.
+    pub fn last_write_retries(&self) -> u32 {
+        self.last_write_retries
     }
-}
 
-#[derive(Debug)]
-pub struct EspOtaUpdateFinished<'a> {
-    update_partition: *const esp_partition_t,
-    _data: PhantomData<&'a mut ()>,
-}
+    /// Writes OTA update data to partition.
+    /// This function can be called multiple times as data is received during the OTA operation.
+    /// Data is written sequentially to the partition.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if data could not be written to flash.
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self.check_write()?;
+
+        if self.warn_unaligned && self.written % RECOMMENDED_WRITE_SIZE != 0 {
+            warn!(
+                "Unaligned write at offset {}, use buffers of {} bytes for optimal throughput",
+                self.written, RECOMMENDED_WRITE_SIZE
+            );
+        }
+
+        self.last_write_retries = 0;
+
+        if !buf.is_empty() {
+            loop {
+                let result = esp!(unsafe {
+                    esp_ota_write(self.update_handle, buf.as_ptr() as _, buf.len() as _)
+                });
+
+                match result {
+                    Err(err)
+                        if self.last_write_retries < self.write_retries
+                            && Self::is_transient(err) =>
+                    {
+                        self.last_write_retries += 1;
+
+                        warn!(
+                            "Write failed: {err}, retrying ({}/{})",
+                            self.last_write_retries, self.write_retries
+                        );
+
+                        FreeRtos::delay_ms(self.write_retry_delay.as_millis() as _);
+                    }
+                    result => break result?,
+                }
+            }
+        }
+
+        self.written += buf.len();
+        self.last_write_len = buf.len();
+        self.throughput.update(buf.len());
+
+        #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            if let Err(err) = checkpoint.save(self.update_partition, self.written) {
+                warn!("Saving the OTA checkpoint failed: {err}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads back the data written by the most recent `write` call and compares it with `original`,
+    /// which should be the buffer passed to that call.
+    ///
+    /// Returns `false` if the data in flash differs from `original`.
+    /// As the data is read with `esp_partition_read`, it is transparently decrypted
+    /// if flash encryption is enabled. Note however that with flash encryption, `write` buffers
+    /// data which is not aligned to 16 bytes until the next `write`, so writes should be 16-byte aligned
+    /// for the verification to be meaningful.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if `original` does not have the length of the most recent write.
+    pub fn verify_last_write(&self, original: &[u8]) -> Result<bool, EspError> {
+        self.check_write()?;
+
+        if original.len() != self.last_write_len {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut offset = self.written - self.last_write_len;
+        let mut buf = [0_u8; 256];
+
+        for chunk in original.chunks(buf.len()) {
+            let buf = &mut buf[..chunk.len()];
+
+            esp!(unsafe {
+                esp_partition_read(
+                    self.update_partition,
+                    offset,
+                    buf.as_mut_ptr() as _,
+                    buf.len(),
+                )
+            })?;
+
+            if buf != chunk {
+                return Ok(false);
+            }
+
+            cooperative_yield(self.yield_interval, offset, offset + chunk.len());
+
+            offset += chunk.len();
+        }
+
+        Ok(true)
+    }
+
+    /// Makes sure that the `len` bytes at `offset` of the update partition are erased,
+    /// erasing the flash sectors which are not. Returns the number of sectors which had to be erased.
+    ///
+    /// Flash writes can only clear bits, so writing over data which was not erased silently
+    /// corrupts the image. `esp_ota_begin` erases the partition (or, for updates of a known size,
+    /// only the sectors needed by the image), but an update which is resumed or written beyond
+    /// the declared size might hit sectors which were only partially erased. Calling this before
+    /// writing such a region prevents this.
+    ///
+    /// Flash is erased by whole sectors of `RECOMMENDED_WRITE_SIZE` bytes, so `offset` and `len`
+    /// must both be multiples of that size. The region is checked by reading the raw
+    /// (i.e. possibly encrypted) flash contents, on which erased bytes always read as `0xff`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the region is not aligned to flash sectors,
+    /// `ESP_ERR_INVALID_SIZE` if it does not fit in the partition, and `ESP_ERR_INVALID_STATE`
+    /// if it overlaps the data written so far.
+    pub fn ensure_erased(&mut self, offset: usize, len: usize) -> Result<usize, EspError> {
+        self.check_write()?;
+
+        if offset % RECOMMENDED_WRITE_SIZE != 0 || len % RECOMMENDED_WRITE_SIZE != 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if offset + len > self.partition_size() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        if offset < self.written {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut erased = 0;
+        let mut buf = [0_u8; 256];
+
+        for sector in (offset..offset + len).step_by(RECOMMENDED_WRITE_SIZE) {
+            let mut clean = true;
+
+            for chunk in (sector..sector + RECOMMENDED_WRITE_SIZE).step_by(buf.len()) {
+                esp!(unsafe {
+                    esp_partition_read_raw(
+                        self.update_partition,
+                        chunk,
+                        buf.as_mut_ptr() as _,
+                        buf.len(),
+                    )
+                })?;
+
+                if buf.iter().any(|byte| *byte != 0xff) {
+                    clean = false;
+                    break;
+                }
+            }
+
+            if !clean {
+                esp!(unsafe {
+                    esp_partition_erase_range(self.update_partition, sector, RECOMMENDED_WRITE_SIZE)
+                })?;
+
+                erased += 1;
+            }
+
+            cooperative_yield(self.yield_interval, sector, sector + RECOMMENDED_WRITE_SIZE);
+        }
+
+        if erased > 0 {
+            warn!(
+                "Erased {erased} sector(s) which were not erased in range {offset}..{}",
+                offset + len
+            );
+        }
+
+        Ok(erased)
+    }
+
+    /// Configures whether the data written to the update partition should be erased when the image
+    /// fails the validation done by `finish` and `complete`.
+    ///
+    /// This leaves a clean slate for the next attempt, and prevents `EspOta::get_update_slot` from
+    /// reporting the firmware info of the invalid image. Only the flash sectors which were written
+    /// are erased. By default, the invalid image is kept.
+    pub fn set_erase_on_failed_validation(&mut self, erase: bool) {
+        self.erase_on_failed_validation = erase;
+    }
+
+    /// Ends the update, validating the image.
+    fn end(&self) -> Result<(), EspError> {
+        let result = esp!(unsafe { esp_ota_end(self.update_handle) });
+
+        if let Err(err) = result {
+            if err.code() == ESP_ERR_OTA_VALIDATE_FAILED && self.erase_on_failed_validation {
+                let len = min(
+                    self.written.div_ceil(RECOMMENDED_WRITE_SIZE) * RECOMMENDED_WRITE_SIZE,
+                    self.partition_size(),
+                );
+
+                warn!("Image validation failed, erasing {len} bytes of the update partition");
+
+                if let Err(err) =
+                    esp!(unsafe { esp_partition_erase_range(self.update_partition, 0, len) })
+                {
+                    warn!("Erasing the update partition failed: {err}");
+                }
+            }
+        }
+
+        result
+    }
+
+    fn is_transient(err: EspError) -> bool {
+        // With flash encryption, a failed write might have already buffered part of the data
+        // in the update handle, so writing the same data again would duplicate it
+        matches!(
+            err.code(),
+            ESP_ERR_FLASH_OP_FAIL | ESP_ERR_FLASH_OP_TIMEOUT | ESP_ERR_TIMEOUT
+        ) && !unsafe { esp_flash_encryption_enabled() }
+    }
+
+    /// This function does not perform any flash operations, as flash writes are not cached and,
+    /// therefore, do not need to be flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error update partition is not valid.
+    pub fn flush(&mut self) -> Result<(), EspError> {
+        self.check_write()?;
+
+        Ok(())
+    }
+
+    /// Finishes the OTA update and validates the new app image. Returns an instance of `EspOtaUpdateFinished`.
+    ///
+    /// <div class="warning">
+    /// This function does not update the boot partition. The user must call activate()
+    /// on the returned instance of EspOtaUpdateFinished, or - at a later time - call
+    /// `EspOta::activate_slot` with the label of the updated slot.
+    /// </div>
+    ///
+    /// See also: [`complete`](Self::complete)
+    pub fn finish(mut self) -> Result<EspOtaUpdateFinished<'a>, EspError> {
+        self.check_write()?;
+
+        self.end()?;
+
+        // A checkpoint left behind is rejected by `EspOta::resume_from_checkpoint` anyway, so failing
+        // to remove it must not fail - and therefore abort - an update whose image is complete
+        if let Err(err) = self.clear_checkpoint() {
+            warn!("Removing the checkpoint failed: {err}");
+        }
+
+        let update_partition = self.update_partition;
+
+        // `Drop::drop` must not be called on `EspOtaUpdate` after the OTA handle has been
+        // invalidated.
+        mem::forget(self);
+
+        *UPDATING.lock() = false;
+
+        Ok(EspOtaUpdateFinished {
+            update_partition,
+            _data: PhantomData,
+        })
+    }
+
+    /// Completes the OTA process by validating the new app image and updating the boot partition.
+    pub fn complete(self) -> Result<(), EspError> {
+        self.complete_with(|_| ())
+    }
+
+    /// Same as [`complete`](Self::complete), but calls `on_commit` with the label of the updated slot
+    /// once the boot partition is set, before returning.
+    ///
+    /// This is a guaranteed hook point for e.g. an audit record of the switch of the boot partition:
+    /// `on_commit` is called if and only if the update is committed.
+    pub fn complete_with<F>(mut self, on_commit: F) -> Result<(), EspError>
+    where
+        F: FnOnce(&str),
+    {
+        self.check_write()?;
+
+        self.end()?;
+
+        // Only logged, see `finish`
+        if let Err(err) = self.clear_checkpoint() {
+            warn!("Removing the checkpoint failed: {err}");
+        }
+
+        let update_partition = self.update_partition;
+
+        // `Drop::drop` must not be called on `EspOtaUpdate` after the OTA handle has been
+        // invalidated.
+        mem::forget(self);
+
+        *UPDATING.lock() = false;
+
+        esp!(unsafe { esp_ota_set_boot_partition(update_partition) })?;
+
+        on_commit(partition_label(unsafe { &*update_partition }));
+
+        Ok(())
+    }
+
+    /// Same as [`complete`](Self::complete), but additionally reads back the committed state
+    /// from flash, and only returns once it is confirmed to be there, so that the device
+    /// can be rebooted immediately after.
+    ///
+    /// The durability itself is provided by the ESP IDF flash driver: `esp_flash_write` and
+    /// `esp_flash_erase_region` (used by `esp_ota_write` and by `esp_ota_set_boot_partition`
+    /// for the otadata partition) only return once the flash chip reports the operation as done
+    /// (i.e. once its WIP status bit is cleared), and the flash cache is never used for writes.
+    /// There is therefore no additional barrier or cache flush to perform. This method confirms,
+    /// with uncached `esp_partition_read`s, that:
+    /// - the image header of the updated partition is in place (`esp_ota_end` already verified the whole image);
+    /// - the otadata selects the updated partition as the boot partition.
+    ///
+    /// Fails with `ESP_ERR_OTA_VALIDATE_FAILED` if either cannot be confirmed.
+    pub fn complete_durable(self) -> Result<(), EspError> {
+        let update_partition = self.update_partition;
+
+        self.complete()?;
+
+        let mut magic = 0_u8;
+        esp!(unsafe {
+            esp_partition_read(update_partition, 0, &mut magic as *mut u8 as *mut _, 1)
+        })?;
+
+        if magic != ESP_IMAGE_HEADER_MAGIC as u8 {
+            return Err(EspError::from_infallible::<ESP_ERR_OTA_VALIDATE_FAILED>());
+        }
+
+        let boot = unsafe { esp_ota_get_boot_partition() };
+
+        if boot.is_null() || unsafe { (*boot).address != (*update_partition).address } {
+            return Err(EspError::from_infallible::<ESP_ERR_OTA_VALIDATE_FAILED>());
+        }
+
+        Ok(())
+    }
+
+    /// Cancels the update.
+    ///
+    /// The checkpoint of the update, if any, is removed too (see `enable_checkpoints`).
+    pub fn abort(mut self) -> Result<(), EspError> {
+        self.clear_checkpoint()?;
+
+        // The OTA update is aborted when `EspOtaUpdate` is dropped.
+        Ok(())
+    }
+
+    /// Converts this update into an update where each chunk of `chunk_size` bytes is verified
+    /// against the corresponding SHA-256 hash in `chunk_hashes` before being written.
+    ///
+    /// See [`EspOtaChunkedUpdate`] for the contract with the caller.
+    #[cfg(esp_idf_comp_mbedtls_enabled)]
+    pub fn into_chunked<'h>(
+        self,
+        chunk_size: usize,
+        chunk_hashes: &'h [[u8; 32]],
+    ) -> Result<EspOtaChunkedUpdate<'a, 'h>, EspError> {
+        if chunk_size == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(EspOtaChunkedUpdate {
+            update: self,
+            chunk_size,
+            chunk_hashes,
+            next_chunk: 0,
+        })
+    }
+
+    /// Returns a writer which decodes hex or base64 text on the fly, and writes the decoded
+    /// bytes to this update. See [`EspOtaTextWriter`].
+    pub fn text_writer(&mut self, encoding: TextEncoding) -> EspOtaTextWriter<'_, 'a> {
+        EspOtaTextWriter {
+            update: self,
+            encoding,
+            bits: 0,
+            pending: 0,
+            padded: false,
+            position: 0,
+            buf: [0; TEXT_WRITER_BUF_LEN],
+            len: 0,
+            error: None,
+        }
+    }
+
+    /// Returns a decompressor which inflates a compressed image and writes the decompressed
+    /// data to this update, see `EspOtaDecompressor`.
+    #[cfg(feature = "ota-compression")]
+    pub fn decompressor(&mut self, format: Compression) -> EspOtaDecompressor<'_, 'a> {
+        EspOtaDecompressor {
+            update: self,
+            format,
+            inflate: miniz_oxide::inflate::stream::InflateState::new_boxed(match format {
+                Compression::Zlib => miniz_oxide::DataFormat::Zlib,
+                Compression::Gzip => miniz_oxide::DataFormat::Raw,
+            }),
+            buf: alloc::vec![0; RECOMMENDED_WRITE_SIZE],
+            header: alloc::vec::Vec::new(),
+            header_len: None,
+            trailer: heapless::Vec::new(),
+            crc: 0,
+            decompressed: 0,
+            done: false,
+        }
+    }
+
+    fn check_write(&self) -> Result<(), EspError> {
+        if !self.update_partition.is_null() {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        }
+    }
+}
+
+impl Drop for EspOtaUpdate<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `esp_ota_abort` can only fail if the provided OTA handle is invalid.
+        //
+        // 1) The only safe way to acquire an `EspOtaUpdate` is through `EspOta::initiate_update`
+        //    which constructs the new instance using an OTA handle returned by `esp_ota_begin`.
+        // 2) The methods which invalidate the OTA handle all call `mem::forget(self)`.
+        //
+        // This means that our API guarantees that the OTA handle contained in this struct is valid
+        // and so calling this function will always be safe.
+        unsafe { esp_ota_abort(self.update_handle) };
+
+        *UPDATING.lock() = false;
+    }
+}
+
+/// The NVS namespace - in the default NVS partition - in which the checkpoint of an update
+/// is stored, see `EspOtaUpdate::enable_checkpoints`.
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub const OTA_CHECKPOINT_NAMESPACE: &str = "esp_ota_ckpt";
+
+/// The maximum length of the image ID of a checkpoint, see `EspOtaUpdate::enable_checkpoints`.
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub const OTA_CHECKPOINT_MAX_ID_LEN: usize = 64;
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+const OTA_CHECKPOINT_KEY: &str = "checkpoint";
+
+/// The checkpoint blob: the address of the update slot and the offset, both as LE `u32`s,
+/// followed by the image ID.
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+const OTA_CHECKPOINT_LEN: usize = 8 + OTA_CHECKPOINT_MAX_ID_LEN;
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+struct OtaCheckpoint {
+    nvs: EspNvs<NvsDefault>,
+    image_id: heapless::Vec<u8, OTA_CHECKPOINT_MAX_ID_LEN>,
+    interval: usize,
+    offset: usize,
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+impl OtaCheckpoint {
+    fn open(nvs: EspDefaultNvsPartition) -> Result<EspNvs<NvsDefault>, EspError> {
+        EspNvs::new(nvs, OTA_CHECKPOINT_NAMESPACE, true)
+    }
+
+    fn new(
+        nvs: EspNvs<NvsDefault>,
+        image_id: &[u8],
+        interval: usize,
+        offset: usize,
+    ) -> Result<Self, EspError> {
+        if interval == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self {
+            nvs,
+            image_id: heapless::Vec::from_slice(image_id)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?,
+            interval,
+            offset,
+        })
+    }
+
+    /// Loads the saved checkpoint, as the address of the update slot, the offset and the image ID.
+    fn load(
+        nvs: &EspNvs<NvsDefault>,
+    ) -> Result<Option<(u32, usize, heapless::Vec<u8, OTA_CHECKPOINT_MAX_ID_LEN>)>, EspError> {
+        let mut buf = [0_u8; OTA_CHECKPOINT_LEN];
+
+        let Some(data) = nvs.get_blob(OTA_CHECKPOINT_KEY, &mut buf)? else {
+            return Ok(None);
+        };
+
+        if data.len() < 8 {
+            return Ok(None);
+        }
+
+        let address = u32::from_le_bytes(data[..4].try_into().unwrap());
+        let offset = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+
+        Ok(Some((
+            address,
+            offset,
+            heapless::Vec::from_slice(&data[8..]).unwrap(),
+        )))
+    }
+
+    /// Saves `written` - rounded down to a flash sector - if it is at least `interval` bytes
+    /// after the previous checkpoint.
+    fn save(&mut self, partition: *const esp_partition_t, written: usize) -> Result<(), EspError> {
+        let offset = written / RECOMMENDED_WRITE_SIZE * RECOMMENDED_WRITE_SIZE;
+
+        if offset < self.offset + self.interval {
+            return Ok(());
+        }
+
+        let len = 8 + self.image_id.len();
+        let mut buf = [0_u8; OTA_CHECKPOINT_LEN];
+
+        buf[..4].copy_from_slice(&unsafe { (*partition).address }.to_le_bytes());
+        buf[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+        buf[8..len].copy_from_slice(&self.image_id);
+
+        self.nvs.set_blob(OTA_CHECKPOINT_KEY, &buf[..len])?;
+
+        self.offset = offset;
+
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), EspError> {
+        self.nvs.remove(OTA_CHECKPOINT_KEY)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+impl core::fmt::Debug for OtaCheckpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("OtaCheckpoint")
+            .field("image_id", &self.image_id)
+            .field("interval", &self.interval)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+/// The NVS namespace - in the default NVS partition - in which the boot counter of the boot guard
+/// is stored, see `EspOta::with_boot_guard`.
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub const OTA_BOOT_GUARD_NAMESPACE: &str = "esp_ota_guard";
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+const OTA_BOOT_GUARD_KEY: &str = "failed_boots";
+
+/// Whether the boot counter was already incremented during this boot.
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+static BOOT_COUNTED: mutex::Mutex<bool> = mutex::Mutex::new(false);
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+struct BootGuard {
+    nvs: EspNvs<NvsDefault>,
+    max_failed_boots: u32,
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+impl BootGuard {
+    /// Counts this boot as failed - until `reset` is called - and returns the number of
+    /// consecutive failed boots, including this one.
+    fn count(&self) -> Result<u32, EspError> {
+        let mut counted = BOOT_COUNTED.lock();
+
+        let failed_boots = self.nvs.get_u32(OTA_BOOT_GUARD_KEY)?.unwrap_or(0);
+
+        if *counted {
+            return Ok(failed_boots);
+        }
+
+        let failed_boots = failed_boots.saturating_add(1);
+
+        self.nvs.set_u32(OTA_BOOT_GUARD_KEY, failed_boots)?;
+
+        *counted = true;
+
+        Ok(failed_boots)
+    }
+
+    fn reset(&self) -> Result<(), EspError> {
+        self.nvs.set_u32(OTA_BOOT_GUARD_KEY, 0)
+    }
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+impl core::fmt::Debug for BootGuard {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BootGuard")
+            .field("max_failed_boots", &self.max_failed_boots)
+            .finish()
+    }
+}
+
+/// An OTA update where each chunk of the image is verified against a manifest of per-chunk
+/// SHA-256 hashes before being written to flash.
+///
+/// The contract with the caller is as follows:
+/// - The image is split into chunks of exactly `chunk_size` bytes, except for the last one,
+///   which may be shorter. The manifest contains the SHA-256 hash of each chunk, in order.
+/// - Chunks must be written in order, one chunk per `write_chunk` call.
+/// - A chunk which does not match its hash is not written, and
+///   `EspOtaChunkError::HashMismatch` is returned with the index of that chunk.
+///   The caller is expected to re-request that same chunk and write it again,
+///   instead of restarting the whole download.
+///
+/// Once all chunks are written, the update is finalized with `finish` or `complete`.
+/// The update is aborted when dropped.
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+pub struct EspOtaChunkedUpdate<'a, 'h> {
+    update: EspOtaUpdate<'a>,
+    chunk_size: usize,
+    chunk_hashes: &'h [[u8; 32]],
+    next_chunk: usize,
+}
+
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+impl<'a> EspOtaChunkedUpdate<'a, '_> {
+    /// Returns the index of the next chunk expected by `write_chunk`.
+    pub fn next_chunk_index(&self) -> usize {
+        self.next_chunk
+    }
+
+    /// Returns the total number of chunks in the manifest.
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_hashes.len()
+    }
+
+    /// Returns the underlying update, e.g. to configure write retries.
+    pub fn update(&mut self) -> &mut EspOtaUpdate<'a> {
+        &mut self.update
+    }
+
+    /// Verifies the next chunk against its hash from the manifest and - if it matches - writes it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EspOtaChunkError::HashMismatch` if the chunk does not match its hash, in which case
+    /// nothing is written and the same chunk should be written again.
+    /// Returns `ESP_ERR_INVALID_SIZE` if all chunks were already written, or if the chunk has a wrong size.
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), EspOtaChunkError> {
+        let index = self.next_chunk;
+
+        let Some(expected) = self.chunk_hashes.get(index) else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?
+        };
+
+        let last = index + 1 == self.chunk_hashes.len();
+
+        if chunk.is_empty()
+            || chunk.len() > self.chunk_size
+            || !last && chunk.len() != self.chunk_size
+        {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+        }
+
+        let actual = sha256(chunk)?;
+
+        if actual != *expected {
+            warn!("Chunk {index} does not match its hash");
+
+            return Err(EspOtaChunkError::HashMismatch(index));
+        }
+
+        self.update.write(chunk)?;
+        self.next_chunk += 1;
+
+        Ok(())
+    }
+
+    /// Finishes the update, see `EspOtaUpdate::finish`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_STATE` if not all chunks were written yet.
+    pub fn finish(self) -> Result<EspOtaUpdateFinished<'a>, EspError> {
+        self.check_complete()?;
+
+        self.update.finish()
+    }
+
+    /// Completes the update, see `EspOtaUpdate::complete`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_STATE` if not all chunks were written yet.
+    pub fn complete(self) -> Result<(), EspError> {
+        self.check_complete()?;
+
+        self.update.complete()
+    }
+
+    /// Cancels the update.
+    pub fn abort(self) -> Result<(), EspError> {
+        self.update.abort()
+    }
+
+    fn check_complete(&self) -> Result<(), EspError> {
+        if self.next_chunk == self.chunk_hashes.len() {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+        }
+    }
+}
+
+/// The error returned by `EspOtaChunkedUpdate::write_chunk`.
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EspOtaChunkError {
+    /// The chunk with the contained index does not match its hash and should be re-requested.
+    HashMismatch(usize),
+    /// Writing the chunk failed.
+    Esp(EspError),
+}
+
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+impl From<EspError> for EspOtaChunkError {
+    fn from(e: EspError) -> Self {
+        Self::Esp(e)
+    }
+}
+
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+impl core::fmt::Display for EspOtaChunkError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::HashMismatch(index) => write!(f, "Chunk {index} does not match its hash"),
+            Self::Esp(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[cfg(all(feature = "std", esp_idf_comp_mbedtls_enabled))]
+impl std::error::Error for EspOtaChunkError {}
+
+/// The text encoding of an image written with an [`EspOtaTextWriter`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TextEncoding {
+    /// Hexadecimal digits, in either case.
+    Hex,
+    /// Standard base64 (RFC 4648), with or without `=` padding.
+    Base64,
+}
+
+const TEXT_WRITER_BUF_LEN: usize = 512;
+
+/// A writer which decodes a hex or base64 encoded image on the fly, e.g. as received over
+/// a serial console, and writes the decoded bytes to the update.
+///
+/// Text can be pushed in pieces of any size with `push_encoded`, or with `core::fmt::Write`.
+/// Whitespace (e.g. line breaks) is ignored. The decoded bytes are buffered, and written
+/// to flash in blocks; `finish` must be called once all the text is pushed, to check that
+/// the text is complete and to write the remaining bytes. The update can then be finished
+/// or completed as usual.
+pub struct EspOtaTextWriter<'u, 'a> {
+    update: &'u mut EspOtaUpdate<'a>,
+    encoding: TextEncoding,
+    bits: u32,
+    pending: u8,
+    padded: bool,
+    position: usize,
+    buf: [u8; TEXT_WRITER_BUF_LEN],
+    len: usize,
+    error: Option<EspOtaTextError>,
+}
+
+impl EspOtaTextWriter<'_, '_> {
+    /// Decodes `text` and writes the decoded bytes to the update.
+    ///
+    /// A decoding error is reported with the position of the offending character in the whole text
+    /// pushed so far, and is distinct from a flash error.
+    pub fn push_encoded(&mut self, text: &str) -> Result<(), EspOtaTextError> {
+        for ch in text.chars() {
+            let position = self.position;
+            self.position += 1;
+
+            if ch.is_ascii_whitespace() {
+                continue;
+            }
+
+            let invalid = EspOtaTextError::InvalidChar { position, ch };
+
+            let (value, width) = match self.encoding {
+                TextEncoding::Hex => (ch.to_digit(16).ok_or(invalid)?, 4),
+                TextEncoding::Base64 => {
+                    if ch == '=' {
+                        self.padded = true;
+                        continue;
+                    }
+
+                    if self.padded {
+                        Err(invalid)?;
+                    }
+
+                    let value = match ch {
+                        'A'..='Z' => ch as u32 - 'A' as u32,
+                        'a'..='z' => ch as u32 - 'a' as u32 + 26,
+                        '0'..='9' => ch as u32 - '0' as u32 + 52,
+                        '+' => 62,
+                        '/' => 63,
+                        _ => Err(invalid)?,
+                    };
+
+                    (value, 6)
+                }
+            };
+
+            self.bits = (self.bits << width) | value;
+            self.pending += width;
+
+            if self.pending >= 8 {
+                self.pending -= 8;
+
+                self.buf[self.len] = (self.bits >> self.pending) as u8;
+                self.len += 1;
+
+                self.bits &= (1 << self.pending) - 1;
+
+                if self.len == self.buf.len() {
+                    self.flush()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the pushed text is complete, i.e. that it does not end in the middle
+    /// of an encoded byte, and writes the remaining decoded bytes.
+    pub fn finish(mut self) -> Result<(), EspOtaTextError> {
+        if let Some(error) = self.error.take() {
+            Err(error)?;
+        }
+
+        // Base64 leaves 2 or 4 bits of padding after the last byte, but never 6
+        let complete = match self.encoding {
+            TextEncoding::Hex => self.pending == 0,
+            TextEncoding::Base64 => self.pending != 6 && self.bits == 0,
+        };
+
+        if !complete {
+            Err(EspOtaTextError::Truncated)?;
+        }
+
+        self.flush()
+    }
+
+    /// Returns and clears the error which caused a `core::fmt::Write` call to fail, if any.
+    pub fn take_error(&mut self) -> Option<EspOtaTextError> {
+        self.error.take()
+    }
+
+    fn flush(&mut self) -> Result<(), EspOtaTextError> {
+        let len = core::mem::replace(&mut self.len, 0);
+
+        self.update.write(&self.buf[..len])?;
+
+        Ok(())
+    }
+}
+
+/// Failures are reported as `core::fmt::Error`; the actual error is available with `take_error`.
+impl core::fmt::Write for EspOtaTextWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_encoded(s).map_err(|error| {
+            self.error = Some(error);
+
+            core::fmt::Error
+        })
+    }
+}
+
+/// The error returned by `EspOtaTextWriter`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EspOtaTextError {
+    /// The character at the contained position is not valid for the encoding.
+    InvalidChar { position: usize, ch: char },
+    /// The text ends in the middle of an encoded byte.
+    Truncated,
+    /// Writing the decoded bytes to flash failed.
+    Esp(EspError),
+}
+
+impl From<EspError> for EspOtaTextError {
+    fn from(e: EspError) -> Self {
+        Self::Esp(e)
+    }
+}
+
+impl core::fmt::Display for EspOtaTextError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidChar { position, ch } => {
+                write!(f, "Invalid character {ch:?} at position {position}")
+            }
+            Self::Truncated => write!(f, "The encoded text is truncated"),
+            Self::Esp(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EspOtaTextError {}
+
+/// The compression format of an image written with `EspOtaDecompressor`.
+#[cfg(feature = "ota-compression")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// A zlib stream (RFC 1950), as produced by e.g. `pigz -z`.
+    Zlib,
+    /// A gzip file (RFC 1952), as produced by `gzip`.
+    Gzip,
+}
+
+/// A decompressor inflating a zlib- or gzip-compressed image into an `EspOtaUpdate`, as returned
+/// by `EspOtaUpdate::decompressor`.
+///
+/// The compressed image can be passed to `write_compressed` in chunks of any size, and the
+/// decompressed data is written to the update in chunks of `RECOMMENDED_WRITE_SIZE` bytes.
+/// Once the whole compressed image is written, `finish` checks that the compressed stream
+/// is complete (and for gzip, that its length and CRC32 match), before the update is completed.
+///
+/// The decompressor allocates about 45 KB on the heap, mostly for the 32 KB window of the
+/// deflate algorithm, so that it can be used from tasks with small stacks.
+///
+/// Requires the `ota-compression` feature.
+#[cfg(feature = "ota-compression")]
+pub struct EspOtaDecompressor<'u, 'a> {
+    update: &'u mut EspOtaUpdate<'a>,
+    format: Compression,
+    inflate: alloc::boxed::Box<miniz_oxide::inflate::stream::InflateState>,
+    buf: alloc::vec::Vec<u8>,
+    header: alloc::vec::Vec<u8>,
+    header_len: Option<usize>,
+    trailer: heapless::Vec<u8, 8>,
+    crc: u32,
+    decompressed: usize,
+    done: bool,
+}
+
+#[cfg(feature = "ota-compression")]
+impl EspOtaDecompressor<'_, '_> {
+    /// Decompresses the next chunk of the compressed image, and writes the decompressed data
+    /// to the update.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EspOtaDecompressError::Corrupted` if the chunk is not valid compressed data,
+    /// and `EspOtaDecompressError::Esp` if writing the decompressed data failed.
+    pub fn write_compressed(&mut self, mut chunk: &[u8]) -> Result<(), EspOtaDecompressError> {
+        if self.format == Compression::Gzip && self.header_len.is_none() {
+            // The header of a gzip file has a variable length, so accumulate it until it is complete
+            self.header.extend_from_slice(chunk);
+
+            let Some(len) = gzip_header_len(&self.header)? else {
+                return Ok(());
+            };
+
+            let header = mem::take(&mut self.header);
+            self.header_len = Some(len);
+
+            return self.write_compressed(&header[len..]);
+        }
+
+        while !self.done {
+            let result = miniz_oxide::inflate::stream::inflate(
+                &mut self.inflate,
+                chunk,
+                &mut self.buf,
+                miniz_oxide::MZFlush::None,
+            );
+
+            chunk = &chunk[result.bytes_consumed..];
+
+            if result.bytes_written > 0 {
+                let data = &self.buf[..result.bytes_written];
+
+                self.update.write(data)?;
+
+                self.crc = unsafe { esp_rom_crc32_le(self.crc, data.as_ptr(), data.len() as _) };
+                self.decompressed += data.len();
+            }
+
+            match result.status {
+                Ok(miniz_oxide::MZStatus::StreamEnd) => self.done = true,
+                Ok(_) => (),
+                // No progress possible without more input
+                Err(miniz_oxide::MZError::Buf) => break,
+                Err(_) => Err(EspOtaDecompressError::Corrupted)?,
+            }
+
+            if chunk.is_empty() && result.bytes_written < self.buf.len() {
+                break;
+            }
+        }
+
+        if self.done && self.format == Compression::Gzip {
+            let len = min(chunk.len(), self.trailer.capacity() - self.trailer.len());
+
+            self.trailer.extend_from_slice(&chunk[..len]).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of decompressed bytes written to the update so far.
+    pub fn decompressed(&self) -> usize {
+        self.decompressed
+    }
+
+    /// Checks that the whole compressed image was decompressed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EspOtaDecompressError::Truncated` if the compressed stream is incomplete,
+    /// and `EspOtaDecompressError::Corrupted` if the length or the CRC32 recorded in the
+    /// trailer of a gzip file do not match the decompressed data.
+    pub fn finish(self) -> Result<(), EspOtaDecompressError> {
+        if !self.done {
+            Err(EspOtaDecompressError::Truncated)?;
+        }
+
+        if self.format == Compression::Gzip {
+            if !self.trailer.is_full() {
+                Err(EspOtaDecompressError::Truncated)?;
+            }
+
+            let crc = u32::from_le_bytes(self.trailer[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(self.trailer[4..].try_into().unwrap());
+
+            if crc != self.crc || len != self.decompressed as u32 {
+                Err(EspOtaDecompressError::Corrupted)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the length of the gzip header at the start of `data`,
+/// or `None` if `data` does not contain the whole header yet.
+#[cfg(feature = "ota-compression")]
+fn gzip_header_len(data: &[u8]) -> Result<Option<usize>, EspOtaDecompressError> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if data.len() < 10 {
+        return Ok(None);
+    }
+
+    // Magic number, and deflate as the compression method
+    if data[..3] != [0x1f, 0x8b, 8] {
+        Err(EspOtaDecompressError::Corrupted)?;
+    }
+
+    let flags = data[3];
+    let mut len = 10;
+
+    if flags & FEXTRA != 0 {
+        let Some(extra) = data.get(len..len + 2) else {
+            return Ok(None);
+        };
+
+        len += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // A NUL-terminated string
+            let Some(end) = data.get(len..).and_then(|s| s.iter().position(|b| *b == 0)) else {
+                return Ok(None);
+            };
+
+            len += end + 1;
+        }
+    }
+
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+
+    Ok((data.len() >= len).then_some(len))
+}
+
+/// The error returned by `EspOtaDecompressor`.
+#[cfg(feature = "ota-compression")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EspOtaDecompressError {
+    /// The compressed data is invalid.
+    Corrupted,
+    /// The compressed data ended before the end of the compressed stream.
+    Truncated,
+    /// Writing the decompressed data to the update failed.
+    Esp(EspError),
+}
+
+#[cfg(feature = "ota-compression")]
+impl From<EspError> for EspOtaDecompressError {
+    fn from(error: EspError) -> Self {
+        Self::Esp(error)
+    }
+}
+
+#[cfg(feature = "ota-compression")]
+impl core::fmt::Display for EspOtaDecompressError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Corrupted => write!(f, "Corrupted compressed data"),
+            Self::Truncated => write!(f, "Truncated compressed data"),
+            Self::Esp(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+#[cfg(all(feature = "ota-compression", feature = "std"))]
+impl std::error::Error for EspOtaDecompressError {}
+
+/// The length of a secure boot V2 signature block.
+const SIG_BLOCK_LEN: usize = 1216;
+
+/// The magic byte at the start of a secure boot V2 signature block.
+const SIG_BLOCK_MAGIC: u8 = 0xe7;
+
+/// The offset of the CRC32 of a secure boot V2 signature block, which covers the preceding bytes.
+const SIG_BLOCK_CRC_OFFSET: usize = 1196;
+
+/// The secure boot V2 signature sector of an app image, as returned by `EspOta::read_signature_block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecureBootSig {
+    /// The offset of the signature sector in the app partition, from which the raw signature blocks
+    /// (of 1216 bytes each) can be read.
+    pub offset: usize,
+    /// The signature blocks of the sector, i.e. one block per signing key (up to three).
+    pub blocks: heapless::Vec<SecureBootSigBlock, 3>,
+}
+
+/// A secure boot V2 signature block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecureBootSigBlock {
+    /// The version of the block, i.e. the signature scheme: 2 for RSA-PSS, 3 for ECDSA.
+    pub version: u8,
+    /// The SHA-256 digest of the image covered by the signature.
+    pub image_digest: [u8; 32],
+    /// Whether the CRC32 of the block matches its contents.
+    pub crc_valid: bool,
+}
+
+/// A raw entry of the OTA data partition, as returned by `EspOta::dump_otadata`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OtaDataEntry {
+    /// The OTA sequence number. The bootloader boots the OTA slot `(seq - 1) % <number of OTA slots>`
+    /// of the valid entry with the highest sequence number.
+    pub seq: u32,
+    /// The raw image state, one of the `esp_ota_img_states_t` values.
+    pub state: esp_ota_img_states_t,
+    /// The CRC32 of the sequence number.
+    pub crc: u32,
+    /// Whether the entry is valid, i.e. not erased and with a matching CRC.
+    pub valid: bool,
+}
+
+impl OtaDataEntry {
+    fn parse(raw: &[u8; 32]) -> Self {
+        let word = |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        let seq = word(0);
+        let state = word(24);
+        let crc = word(28);
+
+        let valid =
+            seq != u32::MAX && crc == unsafe { esp_rom_crc32_le(u32::MAX, raw.as_ptr(), 4) };
+
+        Self {
+            seq,
+            state,
+            crc,
+            valid,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EspOtaUpdateFinished<'a> {
+    update_partition: *const esp_partition_t,
+    _data: PhantomData<&'a mut ()>,
+}
+
+impl EspOtaUpdateFinished<'_> {
+    /// Sets the boot partition to the newly updated app partition.
+    /// The app will be run on the next boot.
+    pub fn activate(self) -> Result<(), EspError> {
+        esp!(unsafe { esp_ota_set_boot_partition(self.update_partition) })
+    }
+
+    /// Stages the update for a two-phase commit: instead of activating it, returns a token
+    /// identifying the updated slot and the SHA-256 of its image, to be sent to e.g. a fleet
+    /// orchestration server. The update is activated only once the token comes back,
+    /// with `EspOta::commit_staged`.
+    pub fn stage(self) -> Result<StageToken, EspError> {
+        let partition = unsafe { &*self.update_partition };
+
+        let mut digest = [0; 32];
+        esp!(unsafe { esp_partition_get_sha256(partition, digest.as_mut_ptr()) })?;
+
+        Ok(StageToken {
+            label: partition_label(partition).try_into().unwrap(),
+            digest,
+        })
+    }
+}
+
+/// The length of the serialized `StageToken`, see `StageToken::to_bytes`.
+pub const STAGE_TOKEN_LEN: usize = 16 + 32;
+
+/// A token identifying a staged update, see `EspOtaUpdateFinished::stage`.
+///
+/// The token holds the label of the updated slot and the SHA-256 of its image, so that
+/// `EspOta::commit_staged` rejects a token for another image, or a stale token whose slot
+/// was overwritten since.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StageToken {
+    label: heapless::String<16>,
+    digest: [u8; 32],
+}
+
+impl StageToken {
+    /// Returns the label of the staged slot.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns the SHA-256 of the image of the staged slot.
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+
+    /// Serializes the token, as the label padded with zeroes to 16 bytes, followed by the digest.
+    pub fn to_bytes(&self) -> [u8; STAGE_TOKEN_LEN] {
+        let mut bytes = [0; STAGE_TOKEN_LEN];
+
+        bytes[..self.label.len()].copy_from_slice(self.label.as_bytes());
+        bytes[16..].copy_from_slice(&self.digest);
+
+        bytes
+    }
+
+    /// Deserializes a token serialized with `to_bytes`, or returns `None` if `bytes` is not
+    /// a serialized token.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; STAGE_TOKEN_LEN] = bytes.try_into().ok()?;
+
+        let len = bytes[..16].iter().position(|b| *b == 0).unwrap_or(16);
+        let label = core::str::from_utf8(&bytes[..len]).ok()?;
+
+        Some(Self {
+            label: label.try_into().ok()?,
+            digest: bytes[16..].try_into().unwrap(),
+        })
+    }
+}
+
+/// The health of the running firmware, as returned by `EspOta::boot_health`.
+#[derive(Debug, Clone)]
+pub enum BootHealth {
+    /// The running firmware is valid (or is the factory app, or app rollback is not enabled),
+    /// and the last update was not rejected.
+    Confirmed,
+    /// The running firmware is a new update which still needs to be validated with
+    /// `EspOta::mark_running_slot_valid`, or rolled back.
+    PendingValidation,
+    /// The last update was rejected - i.e. its slot is marked invalid or aborted in the OTA data -
+    /// and the bootloader (or the previous firmware) rolled back to the running firmware.
+    /// `from` is the firmware info of the rejected update, if it is still readable.
+    ///
+    /// This is the state of the OTA data, not an event: it is reported on every boot
+    /// until the slot of the rejected update is overwritten by the next update.
+    UpdateRejected { from: Option<FirmwareInfo> },
+}
+
+#[derive(Debug)]
+pub struct EspOta {
+    prepared: Option<(&'static esp_partition_t, esp_ota_handle_t)>,
+    yield_interval: Option<usize>,
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    boot_guard: Option<BootGuard>,
+}
+
+impl EspOta {
+    /// Obtains an instance of `EspOta`. Only one instance can exist at a time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `EspOta` already exists.
+    pub fn new() -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        *taken = true;
+
+        Ok(Self {
+            prepared: None,
+            yield_interval: Some(DEFAULT_YIELD_INTERVAL),
+            #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+            boot_guard: None,
+        })
+    }
+
+    /// Same as `new`, but with a boot guard, which rolls back to the previous firmware after
+    /// more than `max_failed_boots` consecutive boots which did not reach `mark_running_slot_valid`.
+    ///
+    /// Unlike the rollback of the bootloader, which only protects the first boot of an update,
+    /// this also catches firmware which is already valid but keeps crashing or hanging - e.g. because
+    /// of a watchdog reset - before the health check of the application marks it valid again.
+    /// The application is therefore expected to call this early, and `mark_running_slot_valid`
+    /// on every boot once it is healthy.
+    ///
+    /// The number of consecutive failed boots is stored as a `u32` in the `OTA_BOOT_GUARD_NAMESPACE`
+    /// namespace of the default NVS partition. It is incremented once per boot, by the first call
+    /// of this function, and reset to 0 by `mark_running_slot_valid`, as well as right before
+    /// rolling back, so that the previous firmware starts with a fresh count.
+    ///
+    /// If the threshold is exceeded, the running slot is marked invalid and the chip reboots into
+    /// the previous firmware, so this function does not return. If the rollback is not possible
+    /// (e.g. there is no other valid firmware), a warning is logged and the instance is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `EspOta` already exists, or if the NVS namespace cannot be accessed.
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    pub fn with_boot_guard(
+        nvs: EspDefaultNvsPartition,
+        max_failed_boots: u32,
+    ) -> Result<Self, EspError> {
+        let mut ota = Self::new()?;
+
+        let boot_guard = BootGuard {
+            nvs: EspNvs::new(nvs, OTA_BOOT_GUARD_NAMESPACE, true)?,
+            max_failed_boots,
+        };
+
+        let failed_boots = boot_guard.count()?;
+
+        if failed_boots > boot_guard.max_failed_boots {
+            warn!("{failed_boots} consecutive boots failed, rolling back");
+
+            boot_guard.reset()?;
+
+            let err = ota.mark_running_slot_invalid_and_reboot();
+
+            warn!("Rollback failed: {err}");
+        }
+
+        ota.boot_guard = Some(boot_guard);
+
+        Ok(ota)
+    }
+
+    /// Returns the currently configured boot slot.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if partition table is invalid or a flash read operation failed.
+    pub fn get_boot_slot(&self) -> Result<Slot, EspError> {
+        if let Some(partition) = unsafe { esp_ota_get_boot_partition().as_ref() } {
+            self.get_slot(partition)
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+        }
+    }
+
+    /// Returns the currently running app slot.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no partition is found or flash read operation failed.
+    pub fn get_running_slot(&self) -> Result<Slot, EspError> {
+        if let Some(partition) = unsafe { esp_ota_get_running_partition().as_ref() } {
+            self.get_slot(partition)
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+        }
+    }
+
+    /// Reads and parses the two entries of the OTA data partition, which the bootloader uses
+    /// to select the app to boot. This is a diagnostic aid for investigating boot slot selection.
+    ///
+    /// <div class="warning">
+    /// This relies on the ESP IDF internal layout of the OTA data partition
+    /// (`esp_ota_select_entry_t` records at the start of each of its two sectors),
+    /// which might change between ESP IDF versions.
+    /// </div>
+    pub fn dump_otadata(&self) -> Result<[OtaDataEntry; 2], EspError> {
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_OTA,
+                ptr::null(),
+            )
+        };
+
+        if partition.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+
+        let mut entries = [[0_u8; 32]; 2];
+
+        for (index, entry) in entries.iter_mut().enumerate() {
+            esp!(unsafe {
+                esp_partition_read(
+                    partition,
+                    index * SPI_FLASH_SEC_SIZE as usize,
+                    entry.as_mut_ptr() as _,
+                    entry.len(),
+                )
+            })?;
+        }
+
+        Ok(entries.map(|entry| OtaDataEntry::parse(&entry)))
+    }
+
+    /// Returns the SHA-256 of the ELF file of the running app, as embedded in its app description.
+    ///
+    /// This is the same value as the signature of the running slot's `FirmwareInfo`,
+    /// but as a fixed-size array, which is more convenient for comparisons.
+    pub fn running_app_elf_sha256_bytes(&self) -> Result<[u8; 32], EspError> {
+        let partition = unsafe { esp_ota_get_running_partition().as_ref() }
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let mut app_desc: esp_app_desc_t = Default::default();
+
+        esp!(unsafe { esp_ota_get_partition_description(partition, &mut app_desc) })?;
+
+        Ok(app_desc.app_elf_sha256)
+    }
+
+    /// Returns `true` if the running app is the factory app, which cannot be marked
+    /// as valid or invalid.
+    pub fn running_is_factory(&self) -> bool {
+        unsafe { esp_ota_get_running_partition().as_ref() }.is_some_and(|partition| {
+            partition.subtype == esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_FACTORY
+        })
+    }
+
+    /// Returns the slot of the next OTA app partition to be used for the new firmware.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if OTA data partition is invalid, or no eligible OTA app slot partition was found.
+    pub fn get_update_slot(&self) -> Result<Slot, EspError> {
+        if let Some(partition) = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() }
+        {
+            self.get_slot(partition)
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+        }
+    }
+
+    /// Returns the last slot with invalid state (invalid or aborted image).
+    pub fn get_last_invalid_slot(&self) -> Result<Option<Slot>, EspError> {
+        if let Some(partition) = unsafe { esp_ota_get_last_invalid_partition().as_ref() } {
+            Ok(Some(self.get_slot(partition)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns true if a factory partition is present.
+    pub fn is_factory_reset_supported(&self) -> Result<bool, EspError> {
+        self.get_factory_partition()
+            .map(|factory| !factory.is_null())
+    }
+
+    /// Returns the firmware info of the factory partition, e.g. to show the version of the factory
+    /// image on a recovery screen, before a `factory_reset`.
+    ///
+    /// Returns `None` if there is no factory partition, or if it does not contain an app image.
+    pub fn factory_firmware_info(&self) -> Result<Option<FirmwareInfo>, EspError> {
+        let factory = match self.get_factory_partition() {
+            Ok(factory) => factory,
+            Err(err) if err.code() == ESP_ERR_NOT_SUPPORTED => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        match unsafe { factory.as_ref() } {
+            Some(factory) => self.get_firmware_info(factory),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the boot partition to factory partition.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if factory partition is not present or boot partition could not be set.
+    pub fn factory_reset(&mut self) -> Result<(), EspError> {
+        let factory = self.get_factory_partition()?;
+
+        esp!(unsafe { esp_ota_set_boot_partition(factory) })?;
+
+        Ok(())
+    }
+
+    /// Reads the trailer written with `EspOtaUpdate::write_trailer` after the image
+    /// in the app slot with the given label, filling `buf`.
+    ///
+    /// The trailer is located right after the image, as declared by the image headers.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if no app slot with that label exists, an error if the slot
+    /// does not contain a valid app image, and `ESP_ERR_INVALID_SIZE` if `buf` extends beyond the partition.
+    pub fn read_trailer(&self, label: &str, buf: &mut [u8]) -> Result<(), EspError> {
+        let partition = self.find_app_partition(label)?;
+
+        let offset = image_len(partition)?;
+
+        if offset + buf.len() > partition.size as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        esp!(unsafe { esp_partition_read(partition, offset, buf.as_mut_ptr() as _, buf.len()) })
+    }
+
+    /// Reads and parses the secure boot V2 signature blocks appended to the image
+    /// in the app slot with the given label, e.g. for attestation or manifest tools.
+    ///
+    /// The signature sector is the last flash sector of the image, as the image length reported
+    /// by ESP IDF for signed apps already includes it. For robustness, the first sector boundary
+    /// after the image is tried too. The signatures themselves are not verified;
+    /// the bootloader does that when booting the image.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_SUPPORTED` if secure boot V2 is not enabled, `ESP_ERR_NOT_FOUND`
+    /// if no app slot with that label exists or if the image has no signature block, and an error
+    /// if the slot does not contain a valid app image.
+    pub fn read_signature_block(&self, label: &str) -> Result<SecureBootSig, EspError> {
+        if !cfg!(esp_idf_secure_boot_v2_enabled) {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        }
+
+        let partition = self.find_app_partition(label)?;
+
+        let sector = SPI_FLASH_SEC_SIZE as usize;
+        let len = image_len(partition)?;
+
+        let mut raw = [0_u8; SIG_BLOCK_LEN];
+
+        let offset = [
+            len.saturating_sub(1) / sector * sector,
+            len.div_ceil(sector) * sector,
+        ]
+        .into_iter()
+        .filter(|offset| offset + sector <= partition.size as usize)
+        .find(|offset| {
+            esp!(unsafe {
+                esp_partition_read(partition, *offset, raw.as_mut_ptr() as _, raw.len())
+            })
+            .is_ok()
+                && raw[0] == SIG_BLOCK_MAGIC
+        })
+        .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let mut sig = SecureBootSig {
+            offset,
+            blocks: heapless::Vec::new(),
+        };
+
+        for index in 0..sig.blocks.capacity() {
+            esp!(unsafe {
+                esp_partition_read(
+                    partition,
+                    offset + index * SIG_BLOCK_LEN,
+                    raw.as_mut_ptr() as _,
+                    raw.len(),
+                )
+            })?;
+
+            if raw[0] != SIG_BLOCK_MAGIC {
+                break;
+            }
+
+            let crc = u32::from_le_bytes(raw[SIG_BLOCK_CRC_OFFSET..][..4].try_into().unwrap());
+
+            sig.blocks
+                .push(SecureBootSigBlock {
+                    version: raw[1],
+                    image_digest: raw[4..36].try_into().unwrap(),
+                    crc_valid: crc
+                        == unsafe { esp_rom_crc32_le(0, raw.as_ptr(), SIG_BLOCK_CRC_OFFSET as _) },
+                })
+                .unwrap();
+        }
+
+        if sig.blocks.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+
+        Ok(sig)
+    }
+
+    /// Stages the update in the app slot with the given label - e.g. finished with
+    /// `EspOtaUpdate::finish` before a reboot - for a two-phase commit, see `EspOtaUpdateFinished::stage`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if no app slot with that label exists, `ESP_ERR_INVALID_STATE`
+    /// if it is the running slot, and an error if it does not contain a valid app image.
+    pub fn stage_update(&self, label: &str) -> Result<StageToken, EspError> {
+        let partition = self.find_app_partition(label)?;
+
+        if ptr::eq(partition, unsafe { esp_ota_get_running_partition() }) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        Ok(StageToken {
+            label: partition_label(partition).try_into().unwrap(),
+            digest: self.get_sha256(partition)?,
+        })
+    }
+
+    /// Commits the update staged with `EspOtaUpdateFinished::stage` or `stage_update`,
+    /// i.e. sets the boot partition to the staged slot, if the slot still holds the image
+    /// the token was issued for. The app in that slot will be run on the next boot.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if the slot of the token does not exist, and
+    /// `ESP_ERR_INVALID_STATE` if the token is stale, i.e. if the slot holds another image.
+    pub fn commit_staged(&mut self, token: &StageToken) -> Result<(), EspError> {
+        let partition = self.find_app_partition(token.label())?;
+
+        if self.get_sha256(partition)? != token.digest {
+            warn!("Stale stage token for slot {}", token.label());
+
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        esp!(unsafe { esp_ota_set_boot_partition(partition) })
+    }
+
+    /// Sets the boot partition to the app slot with the given label.
+    /// The app in that slot will be run on the next boot.
+    ///
+    /// Together with `EspOtaUpdate::finish`, this allows to download and validate an update
+    /// now, and to activate it at a later time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no app slot with that label exists, or if the slot does not contain
+    /// a valid app image.
+    pub fn activate_slot(&mut self, label: &str) -> Result<(), EspError> {
+        let partition = self.find_app_partition(label)?;
+
+        esp!(unsafe { esp_ota_set_boot_partition(partition) })
+    }
+
+    /// Returns `true` if the slot with the given label holds a bootable app image, i.e. if it is not
+    /// marked as invalid or aborted, and its image passes the verification done by the bootloader.
+    ///
+    /// This allows to check a slot before activating it with `activate_slot`. Note that the whole image
+    /// is read to verify its checksum and hash, which takes a while for large images.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if no app slot with that label exists.
+    pub fn is_slot_bootable(&self, label: &str) -> Result<bool, EspError> {
+        let partition = self.find_app_partition(label)?;
+
+        if matches!(self.get_state(partition)?, SlotState::Invalid) {
+            return Ok(false);
+        }
+
+        let mut metadata: esp_image_metadata_t = Default::default();
+
+        let err = unsafe {
+            esp_image_verify(
+                esp_image_load_mode_t_ESP_IMAGE_VERIFY_SILENT,
+                &esp_partition_pos_t {
+                    offset: partition.address,
+                    size: partition.size,
+                },
+                &mut metadata,
+            )
+        };
+
+        Ok(err == ESP_OK)
+    }
+
+    /// Checks that the partition table allows OTA updates, i.e. that it has at least two OTA app
+    /// partitions - or a factory and an OTA app partition - and an otadata partition.
+    ///
+    /// Calling this at startup surfaces a misprovisioned device early, rather than when
+    /// `initiate_update` fails much later. What is missing is logged as an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if any of these partitions is missing.
+    pub fn validate_partition_layout(&self) -> Result<(), EspError> {
+        let mut ota = 0;
+        let mut factory = 0;
+
+        for partition in AppPartitions::new() {
+            if is_ota_partition(partition) {
+                ota += 1;
+            } else if partition.subtype == esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_FACTORY
+            {
+                factory += 1;
+            }
+        }
+
+        let otadata = !unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_OTA,
+                ptr::null(),
+            )
+        }
+        .is_null();
+
+        let mut missing = heapless::String::<160>::new();
+
+        if ota == 0 || ota + factory < 2 {
+            let _ = write!(
+                &mut missing,
+                "two OTA app partitions, or a factory and an OTA app partition (found {ota} OTA and {factory} factory)"
+            );
+        }
+
+        if !otadata {
+            let _ = write!(
+                &mut missing,
+                "{}the otadata partition",
+                if missing.is_empty() { "" } else { "; " }
+            );
+        }
+
+        if !missing.is_empty() {
+            error!("Partition table not suitable for OTA updates, missing: {missing}");
+
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+
+        Ok(())
+    }
+
+    /// Compares the firmware versions of the slots with labels `a` and `b`, e.g. to tell which
+    /// of the two is newer, or whether rolling back would downgrade the firmware.
+    ///
+    /// The versions - from the app descriptions of the images - are compared numerically as
+    /// `major.minor.patch`, with an optional `v` prefix, and any suffix (like `-rc1` or `-dirty`) ignored.
+    /// A slot without an app image, or whose version cannot be parsed, is older than any slot
+    /// with a parseable version, and two such slots are equal.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if no app slot with either label exists.
+    pub fn compare_slots(&self, a: &str, b: &str) -> Result<Ordering, EspError> {
+        let a = self.get_firmware_info(self.find_app_partition(a)?)?;
+        let b = self.get_firmware_info(self.find_app_partition(b)?)?;
+
+        Ok(compare_versions(
+            a.as_ref().map(|info| info.version.as_str()),
+            b.as_ref().map(|info| info.version.as_str()),
+        ))
+    }
+
+    /// Sets the boot partition to the other OTA app slot holding a valid firmware,
+    /// so that the device reverts to that firmware on the next boot.
+    ///
+    /// This is meant for operator-initiated reverts; rollbacks due to the running firmware
+    /// being broken should rather use `mark_running_slot_invalid_and_reboot`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if no OTA app slot other than the running one holds a valid firmware.
+    pub fn rollback_to_previous(&mut self) -> Result<(), EspError> {
+        let running = unsafe { esp_ota_get_running_partition() };
+
+        let previous = AppPartitions::new()
+            .filter(|partition| is_ota_partition(partition) && !ptr::eq(*partition, running))
+            .find(|partition| {
+                matches!(self.get_state(partition), Ok(SlotState::Valid))
+                    && matches!(self.get_firmware_info(partition), Ok(Some(_)))
+            })
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        esp!(unsafe { esp_ota_set_boot_partition(previous) })
+    }
+
+    /// Erases every OTA app slot other than the running slot and the boot slot, e.g. to start
+    /// a rollout from a clean slate. Returns the number of slots which were erased.
+    ///
+    /// The running and the boot slots are never touched, nor is the factory app. Note that this
+    /// also erases the previous firmware, so a rollback is no longer possible afterwards.
+    /// The slot of a prepared update (see `prepare_update`) is already erased, and is kept
+    /// as it is, but it is counted.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_INVALID_STATE` if an `EspOtaUpdate` is alive, and `ESP_ERR_NOT_FOUND`
+    /// if the running slot cannot be determined.
+    pub fn erase_all_standby_slots(&mut self) -> Result<usize, EspError> {
+        check_not_updating()?;
+
+        let running = unsafe { esp_ota_get_running_partition() };
+        let boot = unsafe { esp_ota_get_boot_partition() };
+
+        if running.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+
+        let mut erased = 0;
+
+        for partition in AppPartitions::new()
+            .filter(|partition| is_ota_partition(partition))
+            .filter(|partition| !ptr::eq(*partition, running) && !ptr::eq(*partition, boot))
+        {
+            let prepared = self
+                .prepared
+                .is_some_and(|(prepared, _)| ptr::eq(prepared, partition));
+
+            if !prepared {
+                info!("Erasing slot {}", partition_label(partition));
+
+                esp!(unsafe { esp_partition_erase_range(partition, 0, partition.size as _) })?;
+            }
+
+            erased += 1;
+        }
+
+        Ok(erased)
+    }
+
+    /// Verifies the SHA-256 digest of the app image stored in the slot with the given label.
+    ///
+    /// Only the app image itself is hashed (as per the length declared in its header),
+    /// not the whole partition. This allows to periodically audit standby firmware for bit rot.
+    ///
+    /// Returns `None` if the digest matches the expected one, or `Some` with the actual digest otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no app slot with that label exists, or if it does not contain a valid app image.
+    pub fn verify_slot_sha256(
+        &self,
+        label: &str,
+        expected: &[u8; 32],
+    ) -> Result<Option<[u8; 32]>, EspError> {
+        let partition = self.find_app_partition(label)?;
+
+        let actual = self.get_sha256(partition)?;
+
+        Ok((&actual != expected).then_some(actual))
+    }
+
+    /// Initiates the OTA process and returns an instance of `EspOtaUpdate`
+    /// to be used for performing the OTA operations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if OTA could not be initiated (OTA partition not found, flash error).
+    /// Returns `ESP_ERR_INVALID_STATE` if another `EspOtaUpdate` is still alive.
+    pub fn initiate_update(&mut self) -> Result<EspOtaUpdate<'_>, EspError> {
+        self.initiate_update_with_size(OTA_SIZE_UNKNOWN as usize)
+    }
+
+    /// Initiates an OTA update of an image with a known size.
+    ///
+    /// Only the flash sectors necessary for an image of `size` bytes are erased,
+    /// rather than the whole update partition.
+    ///
+    /// If the update was prepared with `prepare_update`, the prepared - i.e. already erased -
+    /// partition is used instead, and nothing is erased.
+    pub fn initiate_update_with_size(&mut self, size: usize) -> Result<EspOtaUpdate<'_>, EspError> {
+        // This might return a null pointer in case no valid partition can be found.
+        // We don't have to handle this error in here, as this will implicitly trigger an error
+        // as soon as the null pointer is provided to `esp_ota_begin`.
+        check_not_updating()?;
+
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+
+        if let Some((prepared, handle)) = self.prepared.take() {
+            if ptr::eq(prepared, partition) {
+                return Ok(EspOtaUpdate::new(partition, handle, self.yield_interval));
+            }
+
+            // The next update partition changed since the update was prepared
+            unsafe { esp_ota_abort(handle) };
+        }
+
+        let mut handle: esp_ota_handle_t = Default::default();
+
+        esp!(unsafe { esp_ota_begin(partition, size, &mut handle) })?;
 
-impl EspOtaUpdateFinished<'_> {
-    /// Sets the boot partition to the newly updated app partition.
-    /// The app will be run on the next boot.
-    pub fn activate(self) -> Result<(), EspError> {
-        esp!(unsafe { esp_ota_set_boot_partition(self.update_partition) })
+        Ok(EspOtaUpdate::new(partition, handle, self.yield_interval))
     }
-}
-
-#[derive(Debug)]
-pub struct EspOta(());
 
-impl EspOta {
-    /// Obtains an instance of `EspOta`. Only one instance can exist at a time.
+    /// Resumes the update checkpointed with `EspOtaUpdate::enable_checkpoints`, if any, e.g. after
+    /// a power loss interrupted it. The resumed update keeps checkpointing every `interval` bytes,
+    /// and the download should continue from `EspOtaUpdate::offset`.
+    ///
+    /// Returns `None` if there is no checkpoint in the `OTA_CHECKPOINT_NAMESPACE` NVS namespace.
+    /// A stale checkpoint - for another image than `image_id`, for a slot which is no longer
+    /// the next update slot, or whose slot no longer starts with an image header - is removed,
+    /// and `None` is returned too, in which case the update should be started over with `initiate_update`.
+    ///
+    /// A prepared update (see `prepare_update`) is cancelled, as it would have erased the slot.
+    ///
+    /// Resuming requires `esp_ota_resume`, i.e. ESP IDF 5.5 or later.
     ///
     /// # Errors
     ///
-    /// Returns an error if `EspOta` already exists.
-    pub fn new() -> Result<Self, EspError> {
-        let mut taken = TAKEN.lock();
+    /// Returns `ESP_ERR_INVALID_STATE` if another `EspOtaUpdate` is still alive.
+    #[cfg(all(
+        feature = "alloc",
+        esp_idf_comp_nvs_flash_enabled,
+        not(any(
+            esp_idf_version_major = "4",
+            all(
+                esp_idf_version_major = "5",
+                any(
+                    esp_idf_version_minor = "0",
+                    esp_idf_version_minor = "1",
+                    esp_idf_version_minor = "2",
+                    esp_idf_version_minor = "3",
+                    esp_idf_version_minor = "4"
+                )
+            )
+        ))
+    ))]
+    pub fn resume_from_checkpoint(
+        &mut self,
+        nvs: EspDefaultNvsPartition,
+        image_id: &[u8],
+        interval: usize,
+    ) -> Result<Option<EspOtaUpdate<'_>>, EspError> {
+        check_not_updating()?;
+
+        if let Some((_, handle)) = self.prepared.take() {
+            unsafe { esp_ota_abort(handle) };
+        }
 
-        if *taken {
-            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        let mut nvs = OtaCheckpoint::open(nvs)?;
+
+        let Some((address, offset, id)) = OtaCheckpoint::load(&nvs)? else {
+            return Ok(None);
+        };
+
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() }
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let mut magic = ESP_IMAGE_HEADER_MAGIC as u8;
+        if offset > 0 {
+            esp!(unsafe { esp_partition_read(partition, 0, &mut magic as *mut u8 as *mut _, 1) })?;
         }
 
-        *taken = true;
+        if id != image_id
+            || address != partition.address
+            || offset > partition.size as usize
+            || magic != ESP_IMAGE_HEADER_MAGIC as u8
+        {
+            warn!("Discarding a stale OTA checkpoint");
+
+            nvs.remove(OTA_CHECKPOINT_KEY)?;
+
+            return Ok(None);
+        }
+
+        let checkpoint = OtaCheckpoint::new(nvs, image_id, interval, offset)?;
+
+        let mut handle: esp_ota_handle_t = Default::default();
 
-        Ok(Self(()))
+        // The sectors after the checkpoint are erased as they are written
+        esp!(unsafe {
+            esp_ota_resume(
+                partition,
+                OTA_WITH_SEQUENTIAL_WRITES as usize,
+                offset,
+                &mut handle,
+            )
+        })?;
+
+        info!("Resuming the OTA update at offset {offset}");
+
+        let mut update = EspOtaUpdate::new(partition, handle, self.yield_interval);
+        update.written = offset;
+        update.checkpoint = Some(checkpoint);
+
+        Ok(Some(update))
     }
 
-    /// Returns the currently configured boot slot.
+    /// Removes the checkpoint saved by `EspOtaUpdate::enable_checkpoints`, if any,
+    /// so that the interrupted update is not resumed.
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    pub fn clear_checkpoint(&mut self, nvs: EspDefaultNvsPartition) -> Result<(), EspError> {
+        OtaCheckpoint::open(nvs)?.remove(OTA_CHECKPOINT_KEY)?;
+
+        Ok(())
+    }
+
+    /// Configures how often the long flash readback loops (like the ones of `clone_running_to_update`
+    /// and `EspOtaUpdate::verify_last_write`) yield to other tasks for one tick, so that they do not
+    /// starve them or trip the task watchdog. `None` disables yielding.
     ///
-    /// # Errors
+    /// By default, the loops yield every `DEFAULT_YIELD_INTERVAL` bytes.
+    /// The setting is inherited by the updates initiated afterwards.
     ///
-    /// Returns an error if partition table is invalid or a flash read operation failed.
-    pub fn get_boot_slot(&self) -> Result<Slot, EspError> {
-        if let Some(partition) = unsafe { esp_ota_get_boot_partition().as_ref() } {
-            self.get_slot(partition)
-        } else {
-            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
-        }
+    /// Note that `verify_slot_sha256` cannot yield, as the hash is computed by ESP IDF in a single call.
+    pub fn set_yield_interval(&mut self, bytes: Option<usize>) {
+        self.yield_interval = bytes;
     }
 
-    /// Returns the currently running app slot.
+    /// Copies the image of the running app to the next update slot and completes the update,
+    /// so that both slots contain identical - known good - images.
+    ///
+    /// Only the image itself (as declared by its headers) is copied, not the whole partition.
+    /// The image is read and written through the flash encryption layer, so this works
+    /// with flash encryption enabled too.
     ///
     /// # Errors
     ///
-    /// Returns an error if no partition is found or flash read operation failed.
-    pub fn get_running_slot(&self) -> Result<Slot, EspError> {
-        if let Some(partition) = unsafe { esp_ota_get_running_partition().as_ref() } {
-            self.get_slot(partition)
-        } else {
-            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    /// Returns `ESP_ERR_INVALID_SIZE` if the image does not fit in the update slot.
+    pub fn clone_running_to_update(&mut self) -> Result<(), EspError> {
+        let running = unsafe { esp_ota_get_running_partition().as_ref() }
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let image_len = image_len(running)?;
+
+        let mut update = self.initiate_update_with_size(image_len)?;
+
+        let target = unsafe { update.update_partition.as_ref() }.unwrap();
+        if image_len > target.size as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut buf = [0_u8; 1024];
+        let mut offset = 0;
+
+        while offset < image_len {
+            let buf = &mut buf[..min(image_len - offset, 1024)];
+
+            esp!(unsafe { esp_partition_read(running, offset, buf.as_mut_ptr() as _, buf.len()) })?;
+
+            update.write(buf)?;
+
+            cooperative_yield(update.yield_interval, offset, offset + buf.len());
+
+            offset += buf.len();
         }
+
+        update.complete()
     }
 
-    /// Returns the slot of the next OTA app partition to be used for the new firmware.
+    /// Computes the SHA-256 of the image of the running app, e.g. to attest that the running
+    /// firmware matches a known good image.
     ///
-    /// # Errors
+    /// Only the image itself (as declared by its headers, including the appended hash and
+    /// signature, if any) is hashed, not the whole partition, so the result does not depend on
+    /// the size of the partition nor on the padding after the image. It is therefore the SHA-256
+    /// of the `.bin` file which was flashed.
     ///
-    /// Returns an error if OTA data partition is invalid, or no eligible OTA app slot partition was found.
-    pub fn get_update_slot(&self) -> Result<Slot, EspError> {
-        if let Some(partition) = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() }
-        {
-            self.get_slot(partition)
-        } else {
-            Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
-        }
-    }
+    /// The image is read in chunks, yielding as configured with `set_yield_interval`, so that
+    /// the task watchdog is not triggered.
+    #[cfg(esp_idf_comp_mbedtls_enabled)]
+    pub fn hash_running_partition(&self) -> Result<[u8; 32], EspError> {
+        let running = unsafe { esp_ota_get_running_partition().as_ref() }
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
 
-    /// Returns the last slot with invalid state (invalid or aborted image).
-    pub fn get_last_invalid_slot(&self) -> Result<Option<Slot>, EspError> {
-        if let Some(partition) = unsafe { esp_ota_get_last_invalid_partition().as_ref() } {
-            Ok(Some(self.get_slot(partition)?))
-        } else {
-            Ok(None)
+        let image_len = image_len(running)?;
+
+        let mut sha = Sha256::new()?;
+
+        let mut buf = [0_u8; 1024];
+        let mut offset = 0;
+
+        while offset < image_len {
+            let buf = &mut buf[..min(image_len - offset, 1024)];
+
+            esp!(unsafe { esp_partition_read(running, offset, buf.as_mut_ptr() as _, buf.len()) })?;
+
+            sha.update(buf)?;
+
+            cooperative_yield(self.yield_interval, offset, offset + buf.len());
+
+            offset += buf.len();
         }
-    }
 
-    /// Returns true if a factory partition is present.
-    pub fn is_factory_reset_supported(&self) -> Result<bool, EspError> {
-        self.get_factory_partition()
-            .map(|factory| !factory.is_null())
+        sha.finish()
     }
 
-    /// Sets the boot partition to factory partition.
+    /// Prepares the next OTA update ahead of time - e.g. when the device is idle - by erasing
+    /// the whole update partition, which might take several seconds.
     ///
-    /// # Errors
+    /// The next call to `initiate_update` or `initiate_update_with_size` then starts
+    /// writing immediately, without erasing anything. Calling `prepare_update` again
+    /// while an update is already prepared does nothing.
     ///
-    /// Returns an error if factory partition is not present or boot partition could not be set.
-    pub fn factory_reset(&mut self) -> Result<(), EspError> {
-        let factory = self.get_factory_partition()?;
+    /// A prepared update is cancelled when `EspOta` is dropped.
+    pub fn prepare_update(&mut self) -> Result<(), EspError> {
+        if self.prepared.is_some() {
+            return Ok(());
+        }
 
-        esp!(unsafe { esp_ota_set_boot_partition(factory) })?;
+        check_not_updating()?;
+
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() }
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let mut handle: esp_ota_handle_t = Default::default();
+
+        esp!(unsafe { esp_ota_begin(partition, OTA_SIZE_UNKNOWN as usize, &mut handle) })?;
+
+        self.prepared = Some((partition, handle));
 
         Ok(())
     }
 
-    /// Initiates the OTA process and returns an instance of `EspOtaUpdate`
-    /// to be used for performing the OTA operations.
+    /// Initiates an OTA update on the first OTA slot which is neither the running slot,
+    /// nor the boot slot, and whose label is not in `avoid`.
+    ///
+    /// This allows custom slot selection policies with more than two OTA slots.
+    /// If `avoid` is empty, this is equivalent to `initiate_update`.
+    ///
+    /// If the update was prepared with `prepare_update` for the chosen slot, the prepared
+    /// partition is used, and nothing is erased; a prepared update for another slot is cancelled.
     ///
     /// # Errors
     ///
-    /// Returns an error if OTA could not be initiated (OTA partition not found, flash error).
-    pub fn initiate_update(&mut self) -> Result<EspOtaUpdate<'_>, EspError> {
-        // This might return a null pointer in case no valid partition can be found.
-        // We don't have to handle this error in here, as this will implicitly trigger an error
-        // as soon as the null pointer is provided to `esp_ota_begin`.
-        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+    /// Returns `ESP_ERR_NOT_FOUND` if no eligible OTA slot exists.
+    pub fn initiate_update_avoiding(
+        &mut self,
+        avoid: &[&str],
+    ) -> Result<EspOtaUpdate<'_>, EspError> {
+        if avoid.is_empty() {
+            return self.initiate_update();
+        }
+
+        check_not_updating()?;
+
+        let running = unsafe { esp_ota_get_running_partition() };
+        let boot = unsafe { esp_ota_get_boot_partition() };
+
+        let partition = AppPartitions::new()
+            .filter(|partition| is_ota_partition(partition))
+            .filter(|partition| !ptr::eq(*partition, running) && !ptr::eq(*partition, boot))
+            .find(|partition| !avoid.contains(&partition_label(partition)))
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        if let Some((prepared, handle)) = self.prepared.take() {
+            if ptr::eq(prepared, partition) {
+                return Ok(EspOtaUpdate::new(partition, handle, self.yield_interval));
+            }
+
+            // The prepared update is for another slot than the chosen one
+            unsafe { esp_ota_abort(handle) };
+        }
 
         let mut handle: esp_ota_handle_t = Default::default();
 
         esp!(unsafe { esp_ota_begin(partition, OTA_SIZE_UNKNOWN as usize, &mut handle) })?;
 
-        Ok(EspOtaUpdate {
-            update_partition: partition,
-            update_handle: handle,
-            _data: PhantomData,
-        })
+        Ok(EspOtaUpdate::new(partition, handle, self.yield_interval))
     }
 
     /// Marks the current application as valid.
     ///
     /// If rollback is enabled, the application must confirm its operability by calling
     /// `mark_running_slot_valid()` function, otherwise the application will be rolled back upon reboot.
+    ///
+    /// With a boot guard (see `with_boot_guard`), this also resets the number of consecutive failed boots.
     pub fn mark_running_slot_valid(&mut self) -> Result<(), EspError> {
-        Ok(esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() })?)
+        esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() })?;
+
+        #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+        if let Some(boot_guard) = self.boot_guard.as_ref() {
+            boot_guard.reset()?;
+        }
+
+        Ok(())
+    }
+
+    /// Restarts the chip, e.g. to boot the slot activated by `EspOtaUpdate::complete`.
+    ///
+    /// Before restarting, the logger is flushed (and so is `stdout` with the `std` feature).
+    /// The restart itself is done with `esp_restart`, which runs the shutdown handlers
+    /// registered with ESP IDF (e.g. stopping Wi-Fi), but which does not know about the resources
+    /// owned by the application: mounted filesystems are not unmounted, and open files are not
+    /// closed or synced. Drop (or sync) them before calling this method, otherwise the data still
+    /// in their caches is lost.
+    ///
+    /// Does not return, unless an update is still in progress, in which case
+    /// `ESP_ERR_INVALID_STATE` is returned.
+    pub fn reboot(&self) -> Result<core::convert::Infallible, EspError> {
+        check_not_updating()?;
+
+        ::log::logger().flush();
+
+        #[cfg(feature = "std")]
+        {
+            use std::io::Write;
+
+            let _ = std::io::stdout().flush();
+        }
+
+        crate::hal::reset::restart();
+    }
+
+    /// Same as `reboot`, but waits for `delay` first, e.g. to give the user interface
+    /// the time to show that the device is rebooting.
+    pub fn reboot_after(&self, delay: Duration) -> Result<core::convert::Infallible, EspError> {
+        check_not_updating()?;
+
+        FreeRtos::delay_ms(delay.as_millis().try_into().unwrap_or(u32::MAX));
+
+        self.reboot()
+    }
+
+    /// Returns the reason for the last reset of the chip.
+    ///
+    /// This is the raw reason, as reported by `esp_reset_reason()`.
+    pub fn last_reset_reason(&self) -> ResetReason {
+        ResetReason::get()
+    }
+
+    /// Returns `true` if the previous boot ended cleanly, and it is therefore
+    /// safe to mark the running slot as valid.
+    ///
+    /// If the previous boot ended in a panic, a watchdog reset or a brownout,
+    /// `false` is returned, and the application should rather trigger a rollback
+    /// (i.e. by calling `mark_running_slot_invalid_and_reboot`).
+    pub fn should_mark_valid(&self) -> bool {
+        !matches!(
+            self.last_reset_reason(),
+            ResetReason::Panic
+                | ResetReason::InterruptWatchdog
+                | ResetReason::TaskWatchdog
+                | ResetReason::Watchdog
+                | ResetReason::Brownout
+        )
+    }
+
+    /// Returns the health of the running firmware, i.e. whether it is confirmed, still needs
+    /// to be validated, or is the result of a rollback.
+    ///
+    /// This is meant to be called once at startup, to decide between validating the running
+    /// firmware, rolling it back, or reporting a failed update.
+    ///
+    /// A rejected update is detected from the OTA data only: the last slot marked invalid
+    /// or aborted is not the running slot. ESP IDF keeps that state until the slot is overwritten
+    /// by the next update, so the result does not depend on the reset reason, and applications
+    /// which should report a rejected update only once have to remember that they did, e.g. in NVS.
+    pub fn boot_health(&self) -> Result<BootHealth, EspError> {
+        let running = self.get_running_slot()?;
+
+        if matches!(running.state, SlotState::Unverified) {
+            return Ok(BootHealth::PendingValidation);
+        }
+
+        if let Some(invalid) = self.get_last_invalid_slot()? {
+            if invalid.label != running.label {
+                return Ok(BootHealth::UpdateRejected {
+                    from: invalid.firmware,
+                });
+            }
+        }
+
+        Ok(BootHealth::Confirmed)
     }
 
     /// Rolls back to the previously workable app with reboot.
@@ -528,6 +3179,81 @@ impl EspOta {
         }
     }
 
+    /// Puts the running app back into the state of a freshly installed update, so that the
+    /// rollback logic of the application can be tested without flashing a bad update.
+    ///
+    /// The running slot is re-activated, which - with rollback enabled - marks it as a new,
+    /// unverified image: on the next boot, the bootloader runs it as pending verification,
+    /// and if the app does not call `mark_running_slot_valid` during that boot, the boot after
+    /// that rolls back to the previous app.
+    ///
+    /// Only available in debug builds with `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`, so that it cannot
+    /// ship in release builds.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ESP_ERR_NOT_SUPPORTED` if the running app is the factory app, which cannot be rolled back.
+    #[cfg(all(debug_assertions, esp_idf_bootloader_app_rollback_enable))]
+    pub fn simulate_bad_update(&mut self) -> Result<(), EspError> {
+        if self.running_is_factory() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        }
+
+        let running = unsafe { esp_ota_get_running_partition() };
+
+        warn!("Simulating a bad update: the running app will be rolled back unless validated on the next boot");
+
+        esp!(unsafe { esp_ota_set_boot_partition(running) })
+    }
+
+    /// Returns the maximum size of an app image that can be flashed with an OTA update.
+    ///
+    /// This is the size of the smallest OTA app partition, as any OTA app partition
+    /// might end up being the target of an update.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the partition table does not contain any OTA app partition.
+    pub fn max_app_image_size(&self) -> Result<usize, EspError> {
+        AppPartitions::new()
+            .filter(|partition| is_ota_partition(partition))
+            .map(|partition| partition.size as usize)
+            .min()
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    }
+
+    /// Runs the provided health check of the running firmware, and marks the running slot as valid
+    /// only if the check succeeds. Otherwise, the running slot is marked as invalid and the device
+    /// is rebooted into the previous firmware.
+    ///
+    /// With rollback enabled (`CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`), a freshly updated firmware
+    /// which is not marked as valid before the next reset (including a crash or a watchdog reset)
+    /// is rolled back. Therefore, this should be called as early as possible after boot, with
+    /// a health check exercising the functionality required for a future update (i.e. networking).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the slot could not be marked as valid, or if the health check failed but
+    /// the rollback was not possible.
+    pub fn run_self_test_and_validate<F>(&mut self, test: F) -> Result<(), EspError>
+    where
+        F: FnOnce() -> bool,
+    {
+        if test() {
+            self.mark_running_slot_valid()
+        } else {
+            warn!("Self-test failed, rolling back");
+
+            Err(self.mark_running_slot_invalid_and_reboot())
+        }
+    }
+
+    fn find_app_partition(&self, label: &str) -> Result<&'static esp_partition_t, EspError> {
+        AppPartitions::new()
+            .find(|partition| partition_label(partition) == label)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    }
+
     fn get_factory_partition(&self) -> Result<*const esp_partition_t, EspError> {
         let partition_iterator = unsafe {
             esp_partition_find(
@@ -548,11 +3274,75 @@ impl EspOta {
         Ok(partition)
     }
 
+    fn get_sha256(&self, partition: &esp_partition_t) -> Result<[u8; 32], EspError> {
+        let mut sha256 = [0; 32];
+
+        esp!(unsafe { esp_partition_get_sha256(partition, sha256.as_mut_ptr()) })?;
+
+        Ok(sha256)
+    }
+
+    /// Writes a human-readable report of the state of every app slot, one line per slot, e.g.:
+    ///
+    /// ```text
+    /// factory [factory] v1.0.0
+    /// ota_0 [valid] v1.2.3 (running)
+    /// ota_1 [unverified] v1.3.0 (boot)
+    /// ```
+    ///
+    /// `(running)` and `(boot)` mark the running slot and the slot selected for the next boot.
+    pub fn write_status_report<W>(&self, out: &mut W) -> Result<(), EspError>
+    where
+        W: Write,
+    {
+        let running = unsafe { esp_ota_get_running_partition() };
+        let boot = unsafe { esp_ota_get_boot_partition() };
+
+        for partition in AppPartitions::new() {
+            let slot = self.get_slot(partition)?;
+
+            let state = match slot.state {
+                SlotState::Factory => "factory",
+                SlotState::Valid => "valid",
+                SlotState::Invalid => "invalid",
+                SlotState::Unverified => "unverified",
+                SlotState::Unknown => "unknown",
+            };
+
+            let version = slot
+                .firmware
+                .as_ref()
+                .map(|firmware| firmware.version.as_str())
+                .unwrap_or("-");
+
+            let marks = match (ptr::eq(partition, running), ptr::eq(partition, boot)) {
+                (true, true) => " (running, boot)",
+                (true, false) => " (running)",
+                (false, true) => " (boot)",
+                (false, false) => "",
+            };
+
+            writeln!(out, "{} [{state}] {version}{marks}", slot.label)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `write_status_report`, but returns the report as a string of at most `N` bytes.
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if the report does not fit.
+    pub fn status_report<const N: usize>(&self) -> Result<heapless::String<N>, EspError> {
+        let mut report = heapless::String::new();
+
+        self.write_status_report(&mut report)?;
+
+        Ok(report)
+    }
+
     fn get_slot(&self, partition: &esp_partition_t) -> Result<Slot, EspError> {
         Ok(Slot {
-            label: unsafe { from_cstr_ptr(&partition.label as *const _ as *const _) }
-                .try_into()
-                .unwrap(),
+            label: partition_label(partition).try_into().unwrap(),
             state: self.get_state(partition)?,
             firmware: self.get_firmware_info(partition)?,
         })
@@ -613,8 +3403,61 @@ impl EspOta {
     }
 }
 
+/// An iterator over all app partitions in the partition table.
+struct AppPartitions(esp_partition_iterator_t);
+
+impl AppPartitions {
+    fn new() -> Self {
+        Self(unsafe {
+            esp_partition_find(
+                esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                ptr::null(),
+            )
+        })
+    }
+}
+
+impl Iterator for AppPartitions {
+    type Item = &'static esp_partition_t;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_null() {
+            return None;
+        }
+
+        let partition = unsafe { esp_partition_get(self.0).as_ref() };
+
+        // This releases the current iterator and returns null when there are no more partitions
+        self.0 = unsafe { esp_partition_next(self.0) };
+
+        partition
+    }
+}
+
+impl Drop for AppPartitions {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { esp_partition_iterator_release(self.0) };
+        }
+    }
+}
+
+fn partition_label(partition: &esp_partition_t) -> &str {
+    unsafe { from_cstr_ptr(&partition.label as *const _ as *const _) }
+}
+
+fn is_ota_partition(partition: &esp_partition_t) -> bool {
+    partition.subtype >= esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MIN
+        && partition.subtype < esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_APP_OTA_MAX
+}
+
 impl Drop for EspOta {
     fn drop(&mut self) {
+        if let Some((_, handle)) = self.prepared.take() {
+            unsafe { esp_ota_abort(handle) };
+        }
+
         *TAKEN.lock() = false;
 
         info!("Dropped");
@@ -719,3 +3562,48 @@ impl OtaUpdateFinished for EspOtaUpdateFinished<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::cmp::Ordering;
+
+    use super::{compare_versions, parse_idf_version, StageToken, STAGE_TOKEN_LEN};
+
+    #[test]
+    fn parse_idf_versions() {
+        assert_eq!(parse_idf_version("v5.2.1"), Some((5, 2, 1)));
+        assert_eq!(parse_idf_version("v5.1-dirty"), Some((5, 1, 0)));
+        assert_eq!(parse_idf_version("v5.3-dev-1234-gabcdef"), Some((5, 3, 0)));
+        assert_eq!(parse_idf_version("4.4.7"), Some((4, 4, 7)));
+        assert_eq!(parse_idf_version("unknown"), None);
+    }
+
+    #[test]
+    fn stage_token_bytes() {
+        let token = StageToken {
+            label: "ota_1".try_into().unwrap(),
+            digest: [0xab; 32],
+        };
+
+        let bytes = token.to_bytes();
+
+        assert_eq!(&bytes[..6], b"ota_1\0");
+        assert_eq!(StageToken::from_bytes(&bytes), Some(token));
+        assert_eq!(StageToken::from_bytes(&bytes[..STAGE_TOKEN_LEN - 1]), None);
+    }
+
+    #[test]
+    fn compare_firmware_versions() {
+        assert_eq!(compare_versions(Some("v1.2"), Some("v1.3")), Ordering::Less);
+        assert_eq!(
+            compare_versions(Some("1.10.0"), Some("v1.9.9-rc1")),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions(Some("v1.2.0"), Some("1.2-dirty")),
+            Ordering::Equal
+        );
+        assert_eq!(compare_versions(None, Some("0.0.1")), Ordering::Less);
+        assert_eq!(compare_versions(Some("garbage"), None), Ordering::Equal);
+    }
+}
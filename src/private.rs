@@ -5,6 +5,8 @@ pub mod cstr;
 pub mod mutex;
 #[cfg(esp_idf_comp_esp_netif_enabled)]
 pub mod net;
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+pub mod sha256;
 #[cfg(feature = "alloc")]
 pub mod unblocker;
 pub mod waitable;
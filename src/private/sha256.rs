@@ -0,0 +1,65 @@
+//! A SHA-256 digest on top of mbedtls, which returns errors the same way on ESP IDF v4
+//! (mbedtls 2.x, where only the `_ret` variants return an error code) and on ESP IDF v5+ (mbedtls 3.x).
+
+use crate::sys::*;
+
+pub struct Sha256(mbedtls_sha256_context);
+
+impl Sha256 {
+    pub fn new() -> Result<Self, EspError> {
+        let mut sha = Self(Default::default());
+
+        unsafe { mbedtls_sha256_init(&mut sha.0) };
+
+        #[cfg(esp_idf_version_major = "4")]
+        check(unsafe { mbedtls_sha256_starts_ret(&mut sha.0, 0) })?;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        check(unsafe { mbedtls_sha256_starts(&mut sha.0, 0) })?;
+
+        Ok(sha)
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<(), EspError> {
+        #[cfg(esp_idf_version_major = "4")]
+        check(unsafe { mbedtls_sha256_update_ret(&mut self.0, data.as_ptr(), data.len() as _) })?;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        check(unsafe { mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len() as _) })?;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<[u8; 32], EspError> {
+        let mut hash = [0; 32];
+
+        #[cfg(esp_idf_version_major = "4")]
+        check(unsafe { mbedtls_sha256_finish_ret(&mut self.0, hash.as_mut_ptr()) })?;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        check(unsafe { mbedtls_sha256_finish(&mut self.0, hash.as_mut_ptr()) })?;
+
+        Ok(hash)
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+pub fn sha256(data: &[u8]) -> Result<[u8; 32], EspError> {
+    let mut sha = Sha256::new()?;
+
+    sha.update(data)?;
+    sha.finish()
+}
+
+fn check(ret: i32) -> Result<(), EspError> {
+    if ret != 0 {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    } else {
+        Ok(())
+    }
+}